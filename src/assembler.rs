@@ -0,0 +1,775 @@
+use std::{collections::HashMap, fmt::Display, fs, io, path::{Path, PathBuf}};
+use crate::{cpu::opcode::Opcode, memory::Memory};
+
+/// Reason why the source could not be assembled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssemblerErrorKind {
+    /// The line does not follow the syntax of the assembler
+    Syntax(String),
+    UnknownMnemonic(String),
+    /// The operands do not match any form of the mnemonic
+    InvalidOperands(String),
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    /// The value does not fit the operand or the data directive
+    ValueOutOfRange(i64),
+    DivisionByZero,
+    /// The constant is defined in terms of itself
+    RecursiveConstant(String),
+    /// The included file could not be read
+    Include(String)
+}
+
+/// Error raised by the assembler, with the location in the source which caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub kind: AssemblerErrorKind
+}
+
+impl Display for AssemblerErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssemblerErrorKind::Syntax(message) => write!(f, "{}", message),
+            AssemblerErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic {}", mnemonic),
+            AssemblerErrorKind::InvalidOperands(mnemonic) => write!(f, "invalid operands for {}", mnemonic),
+            AssemblerErrorKind::UndefinedSymbol(name) => write!(f, "undefined symbol {}", name),
+            AssemblerErrorKind::DuplicateSymbol(name) => write!(f, "symbol {} is already defined", name),
+            AssemblerErrorKind::ValueOutOfRange(value) => write!(f, "value {} is out of range", value),
+            AssemblerErrorKind::DivisionByZero => write!(f, "division by zero"),
+            AssemblerErrorKind::RecursiveConstant(name) => write!(f, "constant {} is defined in terms of itself", name),
+            AssemblerErrorKind::Include(message) => write!(f, "could not include file: {}", message)
+        }
+    }
+}
+
+impl Display for AssemblerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/**
+Assembles the source file into a ROM loaded at 0x200.
+
+The syntax is the one produced by the disassembler, one statement per line:
+- `label:` defines a label at the current address, and `NAME = expr` (or `NAME equ expr`) a constant
+- instructions use the mnemonics of [`Opcode`], such as `LD V0, 0x12` or `DRW V0, V1, 5`
+- `db` and `dw` emit bytes and big endian words, `db` also accepts strings
+- `include "file"` assembles another file in place, its path is relative to the including file
+- expressions combine numbers (decimal, `0x` hexadecimal or `0b` binary), symbols and `$` (the current address)
+  with the operators `+ - * / % & | ^ << >> ~` and parentheses
+- `;` starts a comment
+
+The register names and the operand keywords `I`, `DT`, `ST`, `K`, `F`, `HF`, `B` and `R` can not be used as symbols
+ */
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AssemblerError> {
+    assemble_file_at(path, Memory::ROM_INIT_ADDRESS)
+}
+
+/// Assembles the source file into a ROM loaded at the given address, which is where its labels start
+pub fn assemble_file_at(path: &Path, load_address: usize) -> Result<Vec<u8>, AssemblerError> {
+    let source = fs::read_to_string(path).map_err(|e| AssemblerError {
+        file: path.display().to_string(),
+        line: 0,
+        column: 0,
+        kind: AssemblerErrorKind::Include(e.to_string())
+    })?;
+    Assembler::new(|path| fs::read_to_string(path), load_address).assemble(&source, path)
+}
+
+/// Assembles the source, see [`assemble_file`] for the syntax, included files are relative to the current directory
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblerError> {
+    Assembler::new(|path| fs::read_to_string(path), Memory::ROM_INIT_ADDRESS).assemble(source, Path::new("<source>"))
+}
+
+/// Tokens are kept with their column, to locate the errors
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Number(i64),
+    Text(String),
+    Symbol(&'static str)
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+    Number(i64),
+    Symbol(String, usize),
+    CurrentAddress,
+    Unary(&'static str, Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Keyword {
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R
+}
+
+#[derive(Debug, Clone)]
+enum OperandKind {
+    Register(u8),
+    Keyword(Keyword),
+    Long(Expression),
+    Expression(Expression)
+}
+
+#[derive(Debug, Clone)]
+struct Operand {
+    kind: OperandKind,
+    column: usize
+}
+
+#[derive(Debug, Clone)]
+enum DataItem {
+    Expression(Expression, usize),
+    Text(Vec<u8>)
+}
+
+#[derive(Debug, Clone)]
+enum StatementKind {
+    Instruction(String, Vec<Operand>),
+    Data(usize, Vec<DataItem>)
+}
+
+#[derive(Debug, Clone)]
+struct Location {
+    file: String,
+    line: usize,
+    column: usize
+}
+
+impl Location {
+    fn error(&self, column: usize, kind: AssemblerErrorKind) -> AssemblerError {
+        AssemblerError { file: self.file.clone(), line: self.line, column, kind }
+    }
+}
+
+struct Statement {
+    kind: StatementKind,
+    address: usize,
+    location: Location
+}
+
+enum SymbolValue {
+    Label(usize),
+    /// Constant expression, with the address where it is defined
+    Constant(Expression, usize)
+}
+
+struct Assembler<L: FnMut(&Path) -> io::Result<String>> {
+    load_file: L,
+    statements: Vec<Statement>,
+    symbols: HashMap<String, SymbolValue>,
+    address: usize,
+    include_stack: Vec<PathBuf>
+}
+
+/// Error without location, which is added by the caller
+type PartialError = (usize, AssemblerErrorKind);
+
+impl<L: FnMut(&Path) -> io::Result<String>> Assembler<L> {
+    const MAX_INCLUDE_DEPTH: usize = 16;
+    const MAX_CONSTANT_DEPTH: usize = 64;
+    const MNEMONICS: [&'static str; 35] = [
+        "SYS", "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE", "SNE", "SAVE", "LOAD",
+        "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN", "SHL", "SHR", "RND", "DRW", "SKP", "SKNP", "PLANE", "AUDIO",
+        "PITCH", "DB", "DW", "INCLUDE"
+    ];
+
+    fn new(load_file: L, load_address: usize) -> Self {
+        Self {
+            load_file,
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+            address: load_address,
+            include_stack: Vec::new()
+        }
+    }
+
+    /// Reads every statement and defines the symbols, then encodes the statements once all labels are known
+    fn assemble(mut self, source: &str, path: &Path) -> Result<Vec<u8>, AssemblerError> {
+        self.read_source(source, path)?;
+
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            match &statement.kind {
+                StatementKind::Instruction(mnemonic, operands) => {
+                    let words = self.encode_instruction(mnemonic, operands, statement.address, statement.location.column)
+                        .map_err(|(column, kind)| statement.location.error(column, kind))?;
+                    words.iter().for_each(|word| rom.extend_from_slice(&word.to_be_bytes()));
+                },
+                StatementKind::Data(width, items) => {
+                    for item in items {
+                        match item {
+                            DataItem::Text(bytes) => rom.extend_from_slice(bytes),
+                            DataItem::Expression(expression, column) => {
+                                let value = self.evaluate(expression, statement.address, 0)
+                                    .map_err(|(column, kind)| statement.location.error(column, kind))?;
+                                let (min, max) = if *width == 1 { (-0x80, 0xFF) } else { (-0x8000, 0xFFFF) };
+                                if value < min || value > max {
+                                    return Err(statement.location.error(*column, AssemblerErrorKind::ValueOutOfRange(value)));
+                                }
+                                rom.extend_from_slice(&(value as u16).to_be_bytes()[2 - width..]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+
+    fn read_source(&mut self, source: &str, path: &Path) -> Result<(), AssemblerError> {
+        self.include_stack.push(path.to_path_buf());
+        for (line_idx, line) in source.lines().enumerate() {
+            let location = Location { file: path.display().to_string(), line: line_idx + 1, column: 1 };
+            self.read_line(line, location)?;
+        }
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    fn read_line(&mut self, line: &str, mut location: Location) -> Result<(), AssemblerError> {
+        let tokens = tokenize(line).map_err(|(column, kind)| location.error(column, kind))?;
+        let mut tokens = tokens.as_slice();
+
+        // Label definition
+        if let [(Token::Identifier(name), column), (Token::Symbol(":"), _), rest @ ..] = tokens {
+            self.define_symbol(name, SymbolValue::Label(self.address))
+                .map_err(|kind| location.error(*column, kind))?;
+            tokens = rest;
+        }
+        let [(Token::Identifier(first), column), rest @ ..] = tokens else {
+            return match tokens.first() {
+                Some((_, column)) => Err(location.error(*column, syntax_error("expected a mnemonic or a directive"))),
+                None => Ok(())
+            };
+        };
+        location.column = *column;
+
+        match (first.to_uppercase().as_str(), rest) {
+            (_, [(Token::Symbol("="), _), expression @ ..]) => self.define_constant(first, expression, &location),
+            (_, [(Token::Identifier(equ), _), expression @ ..]) if equ.eq_ignore_ascii_case("equ") => {
+                self.define_constant(first, expression, &location)
+            },
+            ("INCLUDE", [(Token::Text(file), _)]) => self.include(file, &location),
+            ("INCLUDE", _) => Err(location.error(location.column, syntax_error("expected the file name as a string"))),
+            (directive @ ("DB" | "DW"), items) => {
+                let width = if directive == "DB" { 1 } else { 2 };
+                let items = parse_data_items(items, width).map_err(|(column, kind)| location.error(column, kind))?;
+                let size: usize = items.iter().map(|item| match item {
+                    DataItem::Text(bytes) => bytes.len(),
+                    DataItem::Expression(..) => width
+                }).sum();
+                self.push_statement(StatementKind::Data(width, items), size, location);
+                Ok(())
+            },
+            (mnemonic, operands) => {
+                let operands = parse_operands(operands).map_err(|(column, kind)| location.error(column, kind))?;
+                let size = match (mnemonic, operands.as_slice()) {
+                    ("LD", [_, Operand { kind: OperandKind::Long(_), .. }]) => 4,
+                    _ => 2
+                };
+                self.push_statement(StatementKind::Instruction(mnemonic.to_string(), operands), size, location);
+                Ok(())
+            }
+        }
+    }
+
+    fn push_statement(&mut self, kind: StatementKind, size: usize, location: Location) {
+        self.statements.push(Statement { kind, address: self.address, location });
+        self.address += size;
+    }
+
+    fn define_symbol(&mut self, name: &str, value: SymbolValue) -> Result<(), AssemblerErrorKind> {
+        if self.symbols.contains_key(name) || Assembler::<L>::MNEMONICS.contains(&name.to_uppercase().as_str()) {
+            return Err(AssemblerErrorKind::DuplicateSymbol(name.to_string()));
+        }
+        self.symbols.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &str, tokens: &[(Token, usize)], location: &Location) -> Result<(), AssemblerError> {
+        let expression = parse_full_expression(tokens).map_err(|(column, kind)| location.error(column, kind))?;
+        self.define_symbol(name, SymbolValue::Constant(expression, self.address))
+            .map_err(|kind| location.error(location.column, kind))
+    }
+
+    fn include(&mut self, file: &str, location: &Location) -> Result<(), AssemblerError> {
+        let current_path = self.include_stack.last().cloned().unwrap_or_default();
+        let path = current_path.parent().unwrap_or(Path::new("")).join(file);
+        if self.include_stack.len() >= Assembler::<L>::MAX_INCLUDE_DEPTH || self.include_stack.contains(&path) {
+            return Err(location.error(location.column, AssemblerErrorKind::Include(format!("{} includes itself", file))));
+        }
+        let source = (self.load_file)(&path)
+            .map_err(|e| location.error(location.column, AssemblerErrorKind::Include(format!("{}: {}", file, e))))?;
+        self.read_source(&source, &path)
+    }
+
+    fn evaluate(&self, expression: &Expression, address: usize, depth: usize) -> Result<i64, PartialError> {
+        match expression {
+            Expression::Number(value) => Ok(*value),
+            Expression::CurrentAddress => Ok(address as i64),
+            Expression::Symbol(name, column) => match self.symbols.get(name) {
+                Some(SymbolValue::Label(label_address)) => Ok(*label_address as i64),
+                Some(SymbolValue::Constant(..)) if depth >= Assembler::<L>::MAX_CONSTANT_DEPTH => {
+                    Err((*column, AssemblerErrorKind::RecursiveConstant(name.clone())))
+                },
+                Some(SymbolValue::Constant(constant, constant_address)) => {
+                    self.evaluate(constant, *constant_address, depth + 1)
+                },
+                None => Err((*column, AssemblerErrorKind::UndefinedSymbol(name.clone())))
+            },
+            Expression::Unary(operator, operand) => {
+                let value = self.evaluate(operand, address, depth)?;
+                Ok(match *operator {
+                    "-" => value.wrapping_neg(),
+                    "~" => !value,
+                    _ => value
+                })
+            },
+            Expression::Binary(operator, left, right) => {
+                let left_value = self.evaluate(left, address, depth)?;
+                let right_value = self.evaluate(right, address, depth)?;
+                Ok(match *operator {
+                    "+" => left_value.wrapping_add(right_value),
+                    "-" => left_value.wrapping_sub(right_value),
+                    "*" => left_value.wrapping_mul(right_value),
+                    "/" | "%" if right_value == 0 => return Err((expression_column(right), AssemblerErrorKind::DivisionByZero)),
+                    "/" => left_value.wrapping_div(right_value),
+                    "%" => left_value.wrapping_rem(right_value),
+                    "&" => left_value & right_value,
+                    "|" => left_value | right_value,
+                    "^" => left_value ^ right_value,
+                    "<<" => left_value.wrapping_shl(right_value as u32),
+                    _ => left_value.wrapping_shr(right_value as u32)
+                })
+            }
+        }
+    }
+
+    /// Evaluates the operand, which must be an expression with a value between min and max
+    fn value(&self, operand: &Operand, address: usize, min: i64, max: i64) -> Result<i64, PartialError> {
+        let OperandKind::Expression(expression) = &operand.kind else {
+            return Err((operand.column, syntax_error("expected an expression")));
+        };
+        let value = self.evaluate(expression, address, 0)?;
+        if value < min || value > max {
+            return Err((operand.column, AssemblerErrorKind::ValueOutOfRange(value)));
+        }
+        Ok(value)
+    }
+
+    /// Encodes the instruction into one or two words, the column of the mnemonic locates the invalid operands errors
+    fn encode_instruction(&self, mnemonic: &str, operands: &[Operand], address: usize, column: usize) -> Result<Vec<u16>, PartialError> {
+        use OperandKind::{Register as Reg, Keyword as Key};
+
+        let invalid_operands = || (column, AssemblerErrorKind::InvalidOperands(mnemonic.to_string()));
+        let address_of = |operand: &Operand| self.value(operand, address, 0, 0xFFF).map(|value| value as usize);
+        let byte = |operand: &Operand| self.value(operand, address, -0x80, 0xFF).map(|value| value as u8);
+        let nibble = |operand: &Operand| self.value(operand, address, 0, 0xF).map(|value| value as u8);
+
+        let opcode = match (mnemonic, operands) {
+            ("CLS", []) => Opcode::Clear,
+            ("RET", []) => Opcode::SubroutineReturn,
+            ("SCR", []) => Opcode::ScrollRight,
+            ("SCL", []) => Opcode::ScrollLeft,
+            ("EXIT", []) => Opcode::Exit,
+            ("LOW", []) => Opcode::LowResolution,
+            ("HIGH", []) => Opcode::HighResolution,
+            ("AUDIO", []) => Opcode::LoadAudioPattern,
+            ("SYS", [target]) => Opcode::MachineLanguageRoutine(address_of(target)? as u16),
+            ("JP", [target]) => Opcode::Jump(address_of(target)?),
+            ("JP", [Operand { kind: Reg(0), .. }, target]) => Opcode::JumpOffset(address_of(target)?),
+            ("CALL", [target]) => Opcode::SubroutineCall(address_of(target)?),
+            ("SCD", [n]) => Opcode::ScrollDown(nibble(n)?),
+            ("SCU", [n]) => Opcode::ScrollUp(nibble(n)?),
+            ("PLANE", [n]) => Opcode::SelectPlanes(nibble(n)?),
+            ("SE", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::SkipEqReg(*x, *y),
+            ("SE", [Operand { kind: Reg(x), .. }, value]) => Opcode::SkipEqVal(*x, byte(value)?),
+            ("SNE", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::SkipNotEqReg(*x, *y),
+            ("SNE", [Operand { kind: Reg(x), .. }, value]) => Opcode::SkipNotEqVal(*x, byte(value)?),
+            ("SAVE", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::StoreRegisterRange(*x, *y),
+            ("LOAD", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::LoadRegisterRange(*x, *y),
+            ("ADD", [Operand { kind: Key(Keyword::I), .. }, Operand { kind: Reg(x), .. }]) => Opcode::AddIndexRegister(*x),
+            ("ADD", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::AddRegister(*x, *y),
+            ("ADD", [Operand { kind: Reg(x), .. }, value]) => Opcode::AddValueToRegister(*x, byte(value)?),
+            ("OR", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::BinaryOR(*x, *y),
+            ("AND", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::BinaryAND(*x, *y),
+            ("XOR", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::BinaryXOR(*x, *y),
+            ("SUB", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::SubtractRegister(*x, *y),
+            ("SUBN", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::NegativeSubtractRegister(*x, *y),
+            ("SHL", [Operand { kind: Reg(x), .. }]) => Opcode::ShiftRegisterLeft(*x, *x),
+            ("SHL", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::ShiftRegisterLeft(*x, *y),
+            ("SHR", [Operand { kind: Reg(x), .. }]) => Opcode::ShiftRegisterRight(*x, *x),
+            ("SHR", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::ShiftRegisterRight(*x, *y),
+            ("RND", [Operand { kind: Reg(x), .. }, mask]) => Opcode::Random(*x, byte(mask)?),
+            ("DRW", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }, n]) => Opcode::Display(*x, *y, nibble(n)?),
+            ("SKP", [Operand { kind: Reg(x), .. }]) => Opcode::SkipIfKeyPressed(*x),
+            ("SKNP", [Operand { kind: Reg(x), .. }]) => Opcode::SkipIfKeyNotPressed(*x),
+            ("PITCH", [Operand { kind: Reg(x), .. }]) => Opcode::SetPitch(*x),
+            ("LD", [Operand { kind: Key(Keyword::I), .. }, Operand { kind: OperandKind::Long(expression), column }]) => {
+                let target = self.evaluate(expression, address, 0)?;
+                if !(0..=0xFFFF).contains(&target) {
+                    return Err((*column, AssemblerErrorKind::ValueOutOfRange(target)));
+                }
+                return Ok(vec![0xF000, target as u16]);
+            },
+            ("LD", [Operand { kind: Key(Keyword::I), .. }, target]) => Opcode::SetIndexRegister(address_of(target)?),
+            ("LD", [Operand { kind: Reg(x), .. }, Operand { kind: Reg(y), .. }]) => Opcode::CopyRegister(*x, *y),
+            ("LD", [Operand { kind: Reg(x), .. }, Operand { kind: Key(keyword), .. }]) => match keyword {
+                Keyword::Dt => Opcode::CopyDelayTimerValue(*x),
+                Keyword::K => Opcode::GetKey(*x),
+                Keyword::IndirectI => Opcode::LoadMemory(*x),
+                Keyword::R => Opcode::LoadFlags(*x),
+                _ => return Err(invalid_operands())
+            },
+            ("LD", [Operand { kind: Key(keyword), .. }, Operand { kind: Reg(x), .. }]) => match keyword {
+                Keyword::Dt => Opcode::SetDelayTimer(*x),
+                Keyword::St => Opcode::SetSoundTimer(*x),
+                Keyword::F => Opcode::FontCharacter(*x),
+                Keyword::Hf => Opcode::BigFontCharacter(*x),
+                Keyword::B => Opcode::DecimalConversion(*x),
+                Keyword::IndirectI => Opcode::StoreMemory(*x),
+                Keyword::R => Opcode::StoreFlags(*x),
+                _ => return Err(invalid_operands())
+            },
+            ("LD", [Operand { kind: Reg(x), .. }, value]) => Opcode::SetValueToRegister(*x, byte(value)?),
+            _ if !Assembler::<L>::MNEMONICS.contains(&mnemonic) => return Err((column, AssemblerErrorKind::UnknownMnemonic(mnemonic.to_string()))),
+            _ => return Err(invalid_operands())
+        };
+        opcode.encode().map(|instruction| vec![instruction]).ok_or_else(invalid_operands)
+    }
+}
+
+fn syntax_error(message: &str) -> AssemblerErrorKind {
+    AssemblerErrorKind::Syntax(message.to_string())
+}
+
+fn tokenize(line: &str) -> Result<Vec<(Token, usize)>, PartialError> {
+    const SYMBOLS: [&str; 18] = ["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")", "[", "]", ",", ":", "="];
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let column = i + 1;
+        let c = chars[i];
+        let start = i;
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.') { i += 1; }
+            tokens.push((Token::Identifier(chars[start..i].iter().collect()), column));
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') { i += 1; }
+            let text: String = chars[start..i].iter().filter(|&&c| c != '_').collect();
+            let lowercase = text.to_lowercase();
+            let value = match lowercase.get(..2) {
+                Some("0x") => i64::from_str_radix(&lowercase[2..], 16),
+                Some("0b") => i64::from_str_radix(&lowercase[2..], 2),
+                _ => lowercase.parse()
+            };
+            let value = value.map_err(|_| (column, syntax_error(&format!("invalid number {}", text))))?;
+            tokens.push((Token::Number(value), column));
+        } else if c == '$' {
+            tokens.push((Token::Symbol("$"), column));
+            i += 1;
+        } else if c == '"' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err((column, syntax_error("unterminated string"))),
+                    Some('"') => break,
+                    Some('\\') if i + 1 < chars.len() => { text.push(chars[i + 1]); i += 2; },
+                    Some(&c) => { text.push(c); i += 1; }
+                }
+            }
+            i += 1;
+            tokens.push((Token::Text(text), column));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let symbol = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol))
+                .ok_or((column, syntax_error(&format!("unexpected character {}", c))))?;
+            tokens.push((Token::Symbol(symbol), column));
+            i += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Splits the tokens at the commas outside of parentheses and brackets
+fn split_operands(tokens: &[(Token, usize)]) -> Vec<&[(Token, usize)]> {
+    if tokens.is_empty() { return Vec::new(); }
+    let mut operands = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, (token, _)) in tokens.iter().enumerate() {
+        match token {
+            Token::Symbol("(" | "[") => depth += 1,
+            Token::Symbol(")" | "]") => depth -= 1,
+            Token::Symbol(",") if depth == 0 => {
+                operands.push(&tokens[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    operands.push(&tokens[start..]);
+    operands
+}
+
+fn parse_operands(tokens: &[(Token, usize)]) -> Result<Vec<Operand>, PartialError> {
+    split_operands(tokens).into_iter().map(|operand_tokens| {
+        let column = operand_tokens.first().map_or(1, |(_, column)| *column);
+        let kind = match operand_tokens {
+            [] => return Err((column, syntax_error("expected an operand"))),
+            [(Token::Symbol("["), _), (Token::Identifier(i), _), (Token::Symbol("]"), _)] if i.eq_ignore_ascii_case("i") => {
+                OperandKind::Keyword(Keyword::IndirectI)
+            },
+            [(Token::Identifier(name), _)] => match (register_index(name), keyword(name)) {
+                (Some(x), _) => OperandKind::Register(x),
+                (None, Some(keyword)) => OperandKind::Keyword(keyword),
+                (None, None) => OperandKind::Expression(Expression::Symbol(name.clone(), column))
+            },
+            [(Token::Identifier(long), _), rest @ ..] if long.eq_ignore_ascii_case("long") => OperandKind::Long(parse_full_expression(rest)?),
+            _ => OperandKind::Expression(parse_full_expression(operand_tokens)?)
+        };
+        Ok(Operand { kind, column })
+    }).collect()
+}
+
+fn register_index(name: &str) -> Option<u8> {
+    let digit = name.strip_prefix(['v', 'V'])?;
+    (digit.len() == 1).then(|| u8::from_str_radix(digit, 16).ok()).flatten()
+}
+
+fn keyword(name: &str) -> Option<Keyword> {
+    match name.to_uppercase().as_str() {
+        "I" => Some(Keyword::I),
+        "DT" => Some(Keyword::Dt),
+        "ST" => Some(Keyword::St),
+        "K" => Some(Keyword::K),
+        "F" => Some(Keyword::F),
+        "HF" => Some(Keyword::Hf),
+        "B" => Some(Keyword::B),
+        "R" => Some(Keyword::R),
+        _ => None
+    }
+}
+
+fn parse_data_items(tokens: &[(Token, usize)], width: usize) -> Result<Vec<DataItem>, PartialError> {
+    split_operands(tokens).into_iter().map(|item_tokens| match item_tokens {
+        [(Token::Text(text), _)] if width == 1 => Ok(DataItem::Text(text.as_bytes().to_vec())),
+        [(Token::Text(_), column)] => Err((*column, syntax_error("strings are only allowed in db"))),
+        [] => Err((tokens.last().map_or(1, |(_, column)| *column), syntax_error("expected a value"))),
+        _ => Ok(DataItem::Expression(parse_full_expression(item_tokens)?, item_tokens[0].1))
+    }).collect()
+}
+
+fn parse_full_expression(tokens: &[(Token, usize)]) -> Result<Expression, PartialError> {
+    let mut position = 0;
+    let expression = parse_binary(tokens, &mut position, 0)?;
+    match tokens.get(position) {
+        Some((_, column)) => Err((*column, syntax_error("unexpected token in expression"))),
+        None => Ok(expression)
+    }
+}
+
+/// Binary operators from the lowest to the highest precedence
+const PRECEDENCE: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+fn parse_binary(tokens: &[(Token, usize)], position: &mut usize, level: usize) -> Result<Expression, PartialError> {
+    if level == PRECEDENCE.len() {
+        return parse_unary(tokens, position);
+    }
+    let mut left = parse_binary(tokens, position, level + 1)?;
+    while let Some((Token::Symbol(operator), _)) = tokens.get(*position) {
+        if !PRECEDENCE[level].contains(operator) { break; }
+        *position += 1;
+        let right = parse_binary(tokens, position, level + 1)?;
+        left = Expression::Binary(operator, Box::new(left), Box::new(right));
+    }
+    Ok(left)
+}
+
+fn parse_unary(tokens: &[(Token, usize)], position: &mut usize) -> Result<Expression, PartialError> {
+    let end_column = tokens.last().map_or(1, |(_, column)| column + 1);
+    let Some((token, column)) = tokens.get(*position) else {
+        return Err((end_column, syntax_error("expected an expression")));
+    };
+    *position += 1;
+    match token {
+        Token::Number(value) => Ok(Expression::Number(*value)),
+        Token::Identifier(name) => Ok(Expression::Symbol(name.clone(), *column)),
+        Token::Symbol("$") => Ok(Expression::CurrentAddress),
+        Token::Symbol(operator @ ("-" | "~" | "+")) => Ok(Expression::Unary(operator, Box::new(parse_unary(tokens, position)?))),
+        Token::Symbol("(") => {
+            let expression = parse_binary(tokens, position, 0)?;
+            match tokens.get(*position) {
+                Some((Token::Symbol(")"), _)) => { *position += 1; Ok(expression) },
+                Some((_, column)) => Err((*column, syntax_error("expected )"))),
+                None => Err((end_column, syntax_error("expected )")))
+            }
+        },
+        _ => Err((*column, syntax_error("expected an expression")))
+    }
+}
+
+fn expression_column(expression: &Expression) -> usize {
+    match expression {
+        Expression::Symbol(_, column) => *column,
+        Expression::Unary(_, operand) => expression_column(operand),
+        Expression::Binary(_, left, _) => expression_column(left),
+        _ => 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, io, path::Path};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use crate::{disassembler::{disassemble, disassemble_at}, memory::Memory};
+    use super::{assemble, Assembler, AssemblerError, AssemblerErrorKind};
+
+    #[test]
+    fn assemble_test() {
+        let source = "
+SPRITE_HEIGHT = 5
+X equ 0x10 + 2 * 4   ; constants can use expressions
+
+start:  LD V0, X
+        LD I, sprite
+loop:   DRW V0, V1, SPRITE_HEIGHT
+        ADD V0, -1
+        SE V0, 0
+        JP loop
+        JP $
+sprite:
+        db 0xF0, 0x90, 0x90, 0x90, 0xF0
+        dw start, 0b1010
+        db \"AB\"
+";
+        assert_eq!(assemble(source), Ok(vec![
+            0x60, 0x18, 0xA2, 0x0E, 0xD0, 0x15, 0x70, 0xFF, 0x30, 0x00, 0x12, 0x04, 0x12, 0x0C,
+            0xF0, 0x90, 0x90, 0x90, 0xF0, 0x02, 0x00, 0x00, 0x0A, 0x41, 0x42
+        ]));
+    }
+
+    #[test]
+    fn all_mnemonics_test() {
+        let source = "
+SYS 0x123
+CLS
+RET
+SCD 4
+SCU 2
+SCR
+SCL
+EXIT
+LOW
+HIGH
+JP V0, 0x300
+CALL 0x400
+SE V1, V2
+SNE V1, 3
+SAVE V1, V5
+LOAD V5, V1
+ADD I, V3
+SHL V4
+SHR V4, V5
+RND V6, 0x0F
+SKP V7
+SKNP V8
+PLANE 3
+AUDIO
+PITCH V9
+LD I, LONG 0xABCD
+LD VA, DT
+LD VB, K
+LD DT, VC
+LD ST, VD
+LD F, VE
+LD HF, VF
+LD B, V0
+LD [I], V1
+LD V2, [I]
+LD R, V3
+LD V4, R
+";
+        let expected: Vec<u16> = vec![
+            0x0123, 0x00E0, 0x00EE, 0x00C4, 0x00D2, 0x00FB, 0x00FC, 0x00FD, 0x00FE, 0x00FF, 0xB300, 0x2400, 0x5120,
+            0x4103, 0x5152, 0x5513, 0xF31E, 0x844E, 0x8456, 0xC60F, 0xE79E, 0xE8A1, 0xF301, 0xF002, 0xF93A, 0xF000,
+            0xABCD, 0xFA07, 0xFB0A, 0xFC15, 0xFD18, 0xFE29, 0xFF30, 0xF033, 0xF155, 0xF265, 0xF375, 0xF485
+        ];
+        assert_eq!(assemble(source), Ok(expected.iter().flat_map(|word| word.to_be_bytes()).collect()));
+    }
+
+    #[test]
+    fn include_test() {
+        let files = HashMap::from([
+            ("dir/sprites.8s", "sprite: db 0xFF\n"),
+            ("dir/loop.8s", "include \"loop.8s\"\n")
+        ]);
+        let load_file = |path: &Path| files.get(path.to_str().unwrap_or_default())
+            .map(|source| source.to_string())
+            .ok_or(io::Error::from(io::ErrorKind::NotFound));
+
+        let rom = Assembler::new(load_file, Memory::ROM_INIT_ADDRESS).assemble("LD I, sprite\ninclude \"sprites.8s\"", Path::new("dir/main.8s"));
+        assert_eq!(rom, Ok(vec![0xA2, 0x02, 0xFF]));
+
+        let error = Assembler::new(load_file, Memory::ROM_INIT_ADDRESS).assemble("include \"loop.8s\"", Path::new("dir/main.8s")).unwrap_err();
+        assert_eq!((error.file.as_str(), error.line), ("dir/loop.8s", 1));
+        assert!(matches!(error.kind, AssemblerErrorKind::Include(_)));
+    }
+
+    #[test]
+    fn error_test() {
+        let error = |source: &str| assemble(source).unwrap_err();
+
+        assert_eq!(error("CLS\n  LD V0, missing"), AssemblerError {
+            file: "<source>".to_string(),
+            line: 2,
+            column: 10,
+            kind: AssemblerErrorKind::UndefinedSymbol("missing".to_string())
+        });
+        assert_eq!(error("FOO V0").kind, AssemblerErrorKind::UnknownMnemonic("FOO".to_string()));
+        assert_eq!((error("  DRW V0, 5, 5").column, error("DRW V0, 5, 5").kind), (3, AssemblerErrorKind::InvalidOperands("DRW".to_string())));
+        assert_eq!(error("LD V0, 0x100").kind, AssemblerErrorKind::ValueOutOfRange(0x100));
+        assert_eq!(error("a: CLS\na: CLS").kind, AssemblerErrorKind::DuplicateSymbol("a".to_string()));
+        assert_eq!(error("A = B + 1\nB = A\nLD V0, A").kind, AssemblerErrorKind::RecursiveConstant("A".to_string()));
+        assert_eq!(error("LD V0, 1 / 0").kind, AssemblerErrorKind::DivisionByZero);
+        assert_eq!(error("LD V0, (1 + 2").column, 14);
+        assert_eq!(error("db \"AB").to_string(), "<source>:1:4: unterminated string");
+    }
+
+    #[test]
+    fn disassembler_round_trip_test() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for len in [0, 1, 2, 17, 256, 1024] {
+            let rom: Vec<u8> = (0..len).map(|_| rng.gen::<u8>()).collect();
+            assert_eq!(assemble(&disassemble(&rom)), Ok(rom));
+        }
+
+        // ETI-660 ROMs are loaded at 0x600, so their labels are only valid at that address
+        let rom = vec![0xA6, 0x04, 0x16, 0x02, 0xF0];
+        let load_file = |_: &Path| Err(io::Error::from(io::ErrorKind::NotFound));
+        let source = disassemble_at(&rom, Memory::ETI_660_ROM_INIT_ADDRESS);
+        assert_eq!(Assembler::new(load_file, Memory::ETI_660_ROM_INIT_ADDRESS).assemble(&source, Path::new("<source>")), Ok(rom));
+    }
+}
@@ -0,0 +1,443 @@
+use std::fmt;
+use crate::{cpu::{Cpu, error::CpuError, quirks::Quirks}, display::Display, memory::{Memory, RomTooLargeError}, rng::{RandomSource, XorShiftRng}, timer::{BuzzerFrame, Timer}};
+use crate::savestate::{self, SaveState, SaveStateError, StateReader, StateWriter};
+
+/**
+Whole CHIP-8 machine: CPU registers, memory and timers.
+
+The machine owns all of its state, so it can be cloned, stored or moved across threads freely. Use
+[`Chip8::builder`] to create one
+ */
+#[derive(Clone)]
+pub struct Chip8 {
+    cpu: Cpu,
+    memory: Memory,
+    timer: Timer,
+    rom_hash: u64,
+    instructions_per_frame: u32,
+    buzzer: BuzzerFrame,
+    last_buzzer: BuzzerFrame
+}
+
+impl Chip8 {
+    pub fn builder() -> Chip8Builder {
+        Chip8Builder::new()
+    }
+
+    /// Executes a single instruction
+    pub fn tick(&mut self) -> Result<(), CpuError> {
+        let result = self.cpu.tick(&mut self.memory, &mut self.timer);
+        self.buzzer.record(self.timer.get_sound_timer() > 0);
+        result
+    }
+
+    /**
+    Runs a frame of emulated time: the instructions of a frame, then the timers update and the vertical blank.
+
+    It stops early if the ROM exits the interpreter, and on error the timers are not updated
+     */
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        for _ in 0..self.instructions_per_frame {
+            if self.has_exited() {
+                return Ok(());
+            }
+            self.tick()?;
+        }
+        self.update_timers();
+        Ok(())
+    }
+
+    /// Runs the machine for the number of frames, see [`Chip8::run_frame`]
+    pub fn run_frames(&mut self, frames: u32) -> Result<(), CpuError> {
+        (0..frames).try_for_each(|_| self.run_frame())
+    }
+
+    /// Number of instructions executed by each frame, the CPU speed is this number times the timer frequency
+    pub fn get_instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    pub fn set_instructions_per_frame(&mut self, instructions_per_frame: u32) {
+        self.instructions_per_frame = instructions_per_frame;
+    }
+
+    /// Decrements the timers and signals the vertical blank to the CPU, it must be called at the timer frequency
+    pub fn update_timers(&mut self) {
+        self.timer.update();
+        self.cpu.vblank();
+        let buzzer = BuzzerFrame::new(self.timer.get_sound_timer() > 0);
+        self.last_buzzer = std::mem::replace(&mut self.buzzer, buzzer);
+    }
+
+    pub fn update_input_state(&mut self, input_state: [u8; 16]) {
+        self.cpu.update_input_state(input_state);
+    }
+
+    pub fn get_display(&self) -> &Display {
+        self.cpu.get_display()
+    }
+
+    /// Whether the ROM exited the interpreter with the SUPER-CHIP 0x00FD instruction
+    pub fn has_exited(&self) -> bool {
+        self.cpu.has_exited()
+    }
+
+    pub fn get_audio_pattern(&self) -> &[u8; 16] {
+        self.cpu.get_audio_pattern()
+    }
+
+    pub fn get_pitch(&self) -> u8 {
+        self.cpu.get_pitch()
+    }
+
+    /// State of the buzzer during the last frame which ran to the end, see [`Chip8::update_timers`]
+    pub fn get_buzzer_frame(&self) -> &BuzzerFrame {
+        &self.last_buzzer
+    }
+
+    pub fn get_cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn get_memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn get_timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    /// Mutable access to the CPU, used by debuggers to edit the registers
+    pub fn get_cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn get_memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn get_timer_mut(&mut self) -> &mut Timer {
+        &mut self.timer
+    }
+
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.cpu.set_tracing(enabled);
+    }
+
+    /// Takes the trace lines of the instructions executed since the last call, see [`crate::trace`]
+    pub fn take_trace(&mut self) -> String {
+        self.cpu.take_trace()
+    }
+
+    pub fn get_rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    /// Serializes the whole machine into the versioned save state format, see [`SaveState`]
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.cpu.write_state(&mut writer);
+        self.timer.write_state(&mut writer);
+        self.memory.write_state(&mut writer);
+        SaveState::encode(self.rom_hash, &writer.into_bytes())
+    }
+
+    /// Restores a save state made for the same ROM, on error the machine is left untouched
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let payload = SaveState::decode(self.rom_hash, data)?;
+        let mut reader = StateReader::new(&payload);
+        let mut chip8 = self.clone();
+        chip8.cpu.read_state(&mut reader)?;
+        chip8.timer.read_state(&mut reader)?;
+        chip8.memory.read_state(&mut reader)?;
+        *self = chip8;
+        Ok(())
+    }
+}
+
+/**
+Builder of [`Chip8`], by default the machine has no ROM, 4 KiB of memory, loads the ROM at 0x200, has the default quirks
+and a random seed, and runs [`Chip8Builder::DEFAULT_INSTRUCTIONS_PER_FRAME`] instructions per frame
+ */
+pub struct Chip8Builder {
+    rom: Vec<u8>,
+    memory_size: usize,
+    load_address: usize,
+    instructions_per_frame: u32,
+    quirks: Quirks,
+    seed: Option<u64>,
+    rng: Option<Box<dyn RandomSource>>
+}
+
+impl Chip8Builder {
+    /// About 700 instructions per second at 60 frames per second
+    pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 11;
+
+    pub fn new() -> Self {
+        Self {
+            rom: Vec::new(),
+            memory_size: Memory::DEFAULT_SIZE,
+            load_address: Memory::ROM_INIT_ADDRESS,
+            instructions_per_frame: Chip8Builder::DEFAULT_INSTRUCTIONS_PER_FRAME,
+            quirks: Quirks::default(),
+            seed: None,
+            rng: None
+        }
+    }
+
+    pub fn rom(mut self, rom: &[u8]) -> Self {
+        self.rom = rom.to_vec();
+        self
+    }
+
+    /// Size of the memory in bytes, XO-CHIP ROMs require [`Memory::XO_CHIP_SIZE`]
+    pub fn memory_size(mut self, memory_size: usize) -> Self {
+        self.memory_size = memory_size;
+        self
+    }
+
+    /// Address where the ROM is loaded and the PC starts, e.g. [`Memory::ETI_660_ROM_INIT_ADDRESS`]
+    pub fn load_address(mut self, load_address: usize) -> Self {
+        self.load_address = load_address;
+        self
+    }
+
+    pub fn instructions_per_frame(mut self, instructions_per_frame: u32) -> Self {
+        self.instructions_per_frame = instructions_per_frame;
+        self
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    /// Seed of the random number generator used by 0xCXNN, same seeds produce same runs
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Random number generator used by 0xCXNN instead of the seeded [`XorShiftRng`], the seed is then ignored
+    pub fn rng(mut self, rng: impl RandomSource + 'static) -> Self {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    /// Builds the machine, which fails if the memory size is not supported or if the ROM does not fit in the memory
+    pub fn build(self) -> Result<Chip8, BuildError> {
+        if !Memory::is_supported_size(self.memory_size) {
+            return Err(BuildError::UnsupportedMemorySize(self.memory_size));
+        }
+        let mut memory = Memory::with_size(self.memory_size);
+        memory.set_address_wrapping(self.quirks.wrap_addresses);
+        memory.load_font_data();
+        memory.load_rom_data(&self.rom, self.load_address)?;
+
+        let rng: Box<dyn RandomSource> = match (self.rng, self.seed) {
+            (Some(rng), _) => rng,
+            (None, Some(seed)) => Box::new(XorShiftRng::new(seed)),
+            (None, None) => Box::new(XorShiftRng::from_entropy())
+        };
+
+        let mut cpu = Cpu::new(self.quirks, rng);
+        cpu.set_pc(self.load_address);
+
+        Ok(Chip8 {
+            cpu,
+            memory,
+            timer: Timer::new(),
+            rom_hash: savestate::rom_hash(&self.rom),
+            instructions_per_frame: self.instructions_per_frame,
+            buzzer: BuzzerFrame::default(),
+            last_buzzer: BuzzerFrame::default()
+        })
+    }
+}
+
+impl Default for Chip8Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Error raised when the settings of a [`Chip8Builder`] do not make a valid machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// The memory size is neither [`Memory::DEFAULT_SIZE`] nor [`Memory::XO_CHIP_SIZE`]
+    UnsupportedMemorySize(usize),
+    RomTooLarge(RomTooLargeError)
+}
+
+impl From<RomTooLargeError> for BuildError {
+    fn from(error: RomTooLargeError) -> Self {
+        BuildError::RomTooLarge(error)
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::UnsupportedMemorySize(size) => write!(f, "unsupported memory size of {} bytes", size),
+            BuildError::RomTooLarge(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[cfg(test)]
+mod test {
+    use crate::{cpu::quirks::Quirks, memory::{Memory, RomTooLargeError}, rng::RandomSource};
+    use crate::savestate::SaveStateError;
+    use super::{BuildError, Chip8};
+
+    #[test]
+    fn clone_test() {
+        // V0 = random & 0xFF, V1 += 1, jump back to V1 += 1
+        let rom = [0xC0, 0xFF, 0x71, 0x01, 0x12, 0x02];
+        let mut chip8 = Chip8::builder().rom(&rom).seed(7).build().unwrap();
+        chip8.tick().unwrap();
+
+        let mut cloned = chip8.clone();
+        (0..4).for_each(|_| cloned.tick().unwrap());
+
+        assert_eq!(chip8.get_cpu().get_var_registers()[1], 0);
+        assert_eq!(cloned.get_cpu().get_var_registers()[1], 2);
+        assert_eq!(chip8.get_cpu().get_var_registers()[0], cloned.get_cpu().get_var_registers()[0]);
+    }
+
+    #[test]
+    fn seed_test() {
+        let rom = [0xC0, 0xFF];
+        let mut chip8_a = Chip8::builder().rom(&rom).seed(1234).build().unwrap();
+        let mut chip8_b = Chip8::builder().rom(&rom).seed(1234).build().unwrap();
+        chip8_a.tick().unwrap();
+        chip8_b.tick().unwrap();
+
+        assert_eq!(chip8_a.get_cpu().get_var_registers()[0], chip8_b.get_cpu().get_var_registers()[0]);
+    }
+
+    /// Counter which frontends could use to script the random numbers
+    #[derive(Clone)]
+    struct CounterRng(u64);
+
+    impl RandomSource for CounterRng {
+        fn next_u8(&mut self) -> u8 {
+            self.0 += 1;
+            self.0 as u8
+        }
+
+        fn get_state(&self) -> u64 {
+            self.0
+        }
+
+        fn set_state(&mut self, state: u64) -> bool {
+            self.0 = state;
+            true
+        }
+
+        fn clone_box(&self) -> Box<dyn RandomSource> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn custom_rng_test() {
+        // V0 = random, V1 = random
+        let rom = [0xC0, 0xFF, 0xC1, 0xFF];
+        let mut chip8 = Chip8::builder().rom(&rom).seed(1234).rng(CounterRng(10)).build().unwrap();
+        let state = chip8.save_state();
+        (0..2).for_each(|_| chip8.tick().unwrap());
+        assert_eq!(chip8.get_cpu().get_var_registers()[..2], [11, 12]);
+
+        // The generator state is part of the save state
+        chip8.load_state(&state).unwrap();
+        chip8.tick().unwrap();
+        assert_eq!(chip8.get_cpu().get_var_registers()[0], 11);
+    }
+
+    #[test]
+    fn save_state_test() {
+        // V0 = random, I = 0x300, store V0, call 0x20A, V1 += 1 in loop
+        let rom = [0xC0, 0xFF, 0xA3, 0x00, 0xF0, 0x55, 0x22, 0x0A, 0x00, 0x00, 0x71, 0x01, 0x12, 0x0A];
+        let mut chip8 = Chip8::builder().rom(&rom).build().unwrap();
+        (0..4).for_each(|_| chip8.tick().unwrap());
+        let state = chip8.save_state();
+        let mut expected = chip8.clone();
+
+        let mut restored = Chip8::builder().rom(&rom).build().unwrap();
+        restored.load_state(&state).unwrap();
+        (0..10).for_each(|_| {
+            expected.tick().unwrap();
+            restored.tick().unwrap();
+        });
+
+        assert_eq!(restored.get_cpu().get_var_registers(), expected.get_cpu().get_var_registers());
+        assert_eq!(restored.get_cpu().get_pc(), expected.get_cpu().get_pc());
+        assert_eq!(restored.get_memory().read_byte(0x300), expected.get_memory().read_byte(0x300));
+        assert_eq!(restored.save_state(), expected.save_state());
+    }
+
+    #[test]
+    fn save_state_other_rom_test() {
+        let state = Chip8::builder().rom(&[0x00, 0xE0]).build().unwrap().save_state();
+        let mut chip8 = Chip8::builder().rom(&[0x00, 0xEE]).build().unwrap();
+
+        assert_eq!(chip8.load_state(&state), Err(SaveStateError::RomMismatch));
+    }
+
+    #[test]
+    fn save_state_truncated_test() {
+        let mut chip8 = Chip8::builder().rom(&[0x00, 0xE0]).build().unwrap();
+        let state = chip8.save_state();
+
+        assert_eq!(chip8.load_state(&state[..state.len() - 1]), Err(SaveStateError::Truncated));
+    }
+
+    #[test]
+    fn xo_chip_test() {
+        // I = 0xFF00 with a skipped 0xF000 before, store V0-V1, load pattern, V2 = 100, pitch = V2, select planes 1 and 2
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x00, 0x00, 0xF0, 0x00, 0xFF, 0x00, 0x50, 0x12, 0xF0, 0x02, 0x62, 100, 0xF2, 0x3A, 0xF3, 0x01];
+        let mut chip8 = Chip8::builder()
+            .rom(&rom)
+            .memory_size(Memory::XO_CHIP_SIZE)
+            .quirks(Quirks::XO_CHIP)
+            .build()
+            .unwrap();
+        (0..7).for_each(|_| chip8.tick().unwrap());
+
+        assert_eq!(chip8.get_cpu().get_index_register(), 0xFF00);
+        assert_eq!(chip8.get_memory().read_byte(0xFF00), Ok(0));
+        assert_eq!(chip8.get_audio_pattern(), &[0; 16]);
+        assert_eq!(chip8.get_pitch(), 100);
+        assert_eq!(chip8.get_display().get_selected_planes(), 0b11);
+    }
+
+    #[test]
+    fn build_error_test() {
+        assert_eq!(Chip8::builder().memory_size(0x800).build().err(), Some(BuildError::UnsupportedMemorySize(0x800)));
+
+        let error = RomTooLargeError { rom_size: 0xE01, load_address: 0x200, memory_size: 0x1000 };
+        assert_eq!(Chip8::builder().rom(&[0; 0xE01]).build().err(), Some(BuildError::RomTooLarge(error)));
+    }
+
+    #[test]
+    fn buzzer_test() {
+        // V0 = 2, sound timer = V0, then loop forever
+        let rom = [0x60, 0x02, 0xF0, 0x18, 0x12, 0x04];
+        let mut chip8 = Chip8::builder().rom(&rom).instructions_per_frame(4).build().unwrap();
+
+        // The buzzer starts in the middle of the first frame, then the sound timer counts down at each frame end
+        chip8.run_frame().unwrap();
+        let buzzer = chip8.get_buzzer_frame();
+        assert!(!buzzer.is_on_at(0.25));
+        assert!(buzzer.is_on_at(0.5));
+        chip8.run_frame().unwrap();
+        assert!(chip8.get_buzzer_frame().is_on_at(0.0));
+        assert!(chip8.get_buzzer_frame().is_on());
+        chip8.run_frame().unwrap();
+        assert!(!chip8.get_buzzer_frame().is_on_at(0.0));
+        assert!(!chip8.get_buzzer_frame().is_on());
+    }
+}
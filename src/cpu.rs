@@ -1,40 +1,126 @@
+pub mod error;
 pub mod opcode;
 pub mod opcode_impl;
+pub mod quirks;
 
-use crate::{memory::Memory, stack::Stack, timer::Timer};
-use self::opcode::Opcode;
+use crate::{display::Display, memory::Memory, rng::RandomSource, savestate::{SaveStateError, StateReader, StateWriter}, stack::Stack, timer::Timer, trace::format_trace_line};
+use self::{error::{CpuError, CpuErrorKind}, opcode::Opcode, quirks::Quirks};
 
-pub struct Cpu<'a> {
+#[derive(Clone)]
+pub struct Cpu {
     pc: usize,
     index_reg: usize,
     var_regs: [u8; 16],
     stack: Stack<u16>,
-    display: [[u8; 64]; 32],
+    display: Display,
+    rpl_flags: [u8; 16],
+    audio_pattern: [u8; 16],
+    pitch: u8,
     input_state: [u8; 16],
     last_input_state: [u8; 16],
-    memory: &'a mut Memory,
-    op_frequency: u32
+    quirks: Quirks,
+    rng: Box<dyn RandomSource>,
+    waiting_vblank: bool,
+    exited: bool,
+    /// Number of instructions executed since the CPU was created
+    cycles: u64,
+    /// Trace lines of the executed instructions not taken yet, if tracing is enabled
+    trace: Option<String>
 }
 
-impl<'a> Cpu<'a> {
-    pub fn new(memory: &'a mut Memory) -> Self {
+impl Cpu {
+    /// Square wave played by the buzzer until a ROM loads its own pattern with 0xF002
+    pub const DEFAULT_AUDIO_PATTERN: [u8; 16] = [0xF0; 16];
+    /// Pitch which plays the audio pattern at 4000 bits per second
+    pub const DEFAULT_PITCH: u8 = 64;
+
+    pub fn new(quirks: Quirks, rng: Box<dyn RandomSource>) -> Self {
         Cpu {
             pc: Memory::ROM_INIT_ADDRESS,
             index_reg: 0,
             var_regs: [0; 16],
-            stack: Stack::new(),
-            display: [[0; 64]; 32],
+            stack: Stack::with_max_depth(quirks.stack_depth as usize),
+            display: Display::new(),
+            rpl_flags: [0; 16],
+            audio_pattern: Cpu::DEFAULT_AUDIO_PATTERN,
+            pitch: Cpu::DEFAULT_PITCH,
             input_state: [0; 16],
             last_input_state: [0; 16],
-            memory,
-            op_frequency: 700
+            quirks,
+            rng,
+            waiting_vblank: false,
+            exited: false,
+            cycles: 0,
+            trace: None
         }
     }
 
-    pub fn tick(&mut self, timer: &mut Timer) {
-        let instruction = self.fetch();
+    /**
+    Fetches, decodes and executes the instruction pointed by the program counter.
+
+    On error the program counter is already past the faulty instruction, so calling tick again skips it as a NOP. This
+    holds for the instructions which could not be fetched too, see [`CpuError::is_fetch_error`]
+     */
+    pub fn tick(&mut self, memory: &mut Memory, timer: &mut Timer) -> Result<(), CpuError> {
+        if self.waiting_vblank || self.exited { return Ok(()); }
+
+        let pc = self.pc;
+        let instruction = self.fetch(memory)
+            .map_err(|kind| CpuError { pc, instruction: 0x0000, kind })?;
+        self.cycles += 1;
+        if self.trace.is_some() {
+            let line = format_trace_line(self.cycles, pc, instruction, self, memory, timer);
+            if let Some(trace) = self.trace.as_mut() {
+                trace.push_str(&line);
+                trace.push('\n');
+            }
+        }
         let opcode = self.decode(instruction);
-        self.execute(opcode, timer);
+        let changes_display = opcode.changes_display();
+        let calls_subroutine = matches!(opcode, Opcode::SubroutineCall(_));
+        self.execute(opcode, memory, timer)
+            .and_then(|_| match self.quirks.vip_memory_map {
+                true => self.sync_vip_memory(memory, changes_display, calls_subroutine),
+                false => Ok(())
+            })
+            .map_err(|kind| CpuError { pc, instruction, kind })
+    }
+
+    /**
+    Keeps the display and the call stack in sync with their areas of the VIP memory map.
+
+    The memory holds the reference copy, which is loaded back after each instruction, except after the instructions
+    which changed the display or pushed onto the stack: those copy their change into the memory instead. The high
+    resolution display has no room in the memory map, so it is not mirrored
+     */
+    fn sync_vip_memory(&mut self, memory: &mut Memory, changes_display: bool, calls_subroutine: bool) -> Result<(), CpuErrorKind> {
+        if !self.display.is_hires() {
+            let bitmap = self.display.to_bitmap();
+            match changes_display {
+                true => bitmap.iter().enumerate()
+                    .try_for_each(|(i, &byte)| memory.write_byte(Memory::VIP_DISPLAY_ADDRESS + i, byte))?,
+                false => {
+                    let memory_bitmap = (0..bitmap.len())
+                        .map(|i| memory.read_byte(Memory::VIP_DISPLAY_ADDRESS + i))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if memory_bitmap != bitmap {
+                        self.display.load_bitmap(&memory_bitmap);
+                    }
+                }
+            }
+        }
+
+        for (level, address) in self.stack.as_mut_slice().iter_mut().enumerate() {
+            let stack_address = Memory::VIP_STACK_ADDRESS + 2 * level;
+            match calls_subroutine {
+                true => {
+                    memory.write_byte(stack_address, (*address >> 8) as u8)?;
+                    memory.write_byte(stack_address + 1, *address as u8)?;
+                },
+                false => *address = u16::from_be_bytes([memory.read_byte(stack_address)?, memory.read_byte(stack_address + 1)?])
+            }
+        }
+        Ok(())
     }
 
     pub fn update_input_state(&mut self, input_state: [u8; 16]) {
@@ -42,74 +128,200 @@ impl<'a> Cpu<'a> {
         self.input_state = input_state;
     }
 
-    pub fn get_display(&self) -> &[[u8; 64]; 32] {
+    /// Signals the vertical blank interrupt (60 Hz), which resumes the CPU stalled by the display wait quirk
+    pub fn vblank(&mut self) {
+        self.waiting_vblank = false;
+    }
+
+    /// Whether the CPU is stalled by the display wait quirk until the next vertical blank
+    pub fn is_waiting_vblank(&self) -> bool {
+        self.waiting_vblank
+    }
+
+    pub fn get_quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    pub fn get_display(&self) -> &Display {
         &self.display
     }
 
-    pub fn get_cpu_frequency(&self) -> u32 {
-        self.op_frequency
+    /// Whether the ROM exited the interpreter with 0x00FD
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    /// XO-CHIP 1-bit audio pattern, played from the most significant bit of the first byte
+    pub fn get_audio_pattern(&self) -> &[u8; 16] {
+        &self.audio_pattern
+    }
+
+    pub fn get_pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    pub fn get_pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn get_index_register(&self) -> usize {
+        self.index_reg
+    }
+
+    pub fn get_var_registers(&self) -> &[u8; 16] {
+        &self.var_regs
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub fn set_index_register(&mut self, value: usize) {
+        self.index_reg = value;
+    }
+
+    pub fn set_var_register(&mut self, reg_idx: usize, value: u8) {
+        self.var_regs[reg_idx] = value;
+    }
+
+    pub fn get_stack(&self) -> &Stack<u16> {
+        &self.stack
+    }
+
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
     }
 
-    fn fetch(&mut self) -> u16 {
-        let instruction = self.memory.read_instruction(self.pc);
+    /// Enables or disables the trace of the executed instructions, see [`format_trace_line`]
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.trace = enabled.then(String::new);
+    }
+
+    /// Takes the trace lines written since the last call, empty if tracing is disabled
+    pub fn take_trace(&mut self) -> String {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.pc as u32);
+        writer.write_u32(self.index_reg as u32);
+        writer.write_bytes(&self.var_regs);
+        self.stack.write_state(writer);
+        self.display.write_state(writer);
+        writer.write_bytes(&self.rpl_flags);
+        writer.write_bytes(&self.audio_pattern);
+        writer.write_u8(self.pitch);
+        writer.write_bytes(&self.input_state);
+        writer.write_bytes(&self.last_input_state);
+        writer.write_u64(self.rng.get_state());
+        writer.write_bool(self.waiting_vblank);
+        writer.write_bool(self.exited);
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.pc = reader.read_u32()? as usize;
+        self.index_reg = reader.read_u32()? as usize;
+        self.var_regs = reader.read_array()?;
+        self.stack.read_state(reader)?;
+        self.display.read_state(reader)?;
+        self.rpl_flags = reader.read_array()?;
+        self.audio_pattern = reader.read_array()?;
+        self.pitch = reader.read_u8()?;
+        self.input_state = reader.read_array()?;
+        self.last_input_state = reader.read_array()?;
+        if !self.rng.set_state(reader.read_u64()?) {
+            return Err(SaveStateError::InvalidField("random number generator state"));
+        }
+        self.waiting_vblank = reader.read_bool()?;
+        self.exited = reader.read_bool()?;
+        Ok(())
+    }
+
+    fn fetch(&mut self, memory: &Memory) -> Result<u16, CpuErrorKind> {
+        let instruction = memory.read_instruction(self.pc);
         self.next_opcode();
 
-        instruction
+        Ok(instruction?)
     }
 
     fn decode(&self, instruction: u16) -> Opcode {
         Opcode::from(instruction)
     }
 
-    fn execute(&mut self, opcode: Opcode, timer: &mut Timer) {
+    fn execute(&mut self, opcode: Opcode, memory: &mut Memory, timer: &mut Timer) -> Result<(), CpuErrorKind> {
         match opcode {
-            Opcode::MachineLanguageRoutine(_) => unimplemented!(),
+            Opcode::MachineLanguageRoutine(address) => return Err(CpuErrorKind::UnsupportedMachineRoutine(address)),
             Opcode::Clear => self.opcode_clear(),
-            Opcode::SubroutineReturn => self.opcode_subroutine_return(),
-            Opcode::SubroutineCall(address) => self.opcode_subroutine_call(address),
+            Opcode::SubroutineReturn => self.opcode_subroutine_return()?,
+            Opcode::ScrollDown(n_rows) => self.display.scroll_down(n_rows as usize),
+            Opcode::ScrollUp(n_rows) => self.display.scroll_up(n_rows as usize),
+            Opcode::ScrollRight => self.display.scroll_right(4),
+            Opcode::ScrollLeft => self.display.scroll_left(4),
+            Opcode::Exit => self.exited = true,
+            Opcode::LowResolution => self.display.set_hires(false),
+            Opcode::HighResolution => self.display.set_hires(true),
+            Opcode::SubroutineCall(address) => self.opcode_subroutine_call(address)?,
             Opcode::Jump(address) => self.opcode_jump(address),
-            Opcode::SkipEqVal(reg_idx, value) => if self.var_regs[reg_idx as usize] == value { self.next_opcode(); },
-            Opcode::SkipNotEqVal(reg_idx, value) => if self.var_regs[reg_idx as usize] != value { self.next_opcode(); },
-            Opcode::SkipEqReg(reg_idx_x, reg_idx_y) => if self.var_regs[reg_idx_x as usize] == self.var_regs[reg_idx_y as usize] { self.next_opcode(); },
-            Opcode::SkipNotEqReg(reg_idx_x, reg_idx_y) => if self.var_regs[reg_idx_x as usize] != self.var_regs[reg_idx_y as usize] { self.next_opcode(); },
+            Opcode::SkipEqVal(reg_idx, value) => if self.var_regs[reg_idx as usize] == value { self.skip_next_opcode(memory); },
+            Opcode::SkipNotEqVal(reg_idx, value) => if self.var_regs[reg_idx as usize] != value { self.skip_next_opcode(memory); },
+            Opcode::SkipEqReg(reg_idx_x, reg_idx_y) => if self.var_regs[reg_idx_x as usize] == self.var_regs[reg_idx_y as usize] { self.skip_next_opcode(memory); },
+            Opcode::SkipNotEqReg(reg_idx_x, reg_idx_y) => if self.var_regs[reg_idx_x as usize] != self.var_regs[reg_idx_y as usize] { self.skip_next_opcode(memory); },
+            Opcode::StoreRegisterRange(reg_idx_x, reg_idx_y) => self.opcode_store_register_range(memory, reg_idx_x, reg_idx_y)?,
+            Opcode::LoadRegisterRange(reg_idx_x, reg_idx_y) => self.opcode_load_register_range(memory, reg_idx_x, reg_idx_y)?,
             Opcode::SetValueToRegister(reg_idx, value) => self.opcode_set_value_to_register(reg_idx, value),
             Opcode::AddValueToRegister(reg_idx, value) => self.opcode_add_value_to_register(reg_idx, value),
             Opcode::CopyRegister(reg_idx_x, reg_idx_y) => self.var_regs[reg_idx_x as usize] = self.var_regs[reg_idx_y as usize],
-            Opcode::BinaryOR(reg_idx_x, reg_idx_y) => self.var_regs[reg_idx_x as usize] |= self.var_regs[reg_idx_y as usize],
-            Opcode::BinaryAND(reg_idx_x, reg_idx_y) => self.var_regs[reg_idx_x as usize] &= self.var_regs[reg_idx_y as usize],
-            Opcode::BinaryXOR(reg_idx_x, reg_idx_y) => self.var_regs[reg_idx_x as usize] ^= self.var_regs[reg_idx_y as usize],
+            Opcode::BinaryOR(reg_idx_x, reg_idx_y) => self.opcode_binary_operation(reg_idx_x, reg_idx_y, |x, y| x | y),
+            Opcode::BinaryAND(reg_idx_x, reg_idx_y) => self.opcode_binary_operation(reg_idx_x, reg_idx_y, |x, y| x & y),
+            Opcode::BinaryXOR(reg_idx_x, reg_idx_y) => self.opcode_binary_operation(reg_idx_x, reg_idx_y, |x, y| x ^ y),
             Opcode::AddRegister(reg_idx_x, reg_idx_y) => self.opcode_add_registers(reg_idx_x, reg_idx_y),
             Opcode::SubtractRegister(reg_idx_x, reg_idx_y) => self.opcode_subtract_registers(reg_idx_x, reg_idx_x, reg_idx_y),
             Opcode::NegativeSubtractRegister(reg_idx_x, reg_idx_y) => self.opcode_subtract_registers(reg_idx_x, reg_idx_y, reg_idx_x),
             Opcode::ShiftRegisterLeft(reg_idx_x, reg_idx_y) => self.opcode_shift_left_register(reg_idx_x, reg_idx_y),
             Opcode::ShiftRegisterRight(reg_idx_x, reg_idx_y) => self.opcode_shift_right_register(reg_idx_x, reg_idx_y),
             Opcode::SetIndexRegister(value) => self.opcode_set_index_register(value),
+            Opcode::SetLongIndexRegister => self.opcode_set_long_index_register(memory)?,
             Opcode::JumpOffset(address) => self.opcode_jump_with_offset(address),
             Opcode::Random(reg_idx, mask) => self.opcode_random(reg_idx, mask),
-            Opcode::Display(reg_idx_x, reg_idx_y, n_pixels) => self.opcode_display(reg_idx_x, reg_idx_y, n_pixels),
-            Opcode::SkipIfKeyPressed(reg_idx) => self.opcode_skip_if_key_pressed(reg_idx),
-            Opcode::SkipIfKeyNotPressed(reg_idx) => self.opcode_skip_if_key_not_pressed(reg_idx),
+            Opcode::Display(reg_idx_x, reg_idx_y, n_pixels) => self.opcode_display(memory, reg_idx_x, reg_idx_y, n_pixels)?,
+            Opcode::SkipIfKeyPressed(reg_idx) => self.opcode_skip_if_key_pressed(memory, reg_idx),
+            Opcode::SkipIfKeyNotPressed(reg_idx) => self.opcode_skip_if_key_not_pressed(memory, reg_idx),
             Opcode::CopyDelayTimerValue(reg_idx) => self.var_regs[reg_idx as usize] = timer.get_delay_timer(),
             Opcode::SetDelayTimer(reg_idx) => timer.set_delay_timer(self.var_regs[reg_idx as usize]),
             Opcode::SetSoundTimer(reg_idx) => timer.set_sound_timer(self.var_regs[reg_idx as usize]),
             Opcode::AddIndexRegister(reg_idx) => self.opcode_add_index_register(reg_idx),
             Opcode::GetKey(reg_idx) => self.opcode_get_key(reg_idx),
-            Opcode::FontCharacter(reg_idx) => self.opcode_set_index_register_to_font(reg_idx),
-            Opcode::DecimalConversion(reg_idx) => self.opcode_apply_decimal_conversion(reg_idx),
-            Opcode::StoreMemory(last_reg_idx) => self.opcode_store_memory(last_reg_idx),
-            Opcode::LoadMemory(last_reg_idx) => self.opcode_load_memory(last_reg_idx),
-            Opcode::Unknown => panic!("Unknown opcode"),
+            Opcode::FontCharacter(reg_idx) => self.opcode_set_index_register_to_font(memory, reg_idx),
+            Opcode::BigFontCharacter(reg_idx) => self.opcode_set_index_register_to_big_font(memory, reg_idx),
+            Opcode::DecimalConversion(reg_idx) => self.opcode_apply_decimal_conversion(memory, reg_idx)?,
+            Opcode::StoreMemory(last_reg_idx) => self.opcode_store_memory(memory, last_reg_idx)?,
+            Opcode::LoadMemory(last_reg_idx) => self.opcode_load_memory(memory, last_reg_idx)?,
+            Opcode::StoreFlags(last_reg_idx) => self.opcode_store_flags(last_reg_idx),
+            Opcode::LoadFlags(last_reg_idx) => self.opcode_load_flags(last_reg_idx),
+            Opcode::SelectPlanes(planes) => self.display.select_planes(planes),
+            Opcode::LoadAudioPattern => self.opcode_load_audio_pattern(memory)?,
+            Opcode::SetPitch(reg_idx) => self.pitch = self.var_regs[reg_idx as usize],
+            Opcode::Unknown => return Err(CpuErrorKind::UnknownOpcode),
         }
+
+        Ok(())
     }
 
     fn next_opcode(&mut self) {
         self.pc += 2;
     }
 
+    /// Skips the next instruction, which is 4 bytes long in case of the XO-CHIP 0xF000 NNNN
+    fn skip_next_opcode(&mut self, memory: &Memory) {
+        if memory.read_instruction(self.pc) == Ok(0xF000) { self.next_opcode(); }
+        self.next_opcode();
+    }
+
     fn prev_opcode(&mut self) {
         self.pc -= 2;
     }
 
+    /// Sets VF, which must be done after writing the result so the flag wins when VF is also the destination
     fn set_flag_register(&mut self, value: u8) {
         self.var_regs[0xF] = value;
     }
@@ -117,8 +329,214 @@ impl<'a> Cpu<'a> {
 
 #[cfg(test)]
 mod test {
+    use crate::{memory::Memory, rng::XorShiftRng, timer::Timer};
+    use super::{Cpu, error::{CpuError, CpuErrorKind}, quirks::Quirks};
+
+    fn load_rom(rom: &[u8]) -> Memory {
+        let mut memory = Memory::new();
+        memory.load_font_data();
+        memory.load_rom_data(rom, Memory::ROM_INIT_ADDRESS).unwrap();
+        memory
+    }
+
+    fn run_rom(rom: &[u8], quirks: Quirks, ticks: usize) -> Cpu {
+        let mut memory = load_rom(rom);
+        let mut timer = Timer::new();
+        let mut cpu = Cpu::new(quirks, Box::new(XorShiftRng::new(0)));
+        (0..ticks).for_each(|_| cpu.tick(&mut memory, &mut timer).unwrap());
+        cpu
+    }
+
     #[test]
     fn cpu_test() {
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn shift_quirk_test() {
+        // V1 = 0x03, V2 = 0x80, V1 >>= V2
+        let rom = [0x61, 0x03, 0x62, 0x80, 0x81, 0x26];
+
+        let cpu = run_rom(&rom, Quirks::SUPER_CHIP, 3);
+        assert_eq!(cpu.var_regs[1], 0x01);
+        assert_eq!(cpu.var_regs[0xF], 1);
+
+        let cpu = run_rom(&rom, Quirks::COSMAC_VIP, 3);
+        assert_eq!(cpu.var_regs[1], 0x40);
+        assert_eq!(cpu.var_regs[0xF], 0);
+    }
+
+    #[test]
+    fn jump_offset_quirk_test() {
+        // V0 = 0x10, V3 = 0x20, jump to 0x300 + offset
+        let rom = [0x60, 0x10, 0x63, 0x20, 0xB3, 0x00];
+
+        assert_eq!(run_rom(&rom, Quirks::COSMAC_VIP, 3).pc, 0x310);
+        assert_eq!(run_rom(&rom, Quirks::CHIP_48, 3).pc, 0x320);
+    }
+
+    #[test]
+    fn vf_reset_quirk_test() {
+        // VF = 0x05, V1 |= V2
+        let rom = [0x6F, 0x05, 0x81, 0x21];
+
+        assert_eq!(run_rom(&rom, Quirks::COSMAC_VIP, 2).var_regs[0xF], 0);
+        assert_eq!(run_rom(&rom, Quirks::SUPER_CHIP, 2).var_regs[0xF], 5);
+    }
+
+    #[test]
+    fn vf_destination_test() {
+        // The flag is written after the result, so it wins when VF is also the destination
+        let flag = |rom: &[u8]| run_rom(rom, Quirks::default(), 3).var_regs[0xF];
+
+        // VF = 0xFF, V1 = 0x01, VF += V1
+        assert_eq!(flag(&[0x6F, 0xFF, 0x61, 0x01, 0x8F, 0x14]), 1);
+        // VF = 0x01, V1 = 0x02, VF -= V1
+        assert_eq!(flag(&[0x6F, 0x01, 0x61, 0x02, 0x8F, 0x15]), 0);
+        // VF = 0x03, V1 = 0x05, VF = V1 - VF
+        assert_eq!(flag(&[0x6F, 0x03, 0x61, 0x05, 0x8F, 0x17]), 1);
+        // VF = 0x02, VF >>= 1
+        assert_eq!(flag(&[0x6F, 0x02, 0x61, 0x00, 0x8F, 0xF6]), 0);
+        // VF = 0x81, VF <<= 1
+        assert_eq!(flag(&[0x6F, 0x81, 0x61, 0x00, 0x8F, 0xFE]), 1);
+    }
+
+    #[test]
+    fn load_store_index_quirk_test() {
+        // I = 0x400, store V0-V2
+        let rom = [0xA4, 0x00, 0xF2, 0x55];
+
+        assert_eq!(run_rom(&rom, Quirks::SUPER_CHIP, 2).index_reg, 0x400);
+        assert_eq!(run_rom(&rom, Quirks::CHIP_48, 2).index_reg, 0x402);
+        assert_eq!(run_rom(&rom, Quirks::COSMAC_VIP, 2).index_reg, 0x403);
+    }
+
+    #[test]
+    fn display_wait_quirk_test() {
+        // I = font 0, draw at (0, 0), V1 = 0x01
+        let mut memory = load_rom(&[0xA0, 0x50, 0xD0, 0x05, 0x61, 0x01]);
+        let mut timer = Timer::new();
+        let mut cpu = Cpu::new(Quirks::COSMAC_VIP, Box::new(XorShiftRng::new(0)));
+
+        (0..3).for_each(|_| cpu.tick(&mut memory, &mut timer).unwrap());
+        assert_eq!(cpu.var_regs[1], 0);
+
+        cpu.vblank();
+        cpu.tick(&mut memory, &mut timer).unwrap();
+        assert_eq!(cpu.var_regs[1], 1);
+    }
+
+    #[test]
+    fn sprite_wrap_quirk_test() {
+        // I = font 0, V0 = 62, draw at (62, 0)
+        let rom = [0xA0, 0x50, 0x60, 62, 0xD0, 0x15];
+
+        let cpu = run_rom(&rom, Quirks::SUPER_CHIP, 3);
+        assert_eq!(cpu.display.get_pixel(0, 0), 0);
+
+        let cpu = run_rom(&rom, Quirks::XO_CHIP, 3);
+        assert_eq!(cpu.display.get_pixel(0, 0), 1);
+        assert_eq!(cpu.display.get_pixel(1, 0), 1);
+    }
+
+    #[test]
+    fn hires_big_sprite_test() {
+        // Hires, I = big font 0, V0 = 120, draw 16x16 at (120, 0)
+        let rom = [0x00, 0xFF, 0xA0, 0xA0, 0x60, 120, 0xD0, 0x10];
+
+        let cpu = run_rom(&rom, Quirks::SUPER_CHIP, 4);
+        assert!(cpu.display.is_hires());
+        assert_eq!(cpu.display.get_pixel(127, 0), 1);
+        assert_eq!(cpu.display.get_pixel(120, 2), 1);
+        assert_eq!(cpu.display.get_pixel(122, 2), 0);
+    }
+
+    #[test]
+    fn flags_test() {
+        // V0 = 1, V1 = 2, store V0-V1 into flags, clear V0-V1, load V0-V1 from flags
+        let rom = [0x60, 0x01, 0x61, 0x02, 0xF1, 0x75, 0x60, 0x00, 0x61, 0x00, 0xF1, 0x85];
+
+        let cpu = run_rom(&rom, Quirks::SUPER_CHIP, 6);
+        assert_eq!(cpu.var_regs[0], 1);
+        assert_eq!(cpu.var_regs[1], 2);
+    }
+
+    #[test]
+    fn exit_test() {
+        // Exit, V0 = 1
+        let cpu = run_rom(&[0x00, 0xFD, 0x60, 0x01], Quirks::SUPER_CHIP, 2);
+
+        assert!(cpu.has_exited());
+        assert_eq!(cpu.var_regs[0], 0);
+    }
+
+    #[test]
+    fn trace_test() {
+        // V0 = 1, exit, V0 = 2
+        let mut memory = load_rom(&[0x60, 0x01, 0x00, 0xFD, 0x60, 0x02]);
+        let mut timer = Timer::new();
+        let mut cpu = Cpu::new(Quirks::SUPER_CHIP, Box::new(XorShiftRng::new(0)));
+        cpu.set_tracing(true);
+
+        (0..3).for_each(|_| cpu.tick(&mut memory, &mut timer).unwrap());
+        let trace = cpu.take_trace();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(cpu.get_cycles(), 2);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("CYC:1 PC:0200 OP:6001 V0:00"));
+        assert!(lines[1].starts_with("CYC:2 PC:0202 OP:00FD V0:01"));
+        assert!(cpu.take_trace().is_empty());
+    }
+
+    #[test]
+    fn error_test() {
+        // Unknown opcode, return with empty stack, machine routine, store past the end of memory
+        let mut memory = load_rom(&[0xFF, 0xFF, 0x00, 0xEE, 0x01, 0x23, 0xAF, 0xFF, 0xF1, 0x55]);
+        let mut timer = Timer::new();
+        let mut cpu = Cpu::new(Quirks::default(), Box::new(XorShiftRng::new(0)));
+
+        assert_eq!(cpu.tick(&mut memory, &mut timer), Err(CpuError { pc: 0x200, instruction: 0xFFFF, kind: CpuErrorKind::UnknownOpcode }));
+        assert_eq!(cpu.tick(&mut memory, &mut timer), Err(CpuError { pc: 0x202, instruction: 0x00EE, kind: CpuErrorKind::StackUnderflow }));
+        assert_eq!(cpu.tick(&mut memory, &mut timer), Err(CpuError { pc: 0x204, instruction: 0x0123, kind: CpuErrorKind::UnsupportedMachineRoutine(0x123) }));
+        assert_eq!(cpu.tick(&mut memory, &mut timer), Ok(()));
+        assert_eq!(cpu.tick(&mut memory, &mut timer), Err(CpuError { pc: 0x208, instruction: 0xF155, kind: CpuErrorKind::OutOfBounds(0x1000) }));
+
+        // The PC moves past an instruction which can not be fetched too
+        cpu.set_pc(0xFFF);
+        let error = cpu.tick(&mut memory, &mut timer).unwrap_err();
+        assert_eq!(error, CpuError { pc: 0xFFF, instruction: 0x0000, kind: CpuErrorKind::OutOfBounds(0x1000) });
+        assert!(error.is_fetch_error());
+        assert_eq!(cpu.get_pc(), 0x1001);
+    }
+
+    #[test]
+    fn vip_memory_map_test() {
+        // V0 = 0xFF, I = 0xF00, store V0, call 0x20C, loop, loop, then overwrite the return address with 0x20A and return
+        let mut memory = load_rom(&[0x60, 0xFF, 0xAF, 0x00, 0xF0, 0x55, 0x22, 0x0C, 0x12, 0x08, 0x12, 0x0A,
+            0x60, 0x02, 0x61, 0x0A, 0xAE, 0xA0, 0xF1, 0x55, 0x00, 0xEE]);
+        let mut timer = Timer::new();
+        let mut cpu = Cpu::new(Quirks { vip_memory_map: true, ..Quirks::COSMAC_VIP }, Box::new(XorShiftRng::new(0)));
+        (0..3).for_each(|_| cpu.tick(&mut memory, &mut timer).unwrap());
+        assert!(cpu.get_display().rows().next().unwrap()[..9] == [1, 1, 1, 1, 1, 1, 1, 1, 0]);
+
+        cpu.tick(&mut memory, &mut timer).unwrap();
+        assert_eq!(memory.read_instruction(0xEA0), Ok(0x208));
+
+        (0..5).for_each(|_| cpu.tick(&mut memory, &mut timer).unwrap());
+        assert_eq!(cpu.get_pc(), 0x20A);
+        assert!(cpu.get_stack().is_empty());
+    }
+
+    #[test]
+    fn stack_overflow_test() {
+        // Subroutine calling itself forever, the COSMAC VIP has room for 12 return addresses
+        let mut memory = load_rom(&[0x22, 0x00]);
+        let mut timer = Timer::new();
+        let mut cpu = Cpu::new(Quirks::COSMAC_VIP, Box::new(XorShiftRng::new(0)));
+        (0..12).for_each(|_| cpu.tick(&mut memory, &mut timer).unwrap());
+
+        assert_eq!(cpu.get_stack().len(), 12);
+        assert_eq!(cpu.get_stack().peek(), Some(&0x202));
+        assert_eq!(cpu.tick(&mut memory, &mut timer), Err(CpuError { pc: 0x200, instruction: 0x2200, kind: CpuErrorKind::StackOverflow }));
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt::Display;
+use crate::{memory::OutOfBoundsError, stack::StackError};
+
+/// Reason why the CPU could not execute an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuErrorKind {
+    /// The instruction does not match any known opcode
+    UnknownOpcode,
+    /// 0x00EE was executed with an empty call stack
+    StackUnderflow,
+    /// 0x2NNN was executed with a full call stack
+    StackOverflow,
+    /// The instruction accessed an address outside of the memory
+    OutOfBounds(usize),
+    /// 0x0NNN machine language routine, which can not be emulated
+    UnsupportedMachineRoutine(u16)
+}
+
+/// Error raised by the CPU, with the address and the raw instruction which caused it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuError {
+    pub pc: usize,
+    /// Raw instruction, which is 0x0000 if the instruction itself could not be fetched
+    pub instruction: u16,
+    pub kind: CpuErrorKind
+}
+
+impl CpuError {
+    /// Whether the instruction could not be fetched because the PC is outside of the memory, which no instruction can
+    /// recover from since the PC only moves forward from there
+    pub fn is_fetch_error(&self) -> bool {
+        let instruction_bytes = self.pc..=self.pc.saturating_add(1);
+        matches!(self.kind, CpuErrorKind::OutOfBounds(address) if instruction_bytes.contains(&address))
+    }
+}
+
+impl From<OutOfBoundsError> for CpuErrorKind {
+    fn from(error: OutOfBoundsError) -> Self {
+        CpuErrorKind::OutOfBounds(error.0)
+    }
+}
+
+impl From<StackError> for CpuErrorKind {
+    fn from(error: StackError) -> Self {
+        match error {
+            StackError::Overflow => CpuErrorKind::StackOverflow,
+            StackError::Underflow => CpuErrorKind::StackUnderflow
+        }
+    }
+}
+
+impl Display for CpuErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuErrorKind::UnknownOpcode => write!(f, "unknown opcode"),
+            CpuErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            CpuErrorKind::StackOverflow => write!(f, "stack overflow"),
+            CpuErrorKind::OutOfBounds(address) => write!(f, "out of bounds access at 0x{:X}", address),
+            CpuErrorKind::UnsupportedMachineRoutine(address) => write!(f, "unsupported machine routine 0x{:03X}", address)
+        }
+    }
+}
+
+impl Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (pc: 0x{:03X}, instruction: 0x{:04X})", self.kind, self.pc, self.instruction)
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+#[cfg(test)]
+mod test {
+    use super::{CpuError, CpuErrorKind};
+
+    #[test]
+    fn display_test() {
+        let error = CpuError { pc: 0x204, instruction: 0xFFFF, kind: CpuErrorKind::UnknownOpcode };
+
+        assert_eq!(error.to_string(), "unknown opcode (pc: 0x204, instruction: 0xFFFF)");
+    }
+}
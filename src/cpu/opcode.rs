@@ -1,14 +1,26 @@
-#[derive(Debug, PartialEq)]
+use std::fmt::Display;
+use super::quirks::Quirks;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
     MachineLanguageRoutine(u16),
     Clear,
     SubroutineReturn,
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowResolution,
+    HighResolution,
     SubroutineCall(usize),
     Jump(usize),
     SkipEqVal(u8, u8),
     SkipNotEqVal(u8, u8),
     SkipEqReg(u8, u8),
     SkipNotEqReg(u8, u8),
+    StoreRegisterRange(u8, u8),
+    LoadRegisterRange(u8, u8),
     SetValueToRegister(u8, u8),
     AddValueToRegister(u8, u8),
     CopyRegister(u8, u8),
@@ -21,6 +33,7 @@ pub enum Opcode {
     ShiftRegisterLeft(u8, u8),
     ShiftRegisterRight(u8, u8),
     SetIndexRegister(usize),
+    SetLongIndexRegister,
     JumpOffset(usize),
     Random(u8, u8),
     Display(u8, u8, u8),
@@ -32,9 +45,15 @@ pub enum Opcode {
     AddIndexRegister(u8),
     GetKey(u8),
     FontCharacter(u8),
+    BigFontCharacter(u8),
     DecimalConversion(u8),
     StoreMemory(u8),
     LoadMemory(u8),
+    StoreFlags(u8),
+    LoadFlags(u8),
+    SelectPlanes(u8),
+    LoadAudioPattern,
+    SetPitch(u8),
     Unknown
 }
 
@@ -50,13 +69,25 @@ impl From<u16> for Opcode {
             0x0 => match all_data {
                 0x0E0 => Opcode::Clear,
                 0x0EE => Opcode::SubroutineReturn,
+                0x0C0..=0x0CF => Opcode::ScrollDown(last_half_byte),
+                0x0D0..=0x0DF => Opcode::ScrollUp(last_half_byte),
+                0x0FB => Opcode::ScrollRight,
+                0x0FC => Opcode::ScrollLeft,
+                0x0FD => Opcode::Exit,
+                0x0FE => Opcode::LowResolution,
+                0x0FF => Opcode::HighResolution,
                 _ => Opcode::MachineLanguageRoutine(all_data)
             },
             0x1 => Opcode::Jump(all_data as usize),
             0x2 => Opcode::SubroutineCall(all_data as usize),
             0x3 => Opcode::SkipEqVal(second_half_byte, last_single_byte),
             0x4 => Opcode::SkipNotEqVal(second_half_byte, last_single_byte),
-            0x5 => Opcode::SkipEqReg(second_half_byte, third_half_byte),
+            0x5 => match last_half_byte {
+                0x0 => Opcode::SkipEqReg(second_half_byte, third_half_byte),
+                0x2 => Opcode::StoreRegisterRange(second_half_byte, third_half_byte),
+                0x3 => Opcode::LoadRegisterRange(second_half_byte, third_half_byte),
+                _ => Opcode::Unknown
+            },
             0x6 => Opcode::SetValueToRegister(second_half_byte, last_single_byte),
             0x7 => Opcode::AddValueToRegister(second_half_byte, last_single_byte),
             0x8 => match last_half_byte {
@@ -82,15 +113,22 @@ impl From<u16> for Opcode {
                 _ => Opcode::Unknown
             },
             0xF => match last_single_byte {
+                0x00 if second_half_byte == 0 => Opcode::SetLongIndexRegister,
+                0x01 => Opcode::SelectPlanes(second_half_byte),
+                0x02 if second_half_byte == 0 => Opcode::LoadAudioPattern,
                 0x07 => Opcode::CopyDelayTimerValue(second_half_byte),
                 0x15 => Opcode::SetDelayTimer(second_half_byte),
                 0x18 => Opcode::SetSoundTimer(second_half_byte),
                 0x1E => Opcode::AddIndexRegister(second_half_byte),
                 0x0A => Opcode::GetKey(second_half_byte),
                 0x29 => Opcode::FontCharacter(second_half_byte),
+                0x30 => Opcode::BigFontCharacter(second_half_byte),
+                0x3A => Opcode::SetPitch(second_half_byte),
                 0x33 => Opcode::DecimalConversion(second_half_byte),
                 0x55 => Opcode::StoreMemory(second_half_byte),
                 0x65 => Opcode::LoadMemory(second_half_byte),
+                0x75 => Opcode::StoreFlags(second_half_byte),
+                0x85 => Opcode::LoadFlags(second_half_byte),
                 _ => Opcode::Unknown
             }
             _ => Opcode::Unknown
@@ -98,8 +136,163 @@ impl From<u16> for Opcode {
     }
 }
 
+impl Opcode {
+    /**
+    Mnemonic of the opcode as the CPU executes it, unlike its [`Display`] form which does not depend on the machine.
+
+    The 0xF000 long index instruction shows its address, which is the next word in memory, and 0xBNNN shows the register
+    added to the address, which is VX under the jump quirk (X being the highest nibble of NNN). The assembler only
+    accepts the V0 form
+     */
+    pub fn format_executed(&self, quirks: &Quirks, next_word: Option<u16>) -> String {
+        match (*self, next_word) {
+            (Opcode::SetLongIndexRegister, Some(address)) => format!("LD I, LONG 0x{:04X}", address),
+            (Opcode::JumpOffset(address), _) if quirks.jump_uses_vx => {
+                format!("JP V{:X}, 0x{:03X}", address >> 8, address)
+            },
+            (opcode, _) => opcode.to_string()
+        }
+    }
+
+    /// Whether the instruction changes the pixels or the resolution of the display
+    pub fn changes_display(&self) -> bool {
+        matches!(self, Opcode::Clear | Opcode::ScrollDown(_) | Opcode::ScrollUp(_) | Opcode::ScrollRight | Opcode::ScrollLeft
+            | Opcode::LowResolution | Opcode::HighResolution | Opcode::Display(..))
+    }
+
+    /// Encodes the opcode into its instruction, which is the inverse of `Opcode::from`, it returns None for unknown opcodes
+    pub fn encode(&self) -> Option<u16> {
+        let x_nn = |base: u16, x: u8, nn: u8| base | (x as u16 & 0xF) << 8 | nn as u16;
+        let x_y_n = |base: u16, x: u8, y: u8, n: u8| base | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | (n as u16 & 0xF);
+        let nnn = |base: u16, address: usize| base | (address as u16 & 0xFFF);
+
+        let instruction = match *self {
+            Opcode::MachineLanguageRoutine(address) => nnn(0x0000, address as usize),
+            Opcode::Clear => 0x00E0,
+            Opcode::SubroutineReturn => 0x00EE,
+            Opcode::ScrollDown(n) => x_y_n(0x00C0, 0, 0, n),
+            Opcode::ScrollUp(n) => x_y_n(0x00D0, 0, 0, n),
+            Opcode::ScrollRight => 0x00FB,
+            Opcode::ScrollLeft => 0x00FC,
+            Opcode::Exit => 0x00FD,
+            Opcode::LowResolution => 0x00FE,
+            Opcode::HighResolution => 0x00FF,
+            Opcode::Jump(address) => nnn(0x1000, address),
+            Opcode::SubroutineCall(address) => nnn(0x2000, address),
+            Opcode::SkipEqVal(x, value) => x_nn(0x3000, x, value),
+            Opcode::SkipNotEqVal(x, value) => x_nn(0x4000, x, value),
+            Opcode::SkipEqReg(x, y) => x_y_n(0x5000, x, y, 0x0),
+            Opcode::StoreRegisterRange(x, y) => x_y_n(0x5000, x, y, 0x2),
+            Opcode::LoadRegisterRange(x, y) => x_y_n(0x5000, x, y, 0x3),
+            Opcode::SetValueToRegister(x, value) => x_nn(0x6000, x, value),
+            Opcode::AddValueToRegister(x, value) => x_nn(0x7000, x, value),
+            Opcode::CopyRegister(x, y) => x_y_n(0x8000, x, y, 0x0),
+            Opcode::BinaryOR(x, y) => x_y_n(0x8000, x, y, 0x1),
+            Opcode::BinaryAND(x, y) => x_y_n(0x8000, x, y, 0x2),
+            Opcode::BinaryXOR(x, y) => x_y_n(0x8000, x, y, 0x3),
+            Opcode::AddRegister(x, y) => x_y_n(0x8000, x, y, 0x4),
+            Opcode::SubtractRegister(x, y) => x_y_n(0x8000, x, y, 0x5),
+            Opcode::ShiftRegisterRight(x, y) => x_y_n(0x8000, x, y, 0x6),
+            Opcode::NegativeSubtractRegister(x, y) => x_y_n(0x8000, x, y, 0x7),
+            Opcode::ShiftRegisterLeft(x, y) => x_y_n(0x8000, x, y, 0xE),
+            Opcode::SkipNotEqReg(x, y) => x_y_n(0x9000, x, y, 0x0),
+            Opcode::SetIndexRegister(address) => nnn(0xA000, address),
+            Opcode::JumpOffset(address) => nnn(0xB000, address),
+            Opcode::Random(x, mask) => x_nn(0xC000, x, mask),
+            Opcode::Display(x, y, n) => x_y_n(0xD000, x, y, n),
+            Opcode::SkipIfKeyPressed(x) => x_nn(0xE000, x, 0x9E),
+            Opcode::SkipIfKeyNotPressed(x) => x_nn(0xE000, x, 0xA1),
+            Opcode::SetLongIndexRegister => 0xF000,
+            Opcode::SelectPlanes(n) => x_nn(0xF000, n, 0x01),
+            Opcode::LoadAudioPattern => 0xF002,
+            Opcode::CopyDelayTimerValue(x) => x_nn(0xF000, x, 0x07),
+            Opcode::GetKey(x) => x_nn(0xF000, x, 0x0A),
+            Opcode::SetDelayTimer(x) => x_nn(0xF000, x, 0x15),
+            Opcode::SetSoundTimer(x) => x_nn(0xF000, x, 0x18),
+            Opcode::AddIndexRegister(x) => x_nn(0xF000, x, 0x1E),
+            Opcode::FontCharacter(x) => x_nn(0xF000, x, 0x29),
+            Opcode::BigFontCharacter(x) => x_nn(0xF000, x, 0x30),
+            Opcode::DecimalConversion(x) => x_nn(0xF000, x, 0x33),
+            Opcode::SetPitch(x) => x_nn(0xF000, x, 0x3A),
+            Opcode::StoreMemory(x) => x_nn(0xF000, x, 0x55),
+            Opcode::LoadMemory(x) => x_nn(0xF000, x, 0x65),
+            Opcode::StoreFlags(x) => x_nn(0xF000, x, 0x75),
+            Opcode::LoadFlags(x) => x_nn(0xF000, x, 0x85),
+            Opcode::Unknown => return None
+        };
+        Some(instruction)
+    }
+}
+
+/**
+Mnemonic of the opcode, in the syntax accepted by the assembler.
+
+The 0xF000 long index instruction is rendered without its address, which is the next 16 bit word in memory, and 0xBNNN
+is always rendered as a jump relative to V0 though it uses VX under the jump quirk. See [`Opcode::format_executed`] for
+the mnemonic of a machine
+ */
+impl Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Opcode::MachineLanguageRoutine(address) => write!(f, "SYS 0x{:03X}", address),
+            Opcode::Clear => write!(f, "CLS"),
+            Opcode::SubroutineReturn => write!(f, "RET"),
+            Opcode::ScrollDown(n) => write!(f, "SCD {}", n),
+            Opcode::ScrollUp(n) => write!(f, "SCU {}", n),
+            Opcode::ScrollRight => write!(f, "SCR"),
+            Opcode::ScrollLeft => write!(f, "SCL"),
+            Opcode::Exit => write!(f, "EXIT"),
+            Opcode::LowResolution => write!(f, "LOW"),
+            Opcode::HighResolution => write!(f, "HIGH"),
+            Opcode::SubroutineCall(address) => write!(f, "CALL 0x{:03X}", address),
+            Opcode::Jump(address) => write!(f, "JP 0x{:03X}", address),
+            Opcode::SkipEqVal(x, value) => write!(f, "SE V{:X}, 0x{:02X}", x, value),
+            Opcode::SkipNotEqVal(x, value) => write!(f, "SNE V{:X}, 0x{:02X}", x, value),
+            Opcode::SkipEqReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Opcode::SkipNotEqReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Opcode::StoreRegisterRange(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Opcode::LoadRegisterRange(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Opcode::SetValueToRegister(x, value) => write!(f, "LD V{:X}, 0x{:02X}", x, value),
+            Opcode::AddValueToRegister(x, value) => write!(f, "ADD V{:X}, 0x{:02X}", x, value),
+            Opcode::CopyRegister(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Opcode::BinaryOR(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Opcode::BinaryAND(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Opcode::BinaryXOR(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Opcode::AddRegister(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Opcode::SubtractRegister(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Opcode::NegativeSubtractRegister(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Opcode::ShiftRegisterLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Opcode::ShiftRegisterRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Opcode::SetIndexRegister(address) => write!(f, "LD I, 0x{:03X}", address),
+            Opcode::SetLongIndexRegister => write!(f, "LD I, LONG"),
+            Opcode::JumpOffset(address) => write!(f, "JP V0, 0x{:03X}", address),
+            Opcode::Random(x, mask) => write!(f, "RND V{:X}, 0x{:02X}", x, mask),
+            Opcode::Display(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Opcode::SkipIfKeyPressed(x) => write!(f, "SKP V{:X}", x),
+            Opcode::SkipIfKeyNotPressed(x) => write!(f, "SKNP V{:X}", x),
+            Opcode::CopyDelayTimerValue(x) => write!(f, "LD V{:X}, DT", x),
+            Opcode::SetDelayTimer(x) => write!(f, "LD DT, V{:X}", x),
+            Opcode::SetSoundTimer(x) => write!(f, "LD ST, V{:X}", x),
+            Opcode::AddIndexRegister(x) => write!(f, "ADD I, V{:X}", x),
+            Opcode::GetKey(x) => write!(f, "LD V{:X}, K", x),
+            Opcode::FontCharacter(x) => write!(f, "LD F, V{:X}", x),
+            Opcode::BigFontCharacter(x) => write!(f, "LD HF, V{:X}", x),
+            Opcode::DecimalConversion(x) => write!(f, "LD B, V{:X}", x),
+            Opcode::StoreMemory(x) => write!(f, "LD [I], V{:X}", x),
+            Opcode::LoadMemory(x) => write!(f, "LD V{:X}, [I]", x),
+            Opcode::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            Opcode::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            Opcode::SelectPlanes(n) => write!(f, "PLANE {}", n),
+            Opcode::LoadAudioPattern => write!(f, "AUDIO"),
+            Opcode::SetPitch(x) => write!(f, "PITCH V{:X}", x),
+            Opcode::Unknown => write!(f, "???")
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use crate::cpu::quirks::Quirks;
     use super::Opcode;
 
     #[test]
@@ -115,4 +308,64 @@ mod test {
         
         assert_eq!(opcode, Opcode::AddValueToRegister(0, 0x50));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_opcode_super_chip() {
+        assert_eq!(Opcode::from(0x00C5), Opcode::ScrollDown(5));
+        assert_eq!(Opcode::from(0x00FB), Opcode::ScrollRight);
+        assert_eq!(Opcode::from(0x00FC), Opcode::ScrollLeft);
+        assert_eq!(Opcode::from(0x00FD), Opcode::Exit);
+        assert_eq!(Opcode::from(0x00FE), Opcode::LowResolution);
+        assert_eq!(Opcode::from(0x00FF), Opcode::HighResolution);
+        assert_eq!(Opcode::from(0xD120), Opcode::Display(1, 2, 0));
+        assert_eq!(Opcode::from(0xF330), Opcode::BigFontCharacter(3));
+        assert_eq!(Opcode::from(0xF775), Opcode::StoreFlags(7));
+        assert_eq!(Opcode::from(0xF785), Opcode::LoadFlags(7));
+    }
+
+    #[test]
+    fn test_opcode_xo_chip() {
+        assert_eq!(Opcode::from(0x00D3), Opcode::ScrollUp(3));
+        assert_eq!(Opcode::from(0x5122), Opcode::StoreRegisterRange(1, 2));
+        assert_eq!(Opcode::from(0x5123), Opcode::LoadRegisterRange(1, 2));
+        assert_eq!(Opcode::from(0x5121), Opcode::Unknown);
+        assert_eq!(Opcode::from(0xF000), Opcode::SetLongIndexRegister);
+        assert_eq!(Opcode::from(0xF201), Opcode::SelectPlanes(2));
+        assert_eq!(Opcode::from(0xF002), Opcode::LoadAudioPattern);
+        assert_eq!(Opcode::from(0xF43A), Opcode::SetPitch(4));
+    }
+
+    #[test]
+    fn test_opcode_display() {
+        assert_eq!(Opcode::from(0x00E0).to_string(), "CLS");
+        assert_eq!(Opcode::from(0x2ABC).to_string(), "CALL 0xABC");
+        assert_eq!(Opcode::from(0x3A05).to_string(), "SE VA, 0x05");
+        assert_eq!(Opcode::from(0x812E).to_string(), "SHL V1, V2");
+        assert_eq!(Opcode::from(0xD12F).to_string(), "DRW V1, V2, 15");
+        assert_eq!(Opcode::from(0xF565).to_string(), "LD V5, [I]");
+        assert_eq!(Opcode::from(0x5123).to_string(), "LOAD V1, V2");
+    }
+
+    #[test]
+    fn test_opcode_format_executed() {
+        assert_eq!(Opcode::from(0xF000).format_executed(&Quirks::XO_CHIP, Some(0xABCD)), "LD I, LONG 0xABCD");
+        assert_eq!(Opcode::from(0xF000).format_executed(&Quirks::XO_CHIP, None), "LD I, LONG");
+        assert_eq!(Opcode::from(0xB234).format_executed(&Quirks::COSMAC_VIP, None), "JP V0, 0x234");
+        assert_eq!(Opcode::from(0xB234).format_executed(&Quirks::SUPER_CHIP, None), "JP V2, 0x234");
+    }
+
+    #[test]
+    fn test_opcode_encode() {
+        for instruction in 0..=u16::MAX {
+            let opcode = Opcode::from(instruction);
+            // 0x9XYN decodes any N into 0x9XY0
+            if opcode != Opcode::Unknown && (instruction >> 12 != 0x9 || instruction & 0xF == 0) {
+                assert_eq!(opcode.encode(), Some(instruction), "{:?}", opcode);
+            }
+            if let Some(encoded) = opcode.encode() {
+                assert_eq!(Opcode::from(encoded), opcode);
+            }
+        }
+        assert_eq!(Opcode::Unknown.encode(), None);
+    }
+}
@@ -1,26 +1,25 @@
-use rand::Rng;
-use super::Cpu;
+use crate::{display::Display, memory::Memory};
+use super::{Cpu, error::CpuErrorKind, quirks::IndexIncrement};
 
-impl<'a> Cpu<'a> {
+impl Cpu {
     pub(super) fn opcode_clear(&mut self) {
-        self.display.fill([0; 64]);
+        self.display.clear();
     }
 
     pub(super) fn opcode_jump(&mut self, address: usize) {
         self.pc = address;
     }
 
-    pub(super) fn opcode_subroutine_call(&mut self, address: usize) {
-        self.stack.push(self.pc as u16);
+    pub(super) fn opcode_subroutine_call(&mut self, address: usize) -> Result<(), CpuErrorKind> {
+        self.stack.push(self.pc as u16)?;
         self.opcode_jump(address);
+        Ok(())
     }
 
-    pub(super) fn opcode_subroutine_return(&mut self) {
-        if let Some(address) = self.stack.pop() {
-            self.pc = address as usize;
-        } else {
-            panic!("Stack is empty!");
-        }
+    pub(super) fn opcode_subroutine_return(&mut self) -> Result<(), CpuErrorKind> {
+        let address = self.stack.pop()?;
+        self.pc = address as usize;
+        Ok(())
     }
 
     pub(super) fn opcode_set_value_to_register(&mut self, reg_idx: u8, value: u8) {
@@ -31,100 +30,153 @@ impl<'a> Cpu<'a> {
         self.var_regs[reg_idx as usize] = self.var_regs[reg_idx as usize].wrapping_add(value);
     }
 
+    /**
+    This is the implementation of opcodes 0x8XY1, 0x8XY2 and 0x8XY3, where the original COSMAC VIP interpreter also resets VF to 0
+     */
+    pub(super) fn opcode_binary_operation(&mut self, reg_idx_x: u8, reg_idx_y: u8, operation: fn(u8, u8) -> u8) {
+        let x = self.var_regs[reg_idx_x as usize];
+        let y = self.var_regs[reg_idx_y as usize];
+        self.var_regs[reg_idx_x as usize] = operation(x, y);
+        if self.quirks.vf_reset { self.set_flag_register(0); }
+    }
+
     pub(super) fn opcode_add_registers(&mut self, reg_idx_x: u8, reg_idx_y: u8) {
         let x = self.var_regs[reg_idx_x as usize];
         let y = self.var_regs[reg_idx_y as usize];
         let (result, overflowed) = x.overflowing_add(y);
-        self.set_flag_register(overflowed as u8);
         self.var_regs[reg_idx_x as usize] = result;
+        self.set_flag_register(overflowed as u8);
     }
 
     pub(super) fn opcode_subtract_registers(&mut self, dest_reg_idx: u8, reg_idx_x: u8, reg_idx_y: u8) {
         let x = self.var_regs[reg_idx_x as usize];
         let y = self.var_regs[reg_idx_y as usize];
         let (result, overflowed) = x.overflowing_sub(y);
-        self.set_flag_register(!overflowed as u8);
         self.var_regs[dest_reg_idx as usize] = result;
+        self.set_flag_register(!overflowed as u8);
     }
 
-    pub(super) fn opcode_shift_left_register(&mut self, reg_idx_x: u8, _: u8) {
-        // TODO: add configurable step "self.var_regs[reg_idx_x as usize] = self.var_regs[reg_idx_y as usize]"
-        let x = self.var_regs[reg_idx_x as usize];
-        self.set_flag_register((x & (1 << 7) > 0) as u8);
+    /**
+    This is the implementation of opcode 0x8XYE, which is an ambiguous instruction since there are different implementation for interpreters.
+
+    The original COSMAC VIP interpreter shifts VY into VX, while CHIP-48 and SUPER-CHIP shift VX in place
+     */
+    pub(super) fn opcode_shift_left_register(&mut self, reg_idx_x: u8, reg_idx_y: u8) {
+        let source_reg_idx = if self.quirks.shift_uses_vy { reg_idx_y } else { reg_idx_x };
+        let x = self.var_regs[source_reg_idx as usize];
         self.var_regs[reg_idx_x as usize] = x << 1;
+        self.set_flag_register((x & (1 << 7) > 0) as u8);
     }
 
-    pub(super) fn opcode_shift_right_register(&mut self, reg_idx_x: u8, _: u8) {
-        // TODO: add configurable step "self.var_regs[reg_idx_x as usize] = self.var_regs[reg_idx_y as usize]"
-        let x = self.var_regs[reg_idx_x as usize];
-        self.set_flag_register(x & 0x1);
+    /**
+    This is the implementation of opcode 0x8XY6, which is an ambiguous instruction since there are different implementation for interpreters.
+
+    The original COSMAC VIP interpreter shifts VY into VX, while CHIP-48 and SUPER-CHIP shift VX in place
+     */
+    pub(super) fn opcode_shift_right_register(&mut self, reg_idx_x: u8, reg_idx_y: u8) {
+        let source_reg_idx = if self.quirks.shift_uses_vy { reg_idx_y } else { reg_idx_x };
+        let x = self.var_regs[source_reg_idx as usize];
         self.var_regs[reg_idx_x as usize] = x >> 1;
+        self.set_flag_register(x & 0x1);
     }
 
     pub(super) fn opcode_set_index_register(&mut self, value: usize) {
         self.index_reg = value;
     }
 
+    /**
+    This is the implementation of opcode 0xF000 NNNN from XO-CHIP, which loads the 16 bit address following the instruction
+     */
+    pub(super) fn opcode_set_long_index_register(&mut self, memory: &Memory) -> Result<(), CpuErrorKind> {
+        self.index_reg = memory.read_instruction(self.pc)? as usize;
+        self.next_opcode();
+        Ok(())
+    }
+
     pub(super) fn opcode_add_index_register(&mut self, reg_idx: u8) {
         let offset: usize = self.var_regs[reg_idx as usize] as usize;
-        if self.index_reg + offset > 0x1000 { self.set_flag_register(1) }
+        if self.quirks.index_overflow_flag && self.index_reg + offset > 0xFFF { self.set_flag_register(1) }
         self.index_reg += offset;
     }
 
+    /**
+    This is the implementation of opcode 0xBNNN, which is an ambiguous instruction since there are different implementation for interpreters.
+
+    The original COSMAC VIP interpreter jumps to NNN + V0, while CHIP-48 and SUPER-CHIP jump to XNN + VX
+     */
     pub(super) fn opcode_jump_with_offset(&mut self, address: usize) {
-        // TODO: ambiguous instruction add configurability
-        self.pc = address + self.var_regs[0] as usize;
+        let reg_idx = if self.quirks.jump_uses_vx { (address >> 8) & 0xF } else { 0 };
+        self.pc = address + self.var_regs[reg_idx] as usize;
     }
 
     pub(super) fn opcode_random(&mut self, reg_idx: u8, mask: u8) {
-        let mut rng = rand::thread_rng();
-        self.var_regs[reg_idx as usize] = rng.gen_range(0..=0xFF) & mask;
+        self.var_regs[reg_idx as usize] = self.rng.next_u8() & mask;
     }
     
-    pub(super) fn opcode_set_index_register_to_font(&mut self, reg_idx: u8) {
-        self.index_reg = self.memory.get_font_address(self.var_regs[reg_idx as usize] & 0x0F);
+    pub(super) fn opcode_set_index_register_to_font(&mut self, memory: &Memory, reg_idx: u8) {
+        self.index_reg = memory.get_font_address(self.var_regs[reg_idx as usize] & 0x0F);
+    }
+
+    pub(super) fn opcode_set_index_register_to_big_font(&mut self, memory: &Memory, reg_idx: u8) {
+        self.index_reg = memory.get_big_font_address(self.var_regs[reg_idx as usize] & 0x0F);
     }
 
-    pub(super) fn opcode_display(&mut self, reg_idx_x: u8, reg_idx_y: u8, n_pixels: u8) {
-        let initial_x = (self.var_regs[reg_idx_x as usize] as usize) % self.display[0].len();
-        let mut y = (self.var_regs[reg_idx_y as usize] as usize) % self.display.len();
+    /**
+    This is the implementation of opcode 0xDXYN, which is an ambiguous instruction since there are different implementation for interpreters.
+
+    Sprites going past the screen edges are either clipped or wrapped around, and the original COSMAC VIP interpreter
+    also waits for the vertical blank before drawing the next sprite. When N is 0 a 16x16 sprite is drawn, as in SUPER-CHIP
+     */
+    pub(super) fn opcode_display(&mut self, memory: &Memory, reg_idx_x: u8, reg_idx_y: u8, n_pixels: u8) -> Result<(), CpuErrorKind> {
+        let width = self.display.width();
+        let height = self.display.height();
+        let initial_x = (self.var_regs[reg_idx_x as usize] as usize) % width;
+        let initial_y = (self.var_regs[reg_idx_y as usize] as usize) % height;
+        let (sprite_width, sprite_height) = if n_pixels == 0 { (16, 16) } else { (8, n_pixels as usize) };
+        let bytes_per_row = sprite_width / 8;
+        let selected_planes = self.display.get_selected_planes();
 
         self.set_flag_register(0);
 
-        for i in 0..n_pixels {
-            let mut x = initial_x;
-            let sprite_byte = self.memory.read_byte(self.index_reg + i as usize);
-            for j in 0..8 {
-                let bitmask = 1 << (7 - j);
-                let sprite_bit_on = (sprite_byte & bitmask) > 0;
-                if sprite_bit_on {
-                    if self.display[y][x] > 0 {
-                        self.display[y][x] = 0;
+        // With XO-CHIP bitplanes, the sprite data of each selected plane follows the one of the previous plane
+        let mut sprite_address = self.index_reg;
+        for plane in (0..Display::N_PLANES).filter(|plane| selected_planes & (1 << plane) > 0) {
+            for i in 0..sprite_height {
+                if self.quirks.clip_sprites && initial_y + i >= height { break; }
+                let row_address = sprite_address + i * bytes_per_row;
+                let y = (initial_y + i) % height;
+                let mut sprite_row: u16 = 0;
+                for byte_idx in 0..bytes_per_row {
+                    sprite_row = sprite_row << 8 | memory.read_byte(row_address + byte_idx)? as u16;
+                }
+                for j in 0..sprite_width {
+                    if self.quirks.clip_sprites && initial_x + j >= width { break; }
+                    let x = (initial_x + j) % width;
+                    let bitmask = 1 << (sprite_width - 1 - j);
+                    let sprite_bit_on = (sprite_row & bitmask) > 0;
+                    if sprite_bit_on && self.display.flip_pixel(x, y, plane) {
                         self.set_flag_register(1);
-                    } else {
-                        self.display[y][x] = 1;
                     }
                 }
-
-                x += 1;
-                if x >= self.display[0].len() { break; }
             }
-            y += 1;
-            if y >= self.display.len() { break; }
+            sprite_address += sprite_height * bytes_per_row;
         }
+
+        if self.quirks.display_wait { self.waiting_vblank = true; }
+        Ok(())
     }
 
-    pub(super) fn opcode_skip_if_key_pressed(&mut self, reg_idx: u8) {
+    pub(super) fn opcode_skip_if_key_pressed(&mut self, memory: &Memory, reg_idx: u8) {
         let key = self.var_regs[reg_idx as usize] & 0xF;
         if self.input_state[key as usize] > 0 {
-            self.next_opcode();
+            self.skip_next_opcode(memory);
         }
     }
 
-    pub(super) fn opcode_skip_if_key_not_pressed(&mut self, reg_idx: u8) {
+    pub(super) fn opcode_skip_if_key_not_pressed(&mut self, memory: &Memory, reg_idx: u8) {
         let key = self.var_regs[reg_idx as usize] & 0xF;
         if self.input_state[key as usize] == 0 {
-            self.next_opcode();
+            self.skip_next_opcode(memory);
         }
     }
 
@@ -141,30 +193,103 @@ impl<'a> Cpu<'a> {
         }
     }
 
-    pub(super) fn opcode_apply_decimal_conversion(&mut self, reg_idx: u8) {
+    pub(super) fn opcode_apply_decimal_conversion(&mut self, memory: &mut Memory, reg_idx: u8) -> Result<(), CpuErrorKind> {
         let value = self.var_regs[reg_idx as usize];
-        value.to_string().chars().enumerate()
-            .map(|(i, d)| (i, d.to_digit(10).unwrap() as u8))
-            .for_each(|(i, d)| self.memory.write_byte(self.index_reg + i, d));
+        let digits = [value / 100, (value / 10) % 10, value % 10];
+        for (i, digit) in digits.into_iter().enumerate() {
+            memory.write_byte(self.index_reg + i, digit)?;
+        }
+        Ok(())
     }
 
     /**
     This is the implementation of opcode 0xFX55, which is an ambiguous instruction since there are different implementation for interpreters.
     
-    The implemented one comes from modern interpreters where the index register is not mutated
+    Modern interpreters leave the index register untouched, while older ones increment it by X or X + 1
      */
-    pub(super) fn opcode_store_memory(&mut self, last_reg_idx: u8) {
-        (0..=last_reg_idx as usize)
-            .for_each(|i| self.memory.write_byte(self.index_reg + i, self.var_regs[i]));
+    pub(super) fn opcode_store_memory(&mut self, memory: &mut Memory, last_reg_idx: u8) -> Result<(), CpuErrorKind> {
+        for i in 0..=last_reg_idx as usize {
+            memory.write_byte(self.index_reg + i, self.var_regs[i])?;
+        }
+        self.increment_index_after_load_store(last_reg_idx);
+        Ok(())
     }
 
     /**
     This is the implementation of opcode 0xFX65, which is an ambiguous instruction since there are different implementation for interpreters.
      
-    The implemented one comes from modern interpreters where the index register is not mutated
+    Modern interpreters leave the index register untouched, while older ones increment it by X or X + 1
+     */
+    pub(super) fn opcode_load_memory(&mut self, memory: &Memory, last_reg_idx: u8) -> Result<(), CpuErrorKind> {
+        for i in 0..=last_reg_idx as usize {
+            self.var_regs[i] = memory.read_byte(self.index_reg + i)?;
+        }
+        self.increment_index_after_load_store(last_reg_idx);
+        Ok(())
+    }
+
+    /**
+    This is the implementation of opcode 0x5XY2 from XO-CHIP, which saves VX-VY into memory starting at the index register.
+    The registers are saved in reverse order when X is greater than Y, and the index register is not mutated
      */
-    pub(super) fn opcode_load_memory(&mut self, last_reg_idx: u8) {
-        (0..=last_reg_idx as usize)
-            .for_each(|i| self.var_regs[i] = self.memory.read_byte(self.index_reg + i));
+    pub(super) fn opcode_store_register_range(&mut self, memory: &mut Memory, reg_idx_x: u8, reg_idx_y: u8) -> Result<(), CpuErrorKind> {
+        for (i, reg_idx) in Cpu::register_range(reg_idx_x, reg_idx_y).into_iter().enumerate() {
+            memory.write_byte(self.index_reg + i, self.var_regs[reg_idx])?;
+        }
+        Ok(())
+    }
+
+    /**
+    This is the implementation of opcode 0x5XY3 from XO-CHIP, which loads VX-VY from memory starting at the index register.
+    The registers are loaded in reverse order when X is greater than Y, and the index register is not mutated
+     */
+    pub(super) fn opcode_load_register_range(&mut self, memory: &Memory, reg_idx_x: u8, reg_idx_y: u8) -> Result<(), CpuErrorKind> {
+        for (i, reg_idx) in Cpu::register_range(reg_idx_x, reg_idx_y).into_iter().enumerate() {
+            self.var_regs[reg_idx] = memory.read_byte(self.index_reg + i)?;
+        }
+        Ok(())
+    }
+
+    /**
+    This is the implementation of opcode 0xF002 from XO-CHIP, which loads the 16 bytes audio pattern starting at the index register
+     */
+    pub(super) fn opcode_load_audio_pattern(&mut self, memory: &Memory) -> Result<(), CpuErrorKind> {
+        for i in 0..self.audio_pattern.len() {
+            self.audio_pattern[i] = memory.read_byte(self.index_reg + i)?;
+        }
+        Ok(())
+    }
+
+    /**
+    This is the implementation of opcode 0xFX75 from SUPER-CHIP, which saves V0-VX into the HP-48 RPL user flags
+     */
+    pub(super) fn opcode_store_flags(&mut self, last_reg_idx: u8) {
+        let n_regs = last_reg_idx as usize + 1;
+        self.rpl_flags[..n_regs].copy_from_slice(&self.var_regs[..n_regs]);
+    }
+
+    /**
+    This is the implementation of opcode 0xFX85 from SUPER-CHIP, which loads V0-VX from the HP-48 RPL user flags
+     */
+    pub(super) fn opcode_load_flags(&mut self, last_reg_idx: u8) {
+        let n_regs = last_reg_idx as usize + 1;
+        self.var_regs[..n_regs].copy_from_slice(&self.rpl_flags[..n_regs]);
+    }
+
+    /// Registers from VX to VY, in descending order if X is greater than Y
+    fn register_range(reg_idx_x: u8, reg_idx_y: u8) -> Vec<usize> {
+        let mut registers: Vec<usize> = (reg_idx_x.min(reg_idx_y) as usize..=reg_idx_x.max(reg_idx_y) as usize).collect();
+        if reg_idx_x > reg_idx_y {
+            registers.reverse();
+        }
+        registers
+    }
+
+    fn increment_index_after_load_store(&mut self, last_reg_idx: u8) {
+        match self.quirks.load_store_index {
+            IndexIncrement::Unchanged => {},
+            IndexIncrement::ByX => self.index_reg += last_reg_idx as usize,
+            IndexIncrement::ByXPlusOne => self.index_reg += last_reg_idx as usize + 1
+        }
     }
 }
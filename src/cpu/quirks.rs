@@ -0,0 +1,156 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/// Behavior of the index register after 0xFX55 and 0xFX65
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// The index register is left untouched (SUPER-CHIP)
+    Unchanged,
+    /// The index register is incremented by X (CHIP-48)
+    ByX,
+    /// The index register is incremented by X + 1 (COSMAC VIP, XO-CHIP)
+    ByXPlusOne
+}
+
+/**
+Configuration of the ambiguous instructions whose behavior changed between the different CHIP-8 interpreters.
+
+The named presets reproduce the behavior of the most common interpreters, so a ROM can run with the one it was written for.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 0x8XY6 and 0x8XYE copy VY into VX before shifting, instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    /// 0xBNNN jumps to XNN + VX (X being the highest nibble of NNN), instead of NNN + V0
+    pub jump_uses_vx: bool,
+    /// How 0xFX55 and 0xFX65 change the index register
+    pub load_store_index: IndexIncrement,
+    /// 0x8XY1, 0x8XY2 and 0x8XY3 reset VF to 0
+    pub vf_reset: bool,
+    /// 0xDXYN waits for the next vertical blank after drawing, limiting to 60 sprites per second
+    pub display_wait: bool,
+    /// Sprites are clipped at the screen edges, instead of wrapping around to the opposite side
+    pub clip_sprites: bool,
+    /// 0xFX1E sets VF to 1 when the index register goes past the addressable memory
+    pub index_overflow_flag: bool,
+    /// Addresses past the end of the memory wrap around to 0, instead of raising an out of bounds error
+    pub wrap_addresses: bool,
+    /// Maximum number of nested subroutine calls, deeper calls raise a stack overflow error
+    pub stack_depth: u8,
+    /// The call stack and the display buffer live in the memory at 0xEA0 and 0xF00 as on the COSMAC VIP, so writing
+    /// there changes the return addresses and the screen
+    pub vip_memory_map: bool
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        jump_uses_vx: false,
+        load_store_index: IndexIncrement::ByXPlusOne,
+        vf_reset: true,
+        display_wait: true,
+        clip_sprites: true,
+        index_overflow_flag: false,
+        wrap_addresses: true,
+        stack_depth: 12,
+        vip_memory_map: false
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        jump_uses_vx: true,
+        load_store_index: IndexIncrement::ByX,
+        vf_reset: false,
+        display_wait: false,
+        clip_sprites: true,
+        index_overflow_flag: false,
+        wrap_addresses: false,
+        stack_depth: 16,
+        vip_memory_map: false
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        jump_uses_vx: true,
+        load_store_index: IndexIncrement::Unchanged,
+        vf_reset: false,
+        display_wait: false,
+        clip_sprites: true,
+        index_overflow_flag: false,
+        wrap_addresses: false,
+        stack_depth: 16,
+        vip_memory_map: false
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        jump_uses_vx: false,
+        load_store_index: IndexIncrement::ByXPlusOne,
+        vf_reset: false,
+        display_wait: false,
+        clip_sprites: false,
+        index_overflow_flag: false,
+        wrap_addresses: true,
+        stack_depth: 16,
+        vip_memory_map: false
+    };
+}
+
+impl Quirks {
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.shift_uses_vy);
+        writer.write_bool(self.jump_uses_vx);
+        writer.write_u8(match self.load_store_index {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => 1,
+            IndexIncrement::ByXPlusOne => 2
+        });
+        writer.write_bool(self.vf_reset);
+        writer.write_bool(self.display_wait);
+        writer.write_bool(self.clip_sprites);
+        writer.write_bool(self.index_overflow_flag);
+        writer.write_bool(self.wrap_addresses);
+        writer.write_u8(self.stack_depth);
+        writer.write_bool(self.vip_memory_map);
+    }
+
+    pub(crate) fn read_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        Ok(Self {
+            shift_uses_vy: reader.read_bool()?,
+            jump_uses_vx: reader.read_bool()?,
+            load_store_index: match reader.read_u8()? {
+                0 => IndexIncrement::Unchanged,
+                1 => IndexIncrement::ByX,
+                2 => IndexIncrement::ByXPlusOne,
+                _ => return Err(SaveStateError::InvalidField("index increment quirk"))
+            },
+            vf_reset: reader.read_bool()?,
+            display_wait: reader.read_bool()?,
+            clip_sprites: reader.read_bool()?,
+            index_overflow_flag: reader.read_bool()?,
+            wrap_addresses: reader.read_bool()?,
+            stack_depth: match reader.read_u8()? {
+                0 => return Err(SaveStateError::InvalidField("stack depth quirk")),
+                stack_depth => stack_depth
+            },
+            vip_memory_map: reader.read_bool()?
+        })
+    }
+}
+
+impl Default for Quirks {
+    /// Behavior of most modern interpreters, which is the one the emulator always used before quirks were configurable
+    fn default() -> Self {
+        Self {
+            shift_uses_vy: false,
+            jump_uses_vx: false,
+            load_store_index: IndexIncrement::Unchanged,
+            vf_reset: false,
+            display_wait: false,
+            clip_sprites: true,
+            index_overflow_flag: true,
+            wrap_addresses: false,
+            stack_depth: 16,
+            vip_memory_map: false
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use std::{collections::BTreeSet, fmt::{Display, Write}, ops::Range};
+use crate::{chip8::Chip8, cpu::{error::CpuError, opcode::Opcode}};
+
+/// Condition which pauses the execution when it is met by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watchpoint {
+    /// The value of the register VX changes
+    Register(u8),
+    /// The value of the index register changes
+    Index,
+    /// The byte at the address is written
+    MemoryWrite(usize),
+    /// The byte at the address is read
+    MemoryRead(usize)
+}
+
+/// Reason why the debugger paused the execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program counter reached a breakpoint
+    Breakpoint(usize),
+    /// The instruction at the address hit the watchpoint
+    Watchpoint(Watchpoint, usize),
+    /// The subroutine call was stepped over, and the execution stopped at the address
+    SteppedOver(usize),
+    /// The subroutine returned to the address
+    Returned(usize)
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Breakpoint(address) => write!(f, "Breakpoint hit at 0x{:03X}", address),
+            StopReason::Watchpoint(watchpoint, pc) => write!(f, "Watchpoint hit on {} by the instruction at 0x{:03X}", format_watchpoint(watchpoint), pc),
+            StopReason::SteppedOver(address) => write!(f, "Stepped over to 0x{:03X}", address),
+            StopReason::Returned(address) => write!(f, "Returned to 0x{:03X}", address)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunMode {
+    Paused,
+    Running,
+    /// Running until the program counter reaches the instruction after a subroutine call
+    StepOver(usize),
+    /// Running until the subroutine returns, counting the nested calls
+    Finish(usize)
+}
+
+/**
+Command-line debugger which drives a [`Chip8`] machine.
+
+Commands are executed with [`Debugger::execute_command`], and while the debugger is running the frontend calls
+[`Debugger::tick`] instead of [`Chip8::tick`], so breakpoints and watchpoints are checked around each instruction
+ */
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    mode: RunMode,
+    /// Set when resuming from a breakpoint, so the execution does not stop again on the same instruction
+    skip_breakpoint: bool
+}
+
+impl Debugger {
+    const HELP: &'static str = "\
+Commands:
+  c, continue            resume the execution
+  p, pause               pause the execution
+  s, step [n]            execute n instructions (1 by default)
+  n, next                step over subroutine calls
+  f, finish              run until the current subroutine returns
+  b, break <addr>        set a breakpoint
+  d, delete <addr>       delete a breakpoint
+  w, watch <vX|i|addr>   pause when the register changes or the address is written
+  rw, rwatch <addr>      pause when the address is read
+  uw, unwatch <n>        delete the watchpoint number n
+  l, list                list breakpoints and watchpoints
+  r, regs                print registers, stack and timers
+  x, mem <addr> [len]    print a memory hex dump
+  dis, disasm [addr] [n] print the disassembly around the address (PC by default)
+  h, help                print this help
+";
+
+    /// Creates a debugger which starts paused
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            mode: RunMode::Paused,
+            skip_breakpoint: false
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == RunMode::Paused
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = RunMode::Paused;
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|&w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn get_breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    pub fn get_watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Resumes the execution until a breakpoint or watchpoint is hit
+    pub fn resume(&mut self) {
+        self.mode = RunMode::Running;
+        self.skip_breakpoint = true;
+    }
+
+    /**
+    Executes one instruction if the debugger is running, checking breakpoints and watchpoints.
+
+    Nothing is checked while the CPU waits for the vertical blank or has exited, since no instruction runs until the
+    frontend ends the frame. Returns the reason why the execution stopped, if it did
+     */
+    pub fn tick(&mut self, chip8: &mut Chip8) -> Result<Option<StopReason>, CpuError> {
+        if self.is_paused() { return Ok(None); }
+        if chip8.get_cpu().is_waiting_vblank() || chip8.has_exited() { return Ok(None); }
+
+        let pc = chip8.get_cpu().get_pc();
+        if !self.skip_breakpoint && self.breakpoints.contains(&pc) {
+            self.pause();
+            return Ok(Some(StopReason::Breakpoint(pc)));
+        }
+        self.skip_breakpoint = false;
+
+        let opcode = Debugger::current_opcode(chip8);
+        let stop_reason = self.step(chip8)
+            .inspect_err(|_| self.pause())?;
+        if stop_reason.is_some() {
+            self.pause();
+            return Ok(stop_reason);
+        }
+
+        match (self.mode, opcode) {
+            (RunMode::StepOver(return_pc), _) if chip8.get_cpu().get_pc() == return_pc => {
+                self.pause();
+                Ok(Some(StopReason::SteppedOver(return_pc)))
+            },
+            (RunMode::Finish(0), Some(Opcode::SubroutineReturn)) => {
+                self.pause();
+                Ok(Some(StopReason::Returned(chip8.get_cpu().get_pc())))
+            },
+            (RunMode::Finish(depth), Some(Opcode::SubroutineReturn)) => {
+                self.mode = RunMode::Finish(depth - 1);
+                Ok(None)
+            },
+            (RunMode::Finish(depth), Some(Opcode::SubroutineCall(_))) => {
+                self.mode = RunMode::Finish(depth + 1);
+                Ok(None)
+            },
+            _ => Ok(None)
+        }
+    }
+
+    /// Parses and executes a command, returning the text to be printed
+    pub fn execute_command(&mut self, line: &str, chip8: &mut Chip8) -> String {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = tokens.split_first() else { return String::new(); };
+
+        let result = match command {
+            "c" | "continue" => { self.resume(); Ok("Continuing".to_string()) },
+            "p" | "pause" => { self.pause(); Ok(Debugger::format_disassembly(chip8, chip8.get_cpu().get_pc(), 1, &self.breakpoints)) },
+            "s" | "step" => self.command_step(args, chip8),
+            "n" | "next" => Ok(self.command_next(chip8)),
+            "f" | "finish" => { self.mode = RunMode::Finish(0); self.skip_breakpoint = true; Ok("Running until return".to_string()) },
+            "b" | "break" => parse_address(args.first()).map(|address| {
+                self.add_breakpoint(address);
+                format!("Breakpoint set at 0x{:03X}", address)
+            }),
+            "d" | "delete" => parse_address(args.first()).map(|address| match self.remove_breakpoint(address) {
+                true => format!("Breakpoint deleted at 0x{:03X}", address),
+                false => format!("No breakpoint at 0x{:03X}", address)
+            }),
+            "w" | "watch" => parse_watchpoint(args.first()).map(|watchpoint| {
+                self.add_watchpoint(watchpoint);
+                format!("Watchpoint set on {}", format_watchpoint(&watchpoint))
+            }),
+            "rw" | "rwatch" => parse_address(args.first()).map(|address| {
+                self.add_watchpoint(Watchpoint::MemoryRead(address));
+                format!("Watchpoint set on {}", format_watchpoint(&Watchpoint::MemoryRead(address)))
+            }),
+            "uw" | "unwatch" => parse_number(args.first(), 10)
+                .and_then(|n| self.watchpoints.get(n).copied().ok_or_else(|| format!("No watchpoint number {}", n)))
+                .map(|watchpoint| {
+                    self.remove_watchpoint(watchpoint);
+                    format!("Watchpoint deleted on {}", format_watchpoint(&watchpoint))
+                }),
+            "l" | "list" => Ok(self.format_list()),
+            "r" | "regs" => Ok(Debugger::format_registers(chip8)),
+            "x" | "mem" => parse_address(args.first()).and_then(|address| {
+                let len = args.get(1).map_or(Ok(0x40), |_| parse_number(args.get(1), 16))?;
+                Ok(Debugger::format_memory(chip8, address, len))
+            }),
+            "dis" | "disasm" => {
+                let address = args.first().map_or(Ok(chip8.get_cpu().get_pc()), |_| parse_address(args.first()));
+                let n = args.get(1).map_or(Ok(8), |_| parse_number(args.get(1), 10));
+                address.and_then(|address| n.map(|n| Debugger::format_disassembly(chip8, address, n, &self.breakpoints)))
+            },
+            "h" | "help" => Ok(Debugger::HELP.to_string()),
+            _ => Err(format!("Unknown command {}, type help for the list of commands", command))
+        };
+
+        match result {
+            Ok(output) | Err(output) => output
+        }
+    }
+
+    fn command_step(&mut self, args: &[&str], chip8: &mut Chip8) -> Result<String, String> {
+        let n_steps = args.first().map_or(Ok(1), |_| parse_number(args.first(), 10))?;
+        self.pause();
+        for _ in 0..n_steps {
+            match self.step(chip8) {
+                Ok(None) => {},
+                Ok(Some(reason)) => return Ok(format!("{}\n{}", reason, Debugger::format_disassembly(chip8, chip8.get_cpu().get_pc(), 1, &self.breakpoints))),
+                Err(e) => return Err(format!("CPU error: {}", e))
+            }
+        }
+        Ok(Debugger::format_disassembly(chip8, chip8.get_cpu().get_pc(), 1, &self.breakpoints))
+    }
+
+    fn command_next(&mut self, chip8: &mut Chip8) -> String {
+        let pc = chip8.get_cpu().get_pc();
+        match Debugger::current_opcode(chip8) {
+            Some(Opcode::SubroutineCall(_)) => {
+                self.mode = RunMode::StepOver(pc + 2);
+                self.skip_breakpoint = true;
+                format!("Stepping over the call at 0x{:03X}", pc)
+            },
+            _ => self.command_step(&[], chip8).unwrap_or_else(|e| e)
+        }
+    }
+
+    /**
+    Executes one instruction ignoring breakpoints, returning the watchpoint hit if any.
+
+    The frontend does not end the frames while the debugger is paused, so if the CPU waits for the vertical blank
+    (display wait quirk) the frame is ended here with [`Chip8::update_timers`] before the instruction runs
+     */
+    pub fn step(&mut self, chip8: &mut Chip8) -> Result<Option<StopReason>, CpuError> {
+        if chip8.get_cpu().is_waiting_vblank() {
+            chip8.update_timers();
+        }
+        if chip8.has_exited() { return Ok(None); }
+        let (reads, writes) = Debugger::current_opcode(chip8)
+            .map_or((None, None), |opcode| memory_accesses(chip8, &opcode));
+        let old_var_regs = *chip8.get_cpu().get_var_registers();
+        let old_index = chip8.get_cpu().get_index_register();
+        let pc = chip8.get_cpu().get_pc();
+
+        chip8.tick()?;
+
+        let cpu = chip8.get_cpu();
+        let hit = self.watchpoints.iter().find(|watchpoint| match watchpoint {
+            Watchpoint::Register(reg_idx) => old_var_regs[*reg_idx as usize] != cpu.get_var_registers()[*reg_idx as usize],
+            Watchpoint::Index => old_index != cpu.get_index_register(),
+            Watchpoint::MemoryWrite(address) => writes.as_ref().is_some_and(|range| range.contains(address)),
+            Watchpoint::MemoryRead(address) => reads.as_ref().is_some_and(|range| range.contains(address))
+        });
+        Ok(hit.map(|&watchpoint| StopReason::Watchpoint(watchpoint, pc)))
+    }
+
+    fn current_opcode(chip8: &Chip8) -> Option<Opcode> {
+        chip8.get_memory().read_instruction(chip8.get_cpu().get_pc()).ok().map(Opcode::from)
+    }
+
+    fn format_list(&self) -> String {
+        let mut output = String::from("Breakpoints:");
+        self.breakpoints.iter().for_each(|address| write!(output, " 0x{:03X}", address).unwrap());
+        output.push_str("\nWatchpoints:");
+        self.watchpoints.iter().enumerate()
+            .for_each(|(i, watchpoint)| write!(output, "\n  {}: {}", i, format_watchpoint(watchpoint)).unwrap());
+        output
+    }
+
+    pub fn format_registers(chip8: &Chip8) -> String {
+        let cpu = chip8.get_cpu();
+        let timer = chip8.get_timer();
+        let mut output = format!("PC: 0x{:03X}  I: 0x{:03X}  DT: {}  ST: {}\n", cpu.get_pc(), cpu.get_index_register(), timer.get_delay_timer(), timer.get_sound_timer());
+        for (i, value) in cpu.get_var_registers().iter().enumerate() {
+            write!(output, "V{:X}: {:02X}{}", i, value, if i % 8 == 7 { "\n" } else { "  " }).unwrap();
+        }
+        write!(output, "Stack ({}/{}):", cpu.get_stack().len(), cpu.get_stack().get_max_depth()).unwrap();
+        cpu.get_stack().iter().rev().for_each(|address| write!(output, " 0x{:03X}", address).unwrap());
+        output
+    }
+
+    fn format_memory(chip8: &Chip8, address: usize, len: usize) -> String {
+        let memory = chip8.get_memory();
+        let end = (address + len).min(memory.size());
+        (address..end).step_by(16)
+            .map(|row| {
+                let bytes: Vec<String> = (row..(row + 16).min(end))
+                    .map(|a| format!("{:02X}", memory.read_byte(a).unwrap_or_default()))
+                    .collect();
+                format!("{:04X}: {}", row, bytes.join(" "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Disassembles n instructions before and after the address, marking the PC with '>' and breakpoints with '*'
+    fn format_disassembly(chip8: &Chip8, address: usize, n: usize, breakpoints: &BTreeSet<usize>) -> String {
+        let pc = chip8.get_cpu().get_pc();
+        let memory = chip8.get_memory();
+        let mnemonic = |a: usize, instruction: u16| Opcode::from(instruction)
+            .format_executed(chip8.get_cpu().get_quirks(), memory.read_instruction(a + 2).ok());
+        let start = address.saturating_sub(2 * (n / 2));
+        (0..n.max(1))
+            .map(|i| start + 2 * i)
+            .filter_map(|a| memory.read_instruction(a).ok().map(|instruction| (a, instruction)))
+            .map(|(a, instruction)| format!("{}{} {:03X}: {:04X}  {}",
+                if a == pc { '>' } else { ' ' },
+                if breakpoints.contains(&a) { '*' } else { ' ' },
+                a, instruction, mnemonic(a, instruction)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Memory ranges read and written by the opcode, without taking the instruction fetch into account
+fn memory_accesses(chip8: &Chip8, opcode: &Opcode) -> (Option<Range<usize>>, Option<Range<usize>>) {
+    let cpu = chip8.get_cpu();
+    let index = cpu.get_index_register();
+    let range = |len: usize| Some(index..index + len);
+    match *opcode {
+        Opcode::Display(_, _, n_pixels) => {
+            let n_planes = chip8.get_display().get_selected_planes().count_ones() as usize;
+            let sprite_len = if n_pixels == 0 { 32 } else { n_pixels as usize };
+            (range(sprite_len * n_planes), None)
+        },
+        Opcode::LoadMemory(last_reg_idx) => (range(last_reg_idx as usize + 1), None),
+        Opcode::LoadRegisterRange(x, y) => (range(x.abs_diff(y) as usize + 1), None),
+        Opcode::LoadAudioPattern => (range(16), None),
+        Opcode::SetLongIndexRegister => (Some(cpu.get_pc() + 2..cpu.get_pc() + 4), None),
+        Opcode::StoreMemory(last_reg_idx) => (None, range(last_reg_idx as usize + 1)),
+        Opcode::StoreRegisterRange(x, y) => (None, range(x.abs_diff(y) as usize + 1)),
+        Opcode::DecimalConversion(_) => (None, range(3)),
+        _ => (None, None)
+    }
+}
+
+fn parse_number(token: Option<&&str>, radix: u32) -> Result<usize, String> {
+    let token = token.ok_or("Missing argument")?;
+    let digits = token.strip_prefix("0x").unwrap_or(token);
+    usize::from_str_radix(digits, radix).map_err(|_| format!("Invalid number {}", token))
+}
+
+/// Addresses are always hexadecimal, with or without the 0x prefix
+fn parse_address(token: Option<&&str>) -> Result<usize, String> {
+    parse_number(token, 16)
+}
+
+fn parse_watchpoint(token: Option<&&str>) -> Result<Watchpoint, String> {
+    match token.map(|token| token.to_lowercase()) {
+        Some(name) if name == "i" => Ok(Watchpoint::Index),
+        Some(name) if name.len() == 2 && name.starts_with('v') => u8::from_str_radix(&name[1..], 16)
+            .map(Watchpoint::Register)
+            .map_err(|_| format!("Invalid register {}", name)),
+        _ => parse_address(token).map(Watchpoint::MemoryWrite)
+    }
+}
+
+fn format_watchpoint(watchpoint: &Watchpoint) -> String {
+    match watchpoint {
+        Watchpoint::Register(reg_idx) => format!("V{:X}", reg_idx),
+        Watchpoint::Index => "I".to_string(),
+        Watchpoint::MemoryWrite(address) => format!("write of 0x{:03X}", address),
+        Watchpoint::MemoryRead(address) => format!("read of 0x{:03X}", address)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{chip8::Chip8, cpu::quirks::Quirks};
+    use super::Debugger;
+
+    // 0x200: V0 = 1, call 0x20A, V1 = 2, jump 0x208 / 0x20A: I = 0x300, store V0, return
+    const ROM: [u8; 16] = [0x60, 0x01, 0x22, 0x0A, 0x61, 0x02, 0x00, 0x00, 0x12, 0x08, 0xA3, 0x00, 0xF0, 0x55, 0x00, 0xEE];
+
+    fn run(debugger: &mut Debugger, chip8: &mut Chip8) -> Option<String> {
+        (0..100).find_map(|_| debugger.tick(chip8).unwrap()).map(|reason| reason.to_string())
+    }
+
+    #[test]
+    fn step_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut debugger = Debugger::new();
+
+        debugger.execute_command("step 2", &mut chip8);
+        assert_eq!(chip8.get_cpu().get_pc(), 0x20A);
+        assert!(debugger.is_paused());
+    }
+
+    #[test]
+    fn breakpoint_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute_command("break 20c", &mut chip8);
+        debugger.execute_command("continue", &mut chip8);
+
+        assert_eq!(run(&mut debugger, &mut chip8), Some("Breakpoint hit at 0x20C".to_string()));
+        assert_eq!(chip8.get_cpu().get_pc(), 0x20C);
+    }
+
+    #[test]
+    fn next_and_finish_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute_command("step", &mut chip8);
+        debugger.execute_command("next", &mut chip8);
+
+        assert!(run(&mut debugger, &mut chip8).is_some());
+        assert_eq!(chip8.get_cpu().get_pc(), 0x204);
+
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        debugger.execute_command("step 3", &mut chip8);
+        debugger.execute_command("finish", &mut chip8);
+        assert!(run(&mut debugger, &mut chip8).is_some());
+        assert_eq!(chip8.get_cpu().get_pc(), 0x204);
+    }
+
+    #[test]
+    fn watchpoint_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute_command("watch 300", &mut chip8);
+        debugger.execute_command("continue", &mut chip8);
+
+        assert_eq!(run(&mut debugger, &mut chip8), Some("Watchpoint hit on write of 0x300 by the instruction at 0x20C".to_string()));
+
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute_command("watch v1", &mut chip8);
+        debugger.execute_command("continue", &mut chip8);
+
+        assert_eq!(run(&mut debugger, &mut chip8), Some("Watchpoint hit on V1 by the instruction at 0x204".to_string()));
+    }
+
+    #[test]
+    fn display_wait_test() {
+        // 0x200: call 0x206, V1 = 2, jump 0x204 / 0x206: draw, draw, return
+        let rom = [0x22, 0x06, 0x61, 0x02, 0x12, 0x04, 0xD0, 0x15, 0xD0, 0x15, 0x00, 0xEE];
+        let build = || Chip8::builder().rom(&rom).quirks(Quirks::COSMAC_VIP).build().unwrap();
+
+        // Stepping past a draw ends the frame the CPU waits for
+        let mut chip8 = build();
+        let mut debugger = Debugger::new();
+        debugger.execute_command("step 3", &mut chip8);
+        assert_eq!(chip8.get_cpu().get_pc(), 0x20A);
+
+        // The second draw does not hit the watchpoint before it runs, after the vertical blank
+        let mut chip8 = build();
+        let mut debugger = Debugger::new();
+        debugger.execute_command("step 2", &mut chip8);
+        debugger.execute_command("rwatch 0", &mut chip8);
+        debugger.execute_command("continue", &mut chip8);
+        assert!((0..11).all(|_| debugger.tick(&mut chip8).unwrap().is_none()));
+        chip8.update_timers();
+        assert_eq!(run(&mut debugger, &mut chip8), Some("Watchpoint hit on read of 0x000 by the instruction at 0x208".to_string()));
+
+        // The return waiting behind the second draw is not taken for the end of the subroutine before it runs
+        let mut chip8 = build();
+        let mut debugger = Debugger::new();
+        debugger.execute_command("step", &mut chip8);
+        debugger.execute_command("finish", &mut chip8);
+        let stop = (0..10).find_map(|_| {
+            let stop = (0..11).find_map(|_| debugger.tick(&mut chip8).unwrap());
+            chip8.update_timers();
+            stop
+        });
+        assert_eq!(stop.map(|reason| reason.to_string()), Some("Returned to 0x202".to_string()));
+    }
+
+    #[test]
+    fn print_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute_command("step 2", &mut chip8);
+
+        let registers = debugger.execute_command("regs", &mut chip8);
+        assert!(registers.starts_with("PC: 0x20A  I: 0x000"));
+        assert!(registers.ends_with("Stack (1/16): 0x204"));
+        assert_eq!(debugger.execute_command("mem 200 4", &mut chip8), "0200: 60 01 22 0A");
+        assert!(debugger.execute_command("dis 20a 1", &mut chip8).starts_with(">  20A: A300"));
+        assert!(debugger.execute_command("bogus", &mut chip8).starts_with("Unknown command"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::{collections::BTreeMap, fmt::Write};
+use crate::{cpu::opcode::Opcode, memory::Memory};
+
+/// Kind of a label, ordered by priority when an address is the target of several instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Jump,
+    Subroutine
+}
+
+/**
+Disassembles a ROM into source which the assembler rebuilds into the same bytes.
+
+The control flow is followed from the first instruction of the ROM to separate the code from the data. Jump and call
+targets get labels, and so do the data addresses loaded into the index register, while the bytes which are never
+executed are emitted as `db` directives
+ */
+pub fn disassemble(rom: &[u8]) -> String {
+    disassemble_at(rom, Memory::ROM_INIT_ADDRESS)
+}
+
+/// Disassembles a ROM loaded at the given address, which is where the control flow starts
+pub fn disassemble_at(rom: &[u8], load_address: usize) -> String {
+    let mut disassembler = Disassembler::new(rom, load_address);
+    disassembler.trace();
+    disassembler.emit()
+}
+
+struct Disassembler<'a> {
+    rom: &'a [u8],
+    load_address: usize,
+    /// Length of the instruction starting at each byte of the ROM, or 0 if no instruction starts there
+    instruction_lengths: Vec<usize>,
+    /// Whether each byte of the ROM belongs to an instruction
+    code: Vec<bool>,
+    labels: BTreeMap<usize, LabelKind>
+}
+
+impl<'a> Disassembler<'a> {
+    fn new(rom: &'a [u8], load_address: usize) -> Self {
+        Self {
+            rom,
+            load_address,
+            instruction_lengths: vec![0; rom.len()],
+            code: vec![false; rom.len()],
+            labels: BTreeMap::new()
+        }
+    }
+
+    fn read_instruction(&self, address: usize) -> Option<u16> {
+        let offset = address.checked_sub(self.load_address)?;
+        let bytes = self.rom.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn add_label(&mut self, address: usize, kind: LabelKind) {
+        let label = self.labels.entry(address).or_insert(kind);
+        *label = (*label).max(kind);
+    }
+
+    /// Decodes every instruction reachable from the load address
+    fn trace(&mut self) {
+        let mut pending = vec![self.load_address];
+        while let Some(address) = pending.pop() {
+            let Some(instruction) = self.read_instruction(address) else { continue; };
+            let opcode = Opcode::from(instruction);
+            let len = if opcode == Opcode::SetLongIndexRegister { 4 } else { 2 };
+            let offset = address - self.load_address;
+            // Unknown opcodes and the ones which do not encode back into the same instruction (such as 0x9XY1) are
+            // data, and instructions overlapping the ones already decoded are ignored
+            if opcode.encode() != Some(instruction) || offset + len > self.rom.len() || self.code[offset..offset + len].contains(&true) {
+                continue;
+            }
+            self.instruction_lengths[offset] = len;
+            self.code[offset..offset + len].fill(true);
+
+            match opcode {
+                Opcode::Jump(target) => {
+                    self.add_label(target, LabelKind::Jump);
+                    pending.push(target);
+                },
+                Opcode::SubroutineCall(target) => {
+                    self.add_label(target, LabelKind::Subroutine);
+                    pending.extend([target, address + 2]);
+                },
+                Opcode::SubroutineReturn | Opcode::Exit | Opcode::JumpOffset(_) => {},
+                Opcode::SkipEqVal(..) | Opcode::SkipNotEqVal(..) | Opcode::SkipEqReg(..) | Opcode::SkipNotEqReg(..)
+                | Opcode::SkipIfKeyPressed(_) | Opcode::SkipIfKeyNotPressed(_) => {
+                    // The long index instruction is skipped as a whole
+                    let skipped_len = if self.read_instruction(address + 2) == Some(0xF000) { 4 } else { 2 };
+                    pending.extend([address + 2, address + 2 + skipped_len]);
+                },
+                Opcode::SetIndexRegister(target) => {
+                    self.add_label(target, LabelKind::Data);
+                    pending.push(address + 2);
+                },
+                Opcode::SetLongIndexRegister => {
+                    if let Some(target) = self.read_instruction(address + 2) {
+                        self.add_label(target as usize, LabelKind::Data);
+                    }
+                    pending.push(address + 4);
+                },
+                _ => pending.push(address + 2)
+            }
+        }
+
+        // Only the addresses where a line of the output starts can be labeled
+        let load_address = self.load_address;
+        let (code, instruction_lengths) = (&self.code, &self.instruction_lengths);
+        self.labels.retain(|&address, _| address.checked_sub(load_address)
+            .filter(|&offset| offset < code.len())
+            .is_some_and(|offset| !code[offset] || instruction_lengths[offset] > 0));
+    }
+
+    fn emit(&self) -> String {
+        const BYTES_PER_LINE: usize = 8;
+        let mut output = String::new();
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let address = self.load_address + offset;
+            if self.labels.contains_key(&address) {
+                writeln!(output, "{}:", self.format_address(address)).unwrap();
+            }
+
+            if self.instruction_lengths[offset] > 0 {
+                writeln!(output, "    {}", self.format_instruction(address)).unwrap();
+                offset += self.instruction_lengths[offset];
+            } else {
+                let mut end = offset + 1;
+                while end < self.rom.len() && end - offset < BYTES_PER_LINE && !self.code[end]
+                    && !self.labels.contains_key(&(self.load_address + end)) {
+                    end += 1;
+                }
+                let bytes: Vec<String> = self.rom[offset..end].iter().map(|byte| format!("0x{:02X}", byte)).collect();
+                writeln!(output, "    db {}", bytes.join(", ")).unwrap();
+                offset = end;
+            }
+        }
+        output
+    }
+
+    fn format_instruction(&self, address: usize) -> String {
+        let instruction = self.read_instruction(address).unwrap_or_default();
+        match Opcode::from(instruction) {
+            Opcode::Jump(target) => format!("JP {}", self.format_address(target)),
+            Opcode::SubroutineCall(target) => format!("CALL {}", self.format_address(target)),
+            Opcode::SetIndexRegister(target) => format!("LD I, {}", self.format_address(target)),
+            Opcode::SetLongIndexRegister => {
+                let target = self.read_instruction(address + 2).unwrap_or_default() as usize;
+                match self.labels.contains_key(&target) {
+                    true => format!("LD I, LONG {}", self.format_address(target)),
+                    false => format!("LD I, LONG 0x{:04X}", target)
+                }
+            },
+            opcode => opcode.to_string()
+        }
+    }
+
+    /// Label of the address if it has one, otherwise the hexadecimal address
+    fn format_address(&self, address: usize) -> String {
+        match self.labels.get(&address) {
+            Some(LabelKind::Subroutine) => format!("sub_{:03X}", address),
+            Some(LabelKind::Jump) => format!("loc_{:03X}", address),
+            Some(LabelKind::Data) => format!("data_{:03X}", address),
+            None => format!("0x{:03X}", address)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{disassemble, disassemble_at};
+
+    #[test]
+    fn disassemble_test() {
+        // Draws the sprite at 0x20C in a loop which calls a subroutine, followed by the sprite data
+        let rom = [
+            0xA2, 0x0C, 0x22, 0x08, 0x12, 0x02, 0x00, 0x00,
+            0xD0, 0x15, 0x00, 0xEE, 0xF0, 0x90, 0x90, 0x90,
+            0xF0
+        ];
+        let expected = "    LD I, data_20C
+loc_202:
+    CALL sub_208
+    JP loc_202
+    db 0x00, 0x00
+sub_208:
+    DRW V0, V1, 5
+    RET
+data_20C:
+    db 0xF0, 0x90, 0x90, 0x90, 0xF0
+";
+        assert_eq!(disassemble(&rom), expected);
+    }
+
+    #[test]
+    fn skip_long_index_test() {
+        // The skip jumps over the whole 4 bytes long index instruction, and the unreachable unknown opcode is data
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x00, 0xFD, 0xFF, 0xFF];
+        let expected = "    SE V0, 0x00
+    LD I, LONG 0x1234
+    EXIT
+    db 0xFF, 0xFF
+";
+        assert_eq!(disassemble(&rom), expected);
+    }
+
+    #[test]
+    fn load_address_test() {
+        let rom = [0x26, 0x04, 0x16, 0x00, 0x00, 0xEE];
+        let expected = "loc_600:
+    CALL sub_604
+    JP loc_600
+sub_604:
+    RET
+";
+        assert_eq!(disassemble_at(&rom, 0x600), expected);
+    }
+}
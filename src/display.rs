@@ -0,0 +1,267 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/**
+Frame buffer whose resolution can change at runtime.
+
+The low resolution is the 64x32 display of the original CHIP-8, while the high resolution is the 128x64 display
+introduced by SUPER-CHIP. Pixels are stored row by row, one byte per pixel, where each bit of the byte is one of the
+XO-CHIP bitplanes. Clearing and scrolling only affect the selected planes
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Display {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+    selected_planes: u8
+}
+
+impl Display {
+    pub const LORES_WIDTH: usize = 64;
+    pub const LORES_HEIGHT: usize = 32;
+    pub const HIRES_WIDTH: usize = 128;
+    pub const HIRES_HEIGHT: usize = 64;
+    pub const N_PLANES: usize = 2;
+
+    pub fn new() -> Self {
+        Self {
+            width: Display::LORES_WIDTH,
+            height: Display::LORES_HEIGHT,
+            pixels: vec![0; Display::LORES_WIDTH * Display::LORES_HEIGHT],
+            selected_planes: 0b01
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == Display::HIRES_WIDTH
+    }
+
+    /// Switches between low and high resolution, the display is cleared in the process
+    pub fn set_hires(&mut self, hires: bool) {
+        (self.width, self.height) = if hires {
+            (Display::HIRES_WIDTH, Display::HIRES_HEIGHT)
+        } else {
+            (Display::LORES_WIDTH, Display::LORES_HEIGHT)
+        };
+        self.pixels = vec![0; self.width * self.height];
+    }
+
+    /// Selects the planes affected by drawing, clearing and scrolling, as a bitmask where bit 0 is the first plane
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & 0b11;
+    }
+
+    pub fn get_selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    pub fn clear(&mut self) {
+        let mask = !self.selected_planes;
+        self.pixels.iter_mut().for_each(|pixel| *pixel &= mask);
+    }
+
+    /// Gets the pixel value, which is the combination of the planes where the pixel is on
+    pub fn get_pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// XORs the pixel at the given position on the given plane, returning true if the pixel was turned off
+    pub fn flip_pixel(&mut self, x: usize, y: usize, plane: usize) -> bool {
+        let plane_bit = 1 << plane;
+        let pixel = &mut self.pixels[y * self.width + x];
+        *pixel ^= plane_bit;
+        *pixel & plane_bit == 0
+    }
+
+    pub fn get_pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.pixels.chunks(self.width)
+    }
+
+    pub fn scroll_down(&mut self, n_rows: usize) {
+        let offset = (n_rows * self.width).min(self.pixels.len());
+        let mut scrolled = self.pixels.clone();
+        scrolled.rotate_right(offset);
+        scrolled[..offset].fill(0);
+        self.merge_selected_planes(&scrolled);
+    }
+
+    /// Scrolls up, which is available only in XO-CHIP with 0x00DN
+    pub fn scroll_up(&mut self, n_rows: usize) {
+        let offset = (n_rows * self.width).min(self.pixels.len());
+        let mut scrolled = self.pixels.clone();
+        scrolled.rotate_left(offset);
+        let len = scrolled.len();
+        scrolled[len - offset..].fill(0);
+        self.merge_selected_planes(&scrolled);
+    }
+
+    pub fn scroll_right(&mut self, n_columns: usize) {
+        let n_columns = n_columns.min(self.width);
+        let mut scrolled = self.pixels.clone();
+        for row in scrolled.chunks_mut(self.width) {
+            row.rotate_right(n_columns);
+            row[..n_columns].fill(0);
+        }
+        self.merge_selected_planes(&scrolled);
+    }
+
+    pub fn scroll_left(&mut self, n_columns: usize) {
+        let n_columns = n_columns.min(self.width);
+        let mut scrolled = self.pixels.clone();
+        for row in scrolled.chunks_mut(self.width) {
+            row.rotate_left(n_columns);
+            let width = row.len();
+            row[width - n_columns..].fill(0);
+        }
+        self.merge_selected_planes(&scrolled);
+    }
+
+    /// Packs the first plane into bytes of 8 pixels, most significant bit first, as in the COSMAC VIP display buffer
+    pub fn to_bitmap(&self) -> Vec<u8> {
+        self.pixels.chunks(8)
+            .map(|pixels| pixels.iter().fold(0, |byte, &pixel| byte << 1 | (pixel & 1)))
+            .collect()
+    }
+
+    /// Sets the first plane from the bytes packed by [`Display::to_bitmap`], leaving the other planes untouched
+    pub fn load_bitmap(&mut self, bitmap: &[u8]) {
+        for (pixels, &byte) in self.pixels.chunks_mut(8).zip(bitmap) {
+            pixels.iter_mut().enumerate().for_each(|(i, pixel)| *pixel = (*pixel & !1) | (byte >> (7 - i) & 1));
+        }
+    }
+
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.is_hires());
+        writer.write_u8(self.selected_planes);
+        writer.write_bytes(&self.pixels);
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.set_hires(reader.read_bool()?);
+        self.selected_planes = reader.read_u8()?;
+        if self.selected_planes > 0b11 {
+            return Err(SaveStateError::InvalidField("selected planes"));
+        }
+        let pixels = reader.read_bytes(self.pixels.len())?;
+        if pixels.iter().any(|&pixel| pixel > 0b11) {
+            return Err(SaveStateError::InvalidField("display pixel"));
+        }
+        self.pixels.copy_from_slice(pixels);
+        Ok(())
+    }
+
+    fn merge_selected_planes(&mut self, scrolled: &[u8]) {
+        let mask = self.selected_planes;
+        self.pixels.iter_mut().zip(scrolled)
+            .for_each(|(pixel, scrolled_pixel)| *pixel = (*pixel & !mask) | (scrolled_pixel & mask));
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::savestate::{SaveStateError, StateReader, StateWriter};
+    use super::Display;
+
+    #[test]
+    fn bitmap_test() {
+        let mut display = Display::new();
+        display.flip_pixel(0, 0, 0);
+        display.flip_pixel(9, 0, 0);
+        display.flip_pixel(9, 0, 1);
+        let bitmap = display.to_bitmap();
+        assert_eq!(bitmap.len(), 256);
+        assert_eq!(bitmap[..2], [0x80, 0x40]);
+
+        let mut loaded = Display::new();
+        loaded.flip_pixel(9, 0, 1);
+        loaded.load_bitmap(&bitmap);
+        assert_eq!(loaded, display);
+    }
+
+    #[test]
+    fn resolution_test() {
+        let mut display = Display::new();
+        display.flip_pixel(63, 31, 0);
+        assert_eq!(display.get_pixels().len(), 64 * 32);
+
+        display.set_hires(true);
+        assert!(display.is_hires());
+        assert_eq!(display.get_pixels().len(), 128 * 64);
+        assert_eq!(display.get_pixel(63, 31), 0);
+    }
+
+    #[test]
+    fn flip_pixel_test() {
+        let mut display = Display::new();
+
+        assert!(!display.flip_pixel(3, 4, 0));
+        assert_eq!(display.get_pixel(3, 4), 1);
+        assert!(!display.flip_pixel(3, 4, 1));
+        assert_eq!(display.get_pixel(3, 4), 3);
+        assert!(display.flip_pixel(3, 4, 0));
+        assert_eq!(display.get_pixel(3, 4), 2);
+    }
+
+    #[test]
+    fn planes_test() {
+        let mut display = Display::new();
+        display.flip_pixel(0, 0, 0);
+        display.flip_pixel(0, 0, 1);
+
+        display.select_planes(0b10);
+        display.scroll_down(1);
+        assert_eq!(display.get_pixel(0, 0), 1);
+        assert_eq!(display.get_pixel(0, 1), 2);
+
+        display.select_planes(0b01);
+        display.clear();
+        assert_eq!(display.get_pixel(0, 0), 0);
+        assert_eq!(display.get_pixel(0, 1), 2);
+    }
+
+    #[test]
+    fn scroll_test() {
+        let mut display = Display::new();
+        display.flip_pixel(0, 0, 0);
+
+        display.scroll_down(2);
+        assert_eq!(display.get_pixel(0, 0), 0);
+        assert_eq!(display.get_pixel(0, 2), 1);
+        display.scroll_right(4);
+        assert_eq!(display.get_pixel(4, 2), 1);
+        display.scroll_left(4);
+        assert_eq!(display.get_pixel(0, 2), 1);
+        display.scroll_up(2);
+        assert_eq!(display.get_pixel(0, 0), 1);
+        display.scroll_left(4);
+        assert_eq!(display.get_pixels().iter().sum::<u8>(), 0);
+    }
+
+    #[test]
+    fn read_state_test() {
+        let mut writer = StateWriter::new();
+        Display::new().write_state(&mut writer);
+        let mut state = writer.into_bytes();
+
+        assert_eq!(Display::new().read_state(&mut StateReader::new(&state)), Ok(()));
+        state[1] = 0b100;
+        assert_eq!(Display::new().read_state(&mut StateReader::new(&state)), Err(SaveStateError::InvalidField("selected planes")));
+    }
+}
@@ -0,0 +1,486 @@
+use std::{io::{self, ErrorKind, Read, Write}, ops::Range};
+use crate::{chip8::Chip8, cpu::error::{CpuError, CpuErrorKind}, debugger::{Debugger, StopReason, Watchpoint}};
+
+/**
+Server of the GDB remote serial protocol, which lets GDB and other RSP clients drive a [`Chip8`] machine.
+
+The registers are numbered V0 to VF (0 to 15), I (16), PC (17), SP (18), DT (19) and ST (20), where I and PC are 16
+bits wide and big endian like the rest of the CHIP-8, and the other ones are 8 bits wide. The stack pointer is the
+depth of the call stack, so writing it has no effect. Breakpoints, watchpoints and stepping reuse [`Debugger`].
+
+The stream should be non-blocking, since [`GdbStub::poll`] only handles the bytes which are already available
+ */
+pub struct GdbStub<S: Read + Write> {
+    stream: S,
+    debugger: Debugger,
+    buffer: Vec<u8>,
+    last_stop: String,
+    attached: bool
+}
+
+impl<S: Read + Write> GdbStub<S> {
+    const N_REGISTERS: usize = 21;
+    /// Largest packet accepted from the client, advertised by qSupported
+    const PACKET_SIZE: usize = 0x1000;
+    const TARGET_XML: &'static str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+    /// Creates a stub for an attached client, the machine is paused until the client continues it
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            debugger: Debugger::new(),
+            buffer: Vec::new(),
+            last_stop: "S05".to_string(),
+            attached: true
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.debugger.is_paused()
+    }
+
+    /// Whether the client is still connected, it becomes false when the client detaches or closes the connection
+    pub fn is_attached(&self) -> bool {
+        self.attached
+    }
+
+    /// Reads the available bytes from the stream and answers the complete packets
+    pub fn poll(&mut self, chip8: &mut Chip8) -> io::Result<()> {
+        let mut chunk = [0; 1024];
+        while self.attached {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.attached = false,
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        while let Some(&first) = self.buffer.first() {
+            match first {
+                b'$' => {
+                    let Some(end) = self.buffer.iter().position(|&b| b == b'#') else { break; };
+                    if self.buffer.len() < end + 3 { break; }
+                    let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let checksum = std::str::from_utf8(&packet[end + 1..]).ok()
+                        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+                    if checksum != Some(packet_checksum(data)) {
+                        self.stream.write_all(b"-")?;
+                        continue;
+                    }
+                    self.stream.write_all(b"+")?;
+                    let reply = self.handle_packet(data, chip8);
+                    if let Some(reply) = reply {
+                        self.send_packet(&reply)?;
+                    }
+                },
+                // Interrupt request, sent by the client on Ctrl-C
+                0x03 => {
+                    self.buffer.remove(0);
+                    if !self.debugger.is_paused() {
+                        self.debugger.pause();
+                        self.stop("S02".to_string())?;
+                    }
+                },
+                // Acknowledgments and noise between packets
+                _ => { self.buffer.remove(0); }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    Executes one instruction if the client let the machine run, and notifies the client when the execution stops.
+
+    A CPU error is reported to the client as a signal, and it is returned to be logged by the frontend
+     */
+    pub fn tick(&mut self, chip8: &mut Chip8) -> io::Result<Option<CpuError>> {
+        if self.debugger.is_paused() { return Ok(None); }
+
+        match self.debugger.tick(chip8) {
+            Ok(Some(reason)) => self.stop(stop_reply(&reason))?,
+            Ok(None) if chip8.has_exited() => {
+                self.debugger.pause();
+                self.send_packet("W00")?;
+            },
+            Ok(None) => {},
+            Err(cpu_error) => {
+                self.stop(error_reply(&cpu_error))?;
+                return Ok(Some(cpu_error));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the reply to the packet, or None if the reply is sent later as a stop reply
+    fn handle_packet(&mut self, data: &[u8], chip8: &mut Chip8) -> Option<String> {
+        // The protocol is ASCII, anything else gets the empty reply of the unsupported packets
+        let (Some(&command), Ok(packet)) = (data.first(), std::str::from_utf8(data)) else {
+            return Some(String::new());
+        };
+        if !packet.is_ascii() { return Some(String::new()); }
+        let args = &packet[1..];
+        // A memory read is answered with two hex digits per byte
+        let max_len = chip8.get_memory().size().min(GdbStub::<S>::PACKET_SIZE / 2);
+
+        let reply = match command {
+            b'?' => self.last_stop.clone(),
+            b'g' => (0..GdbStub::<S>::N_REGISTERS).map(|reg_idx| read_register(chip8, reg_idx)).collect(),
+            b'G' => self.write_registers(chip8, args),
+            b'p' => usize::from_str_radix(args, 16).ok()
+                .filter(|&reg_idx| reg_idx < GdbStub::<S>::N_REGISTERS)
+                .map_or("E01".to_string(), |reg_idx| read_register(chip8, reg_idx)),
+            b'P' => args.split_once('=')
+                .and_then(|(reg_idx, value)| Some((usize::from_str_radix(reg_idx, 16).ok()?, decode_hex(value)?)))
+                .filter(|(reg_idx, _)| *reg_idx < GdbStub::<S>::N_REGISTERS)
+                .map_or("E01".to_string(), |(reg_idx, value)| { write_register(chip8, reg_idx, &value); "OK".to_string() }),
+            b'm' => memory_range(args, max_len)
+                .and_then(|range| range
+                    .map(|a| chip8.get_memory().read_byte(a).ok().map(|byte| format!("{:02x}", byte)))
+                    .collect::<Option<String>>())
+                .unwrap_or("E01".to_string()),
+            b'M' => args.split_once(':')
+                .and_then(|(range, data)| Some((memory_range(range, max_len)?, decode_hex(data)?)))
+                .filter(|(range, data)| range.len() == data.len())
+                .and_then(|(range, data)| range.zip(data)
+                    .try_for_each(|(address, byte)| chip8.get_memory_mut().write_byte(address, byte).ok())
+                    .map(|_| "OK".to_string()))
+                .unwrap_or("E01".to_string()),
+            b'c' => {
+                self.jump_to(chip8, args);
+                self.debugger.resume();
+                return None;
+            },
+            b's' => {
+                self.jump_to(chip8, args);
+                let reply = match self.debugger.step(chip8) {
+                    Ok(Some(reason)) => stop_reply(&reason),
+                    Ok(None) => "S05".to_string(),
+                    Err(cpu_error) => error_reply(&cpu_error)
+                };
+                self.last_stop = reply.clone();
+                reply
+            },
+            b'Z' | b'z' => self.set_point(command == b'Z', args, max_len),
+            b'H' => "OK".to_string(),
+            b'k' => {
+                self.attached = false;
+                return None;
+            },
+            b'D' => {
+                self.attached = false;
+                self.debugger.resume();
+                "OK".to_string()
+            },
+            _ if packet.starts_with("qSupported") => {
+                format!("PacketSize={:x};qXfer:features:read+", GdbStub::<S>::PACKET_SIZE)
+            },
+            _ if packet == "qAttached" => "1".to_string(),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                parse_address_length(&packet["qXfer:features:read:target.xml:".len()..])
+                    .and_then(|(offset, len)| Some((offset, offset.checked_add(len)?)))
+                    .map_or("E01".to_string(), |(offset, end)| {
+                        let xml = GdbStub::<S>::TARGET_XML;
+                        let chunk = &xml[offset.min(xml.len())..end.min(xml.len())];
+                        let prefix = if end >= xml.len() { 'l' } else { 'm' };
+                        format!("{}{}", prefix, chunk)
+                    })
+            },
+            // Empty reply to the unsupported packets
+            _ => String::new()
+        };
+        Some(reply)
+    }
+
+    fn write_registers(&mut self, chip8: &mut Chip8, args: &str) -> String {
+        let Some(data) = decode_hex(args) else { return "E01".to_string(); };
+        let mut position = 0;
+        for reg_idx in 0..GdbStub::<S>::N_REGISTERS {
+            let size = register_size(reg_idx);
+            let Some(value) = data.get(position..position + size) else { return "E01".to_string(); };
+            write_register(chip8, reg_idx, value);
+            position += size;
+        }
+        "OK".to_string()
+    }
+
+    /// Handles the Z (insert) and z (remove) packets, where the type is 0 or 1 for breakpoints, 2 for write
+    /// watchpoints, 3 for read watchpoints and 4 for access watchpoints
+    fn set_point(&mut self, insert: bool, args: &str, max_len: usize) -> String {
+        let mut fields = args.splitn(2, ',');
+        let point_type = fields.next();
+        let Some(range) = fields.next().and_then(|range| memory_range(range, max_len)) else {
+            return "E01".to_string();
+        };
+
+        let watchpoints: Vec<Watchpoint> = match point_type {
+            Some("0" | "1") => {
+                let address = range.start;
+                if insert { self.debugger.add_breakpoint(address); } else { self.debugger.remove_breakpoint(address); }
+                return "OK".to_string();
+            },
+            Some("2") => range.map(Watchpoint::MemoryWrite).collect(),
+            Some("3") => range.map(Watchpoint::MemoryRead).collect(),
+            Some("4") => range.flat_map(|a| [Watchpoint::MemoryWrite(a), Watchpoint::MemoryRead(a)]).collect(),
+            _ => return String::new()
+        };
+        for watchpoint in watchpoints {
+            if insert { self.debugger.add_watchpoint(watchpoint); } else { self.debugger.remove_watchpoint(watchpoint); }
+        }
+        "OK".to_string()
+    }
+
+    /// Moves the program counter to the address argument of the c and s packets, if any
+    fn jump_to(&self, chip8: &mut Chip8, args: &str) {
+        if let Ok(address) = usize::from_str_radix(args, 16) {
+            chip8.get_cpu_mut().set_pc(address);
+        }
+    }
+
+    fn stop(&mut self, reply: String) -> io::Result<()> {
+        self.last_stop = reply;
+        let reply = self.last_stop.clone();
+        self.send_packet(&reply)
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        write!(self.stream, "${}#{:02x}", data, packet_checksum(data.as_bytes()))?;
+        self.stream.flush()
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |checksum, &byte| checksum.wrapping_add(byte))
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint(Watchpoint::MemoryWrite(address), _) => format!("T05watch:{:x};", address),
+        StopReason::Watchpoint(Watchpoint::MemoryRead(address), _) => format!("T05rwatch:{:x};", address),
+        _ => "S05".to_string()
+    }
+}
+
+/// Signal reported for a CPU error, SIGILL for the instructions which can not be executed and SIGSEGV otherwise
+fn error_reply(cpu_error: &CpuError) -> String {
+    match cpu_error.kind {
+        CpuErrorKind::UnknownOpcode | CpuErrorKind::UnsupportedMachineRoutine(_) => "S04".to_string(),
+        _ => "S0b".to_string()
+    }
+}
+
+fn register_size(reg_idx: usize) -> usize {
+    match reg_idx {
+        16 | 17 => 2,
+        _ => 1
+    }
+}
+
+fn read_register(chip8: &Chip8, reg_idx: usize) -> String {
+    let cpu = chip8.get_cpu();
+    match reg_idx {
+        0..=15 => format!("{:02x}", cpu.get_var_registers()[reg_idx]),
+        16 => format!("{:04x}", cpu.get_index_register() as u16),
+        17 => format!("{:04x}", cpu.get_pc() as u16),
+        18 => format!("{:02x}", cpu.get_stack().len() as u8),
+        19 => format!("{:02x}", chip8.get_timer().get_delay_timer()),
+        _ => format!("{:02x}", chip8.get_timer().get_sound_timer())
+    }
+}
+
+fn write_register(chip8: &mut Chip8, reg_idx: usize, value: &[u8]) {
+    let value = value.iter().fold(0usize, |acc, &byte| (acc << 8) | byte as usize);
+    match reg_idx {
+        0..=15 => chip8.get_cpu_mut().set_var_register(reg_idx, value as u8),
+        16 => chip8.get_cpu_mut().set_index_register(value),
+        17 => chip8.get_cpu_mut().set_pc(value),
+        19 => chip8.get_timer_mut().set_delay_timer(value as u8),
+        20 => chip8.get_timer_mut().set_sound_timer(value as u8),
+        _ => {}
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses the "address,length" argument of the memory and breakpoint packets
+fn parse_address_length(args: &str) -> Option<(usize, usize)> {
+    let (address, len) = args.split_once(',')?;
+    Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+/// Parses an "address,length" argument into a range of addresses, which must not overflow or exceed the maximum length
+fn memory_range(args: &str, max_len: usize) -> Option<Range<usize>> {
+    let (address, len) = parse_address_length(args)?;
+    if len > max_len { return None; }
+    Some(address..address.checked_add(len)?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::VecDeque, io::{self, ErrorKind, Read, Write}};
+    use crate::chip8::Chip8;
+    use super::{GdbStub, packet_checksum};
+
+    /// Non-blocking stream which returns WouldBlock once the input is consumed
+    #[derive(Default)]
+    struct MockStream {
+        input: VecDeque<u8>,
+        output: Vec<u8>
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() { return Err(ErrorKind::WouldBlock.into()); }
+            let len = buf.len().min(self.input.len());
+            buf.iter_mut().zip(self.input.drain(..len)).for_each(|(b, input)| *b = input);
+            Ok(len)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // 0x200: V0 = 0x12, I = 0x300, store V0, jump 0x206
+    const ROM: [u8; 8] = [0x60, 0x12, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x06];
+
+    fn send(stub: &mut GdbStub<MockStream>, chip8: &mut Chip8, packet: &str) -> String {
+        let data = format!("${}#{:02x}", packet, packet_checksum(packet.as_bytes()));
+        stub.stream.input.extend(data.bytes());
+        stub.poll(chip8).unwrap();
+        String::from_utf8(std::mem::take(&mut stub.stream.output)).unwrap()
+    }
+
+    fn reply(packet: &str) -> String {
+        format!("+${}#{:02x}", packet, packet_checksum(packet.as_bytes()))
+    }
+
+    #[test]
+    fn registers_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut stub = GdbStub::new(MockStream::default());
+
+        assert_eq!(send(&mut stub, &mut chip8, "s"), reply("S05"));
+        let registers = format!("12{}00000202000000", "00".repeat(15));
+        assert_eq!(send(&mut stub, &mut chip8, "g"), reply(&registers));
+
+        assert_eq!(send(&mut stub, &mut chip8, "P11=0204"), reply("OK"));
+        assert_eq!(send(&mut stub, &mut chip8, "p11"), reply("0204"));
+        assert_eq!(chip8.get_cpu().get_pc(), 0x204);
+    }
+
+    #[test]
+    fn memory_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut stub = GdbStub::new(MockStream::default());
+
+        assert_eq!(send(&mut stub, &mut chip8, "m200,4"), reply("6012a300"));
+        assert_eq!(send(&mut stub, &mut chip8, "M300,2:abcd"), reply("OK"));
+        assert_eq!(chip8.get_memory().read_byte(0x301), Ok(0xCD));
+        assert_eq!(send(&mut stub, &mut chip8, "mffff,2"), reply("E01"));
+        assert_eq!(send(&mut stub, &mut chip8, "mffffffffffffffff,2"), reply("E01"));
+        assert_eq!(send(&mut stub, &mut chip8, "m200,1001"), reply("E01"));
+        assert_eq!(send(&mut stub, &mut chip8, "Mffffffffffffffff,1:00"), reply("E01"));
+        assert_eq!(send(&mut stub, &mut chip8, "Z2,200,ffffffffffffffff"), reply("E01"));
+        assert_eq!(send(&mut stub, &mut chip8, "qXfer:features:read:target.xml:1,ffffffffffffffff"), reply("E01"));
+    }
+
+    #[test]
+    fn breakpoint_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut stub = GdbStub::new(MockStream::default());
+
+        assert_eq!(send(&mut stub, &mut chip8, "Z0,204,2"), reply("OK"));
+        assert_eq!(send(&mut stub, &mut chip8, "c"), "+");
+        assert!(!stub.is_paused());
+        (0..10).for_each(|_| { stub.tick(&mut chip8).unwrap(); });
+
+        assert!(stub.is_paused());
+        assert_eq!(chip8.get_cpu().get_pc(), 0x204);
+        assert_eq!(String::from_utf8(std::mem::take(&mut stub.stream.output)).unwrap(), "$S05#b8");
+    }
+
+    #[test]
+    fn watchpoint_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut stub = GdbStub::new(MockStream::default());
+
+        assert_eq!(send(&mut stub, &mut chip8, "Z2,300,1"), reply("OK"));
+        send(&mut stub, &mut chip8, "c");
+        (0..10).for_each(|_| { stub.tick(&mut chip8).unwrap(); });
+
+        assert_eq!(chip8.get_cpu().get_pc(), 0x206);
+        assert_eq!(String::from_utf8(std::mem::take(&mut stub.stream.output)).unwrap(), reply("T05watch:300;")[1..]);
+        assert_eq!(send(&mut stub, &mut chip8, "?"), reply("T05watch:300;"));
+    }
+
+    #[test]
+    fn framing_test() {
+        let mut chip8 = Chip8::builder().rom(&ROM).build().unwrap();
+        let mut stub = GdbStub::new(MockStream::default());
+
+        stub.stream.input.extend(b"+$g#00");
+        stub.poll(&mut chip8).unwrap();
+        assert_eq!(stub.stream.output, b"-");
+
+        stub.stream.output.clear();
+        stub.stream.input.extend(b"$?#3");
+        stub.poll(&mut chip8).unwrap();
+        assert!(stub.stream.output.is_empty());
+        stub.stream.input.extend(b"f");
+        stub.poll(&mut chip8).unwrap();
+        assert_eq!(stub.stream.output, reply("S05").as_bytes());
+
+        send(&mut stub, &mut chip8, "c");
+        stub.stream.input.push_back(0x03);
+        stub.poll(&mut chip8).unwrap();
+        assert!(stub.is_paused());
+        assert_eq!(stub.stream.output, b"$S02#b5");
+
+        // Non-ASCII and empty packets get the empty reply
+        stub.stream.output.clear();
+        stub.stream.input.extend(b"$\xc3\xa9#6c$#00");
+        stub.poll(&mut chip8).unwrap();
+        assert_eq!(stub.stream.output, b"+$#00+$#00");
+    }
+}
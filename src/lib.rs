@@ -1,4 +1,20 @@
 pub mod memory;
 pub mod stack;
 pub mod timer;
+pub mod rng;
+pub mod savestate;
+pub mod rewind;
+pub mod movie;
+pub mod debugger;
+pub mod assembler;
+pub mod disassembler;
+pub mod octo;
+pub mod gdb;
+pub mod trace;
+pub mod selftest;
+#[cfg(test)]
+mod regression;
+pub mod display;
+pub mod screenshot;
 pub mod cpu;
+pub mod chip8;
@@ -1,15 +1,15 @@
 pub mod renderer;
 pub mod sound;
 
-use std::{time::{Duration, Instant}, io::{BufReader, Read}, fs::File};
+use std::{time::{Duration, Instant}, io::{self, BufRead, BufReader, BufWriter, Read, Write}, fs::{self, File}, path::Path, sync::mpsc::{self, Receiver}, thread, net::{TcpListener, TcpStream}};
 use anyhow::Error;
-use clap::Parser;
-use log::{debug, info, error};
-use sdl2::{event::Event, keyboard::{Keycode, Scancode}, audio::AudioSpecDesired};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::{debug, info, warn, error};
+use sdl2::{event::Event, keyboard::{Keycode, Scancode}, audio::{AudioQueue, AudioSpecDesired}, render::Canvas, video::Window, Sdl, VideoSubsystem};
 use simple_logger::SimpleLogger;
-use chip_8_emu::{cpu::Cpu, memory::Memory, timer::Timer};
+use chip_8_emu::{chip8::{Chip8, Chip8Builder}, cpu::{error::CpuError, quirks::Quirks}, assembler::assemble_file_at, debugger::Debugger, disassembler::disassemble_at, gdb::GdbStub, memory::Memory, movie::{self, Movie}, octo, rewind::RewindBuffer, screenshot::{self, TextStyle}, selftest::{self, format_results, run_selftests}, trace::diff_traces};
 use renderer::Renderer;
-use sound::SquareWave;
+use sound::{AudioStream, Synthesizer, Waveform};
 
 const CHIP8_KEYS: [Scancode; 16] = [
     Scancode::Num1,
@@ -40,6 +40,53 @@ fn find_sdl_gl_driver() -> Result<u32, Error> {
     Err(Error::msg("Could not find OpenGL driver!"))
 }
 
+/// Backend which draws the window
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum RendererBackend {
+    /// OpenGL, or the software renderer if OpenGL is not available
+    #[default]
+    Auto,
+    Opengl,
+    /// SDL software renderer, which needs no GPU driver
+    Software
+}
+
+/// Opens the emulator window drawn by the backend
+fn open_canvas(video_subsystem: &VideoSubsystem, backend: RendererBackend) -> Result<Canvas<Window>, Error> {
+    let open_opengl = || -> Result<Canvas<Window>, Error> {
+        let window = video_subsystem.window("Chip-8 Emulator", 640, 320).opengl().build()?;
+        Ok(window.into_canvas().index(find_sdl_gl_driver()?).build()?)
+    };
+    let open_software = || -> Result<Canvas<Window>, Error> {
+        let window = video_subsystem.window("Chip-8 Emulator", 640, 320).build()?;
+        Ok(window.into_canvas().software().build()?)
+    };
+    match backend {
+        RendererBackend::Opengl => open_opengl(),
+        RendererBackend::Software => open_software(),
+        RendererBackend::Auto => open_opengl().or_else(|e| {
+            warn!("OpenGL is not available ({}), falling back to the software renderer", e);
+            open_software()
+        })
+    }
+}
+
+/// Sample frequency requested to the audio device, and used by the buzzer without audio device
+const AUDIO_SAMPLE_FREQ: i32 = 44100;
+
+/// Opens the audio queue of the buzzer, or logs a warning and returns None if there is no audio device
+fn open_audio_queue(sdl_context: &Sdl) -> Option<AudioQueue<f32>> {
+    let desired_spec = AudioSpecDesired {
+        freq: Some(AUDIO_SAMPLE_FREQ),
+        channels: Some(1),  // mono
+        samples: None       // default sample size
+    };
+    sdl_context.audio()
+        .and_then(|audio_subsystem| audio_subsystem.open_queue(None, &desired_spec))
+        .inspect_err(|e| warn!("Could not open an audio device, running without sound: {}", e))
+        .ok()
+}
+
 fn read_rom_from_file(file_path: &str) -> Result<Vec<u8>, Error> {
     let file_handle = File::open(file_path)?;
     let mut reader = BufReader::new(file_handle);
@@ -50,75 +97,731 @@ fn read_rom_from_file(file_path: &str) -> Result<Vec<u8>, Error> {
     Ok(buffer)
 }
 
+/// Reads the program to be run, where the Octo sources (`.8o` files) are compiled into a ROM loaded at the address
+fn read_program(file_path: &str, load_address: usize) -> Result<Vec<u8>, Error> {
+    match Path::new(file_path).extension().is_some_and(|extension| extension == "8o") {
+        true => Ok(octo::compile_at(&fs::read_to_string(file_path)?, load_address)
+            .map_err(|e| Error::msg(format!("{}:{}:{}: {}", file_path, e.line, e.column, e.message)))?),
+        false => read_rom_from_file(file_path)
+    }
+}
+
+/// Path of the save state file of the given slot, which is stored next to the ROM
+fn state_slot_path(rom_path: &str, slot: u8) -> String {
+    format!("{}.state{}", rom_path, slot)
+}
+
+fn save_state_to_file(chip8: &Chip8, file_path: &str) -> Result<(), Error> {
+    fs::write(file_path, chip8.save_state())?;
+    Ok(())
+}
+
+fn load_state_from_file(chip8: &mut Chip8, file_path: &str) -> Result<(), Error> {
+    chip8.load_state(&fs::read(file_path)?)?;
+    Ok(())
+}
+
+fn read_movie(file_path: &str) -> Result<Movie, Error> {
+    Ok(Movie::decode(&fs::read(file_path)?)?)
+}
+
+/// Stores the checksum of the machine into the movie and writes it
+fn save_movie(movie: &mut Movie, chip8: &Chip8, file_path: &str) -> Result<(), Error> {
+    movie.finish(chip8);
+    fs::write(file_path, movie.encode())?;
+    info!("Movie of {} frames saved to {}", movie.frames.len(), file_path);
+    Ok(())
+}
+
+/// Logs whether the run played so far matches the one recorded in the movie
+fn check_movie_end(movie: &Movie, chip8: &Chip8, frames: usize) {
+    let checksum = movie::state_checksum(chip8);
+    match checksum == movie.checksum {
+        true => info!("Movie ended after {} frames, the run matches the recording", frames),
+        false => warn!("Movie ended after {} frames, the run diverged: checksum 0x{:016X} instead of 0x{:016X}", frames, checksum, movie.checksum)
+    }
+}
+
+/// Reads the debugger commands from the standard input on another thread, so the window keeps being refreshed
+fn spawn_stdin_reader() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Waits for a GDB client on the address and returns a stub for it
+fn accept_gdb_client(address: &str) -> Result<GdbStub<TcpStream>, Error> {
+    let listener = TcpListener::bind(address)?;
+    info!("Waiting for a GDB client on {}", address);
+    let (stream, client_address) = listener.accept()?;
+    stream.set_nonblocking(true)?;
+    stream.set_nodelay(true)?;
+    info!("GDB client {} attached", client_address);
+    Ok(GdbStub::new(stream))
+}
+
+/// Interpreter whose behavior is reproduced for the ambiguous instructions
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum QuirksPreset {
+    CosmacVip,
+    Chip48,
+    SuperChip,
+    XoChip
+}
+
+impl From<QuirksPreset> for Quirks {
+    fn from(preset: QuirksPreset) -> Self {
+        match preset {
+            QuirksPreset::CosmacVip => Quirks::COSMAC_VIP,
+            QuirksPreset::Chip48 => Quirks::CHIP_48,
+            QuirksPreset::SuperChip => Quirks::SUPER_CHIP,
+            QuirksPreset::XoChip => Quirks::XO_CHIP
+        }
+    }
+}
+
+/// Size of the memory, instead of the one of the quirks preset
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum MemorySize {
+    /// 4 KiB, as on CHIP-8 and SUPER-CHIP
+    #[value(name = "4k")]
+    Standard,
+    /// 64 KiB, as on XO-CHIP
+    #[value(name = "64k")]
+    Extended
+}
+
+/// Parses an address, which is hexadecimal with the 0x prefix and decimal otherwise
+fn parse_address(value: &str) -> Result<usize, String> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse()
+    }.map_err(|e| e.to_string())
+}
+
+/// Slow motion speeds, cycled through with the M key
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SlowMotion {
+    Off,
+    Half,
+    Quarter
+}
+
+impl SlowMotion {
+    fn next(self) -> Self {
+        match self {
+            SlowMotion::Off => SlowMotion::Half,
+            SlowMotion::Half => SlowMotion::Quarter,
+            SlowMotion::Quarter => SlowMotion::Off
+        }
+    }
+
+    /// Number of frame durations each frame lasts
+    fn frame_duration_factor(self) -> u32 {
+        match self {
+            SlowMotion::Off => 1,
+            SlowMotion::Half => 2,
+            SlowMotion::Quarter => 4
+        }
+    }
+}
+
+/// Waveform of the buzzer
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum WaveformArg {
+    /// XO-CHIP audio pattern of the ROM, a 500 Hz square wave unless the ROM changes it
+    Pattern,
+    Square,
+    /// Square wave with the duty cycle of --duty-cycle
+    Pulse,
+    Triangle,
+    Sine,
+    Noise
+}
+
+impl From<WaveformArg> for Waveform {
+    fn from(waveform: WaveformArg) -> Self {
+        match waveform {
+            WaveformArg::Pattern => Waveform::Pattern,
+            WaveformArg::Square => Waveform::Square,
+            WaveformArg::Pulse => Waveform::Pulse,
+            WaveformArg::Triangle => Waveform::Triangle,
+            WaveformArg::Sine => Waveform::Sine,
+            WaveformArg::Noise => Waveform::Noise
+        }
+    }
+}
+
+/// Volume change of the volume hotkeys
+const VOLUME_STEP: f32 = 0.1;
+
+/// Tone change of the tone hotkeys, a semitone
+const TONE_STEP: f32 = 1.059_463_1;
+
+/// Description of the buzzer voice for the log
+fn describe_voice(synthesizer: &Synthesizer) -> String {
+    let waveform = match synthesizer.get_waveform() {
+        Waveform::Pattern => Waveform::Pattern.to_string(),
+        waveform => format!("{} at {:.0} Hz", waveform, synthesizer.get_tone())
+    };
+    let volume = match synthesizer.is_muted() {
+        true => "muted".to_string(),
+        false => format!("volume {:.0}%", synthesizer.get_volume() * 100.0)
+    };
+    format!("Buzzer: {}, {}", waveform, volume)
+}
+
+/// Maximum number of frames of audio waiting in the audio queue
+const AUDIO_LATENCY_FRAMES: u32 = 4;
+
+/// Instructions per frame the speed hotkeys step through
+const SPEED_STEPS: [u32; 16] = [1, 2, 3, 5, 7, 9, 11, 15, 20, 30, 50, 100, 200, 500, 1000, 2000];
+
+/// Next speed step above (or below) the instructions per frame, which stay unchanged past the last step
+fn step_speed(instructions_per_frame: u32, up: bool) -> u32 {
+    match up {
+        true => SPEED_STEPS.iter().copied().find(|&step| step > instructions_per_frame),
+        false => SPEED_STEPS.iter().copied().rev().find(|&step| step < instructions_per_frame)
+    }.unwrap_or(instructions_per_frame)
+}
+
+/// Title of the window, with the speed and the state of the emulation
+fn window_title(chip8: &Chip8, slow_motion: SlowMotion, fast_forward: bool, paused: bool, halted: Option<&CpuError>) -> String {
+    let instructions_per_second = chip8.get_instructions_per_frame() * chip8.get_timer().get_frequency();
+    let mut title = format!("Chip-8 Emulator - {} IPS", instructions_per_second);
+    match (fast_forward, slow_motion) {
+        (true, _) => title.push_str(" - Fast forward"),
+        (false, SlowMotion::Off) => {},
+        (false, slow_motion) => title.push_str(&format!(" - {}%", 100 / slow_motion.frame_duration_factor()))
+    }
+    if paused {
+        title.push_str(" - Paused");
+    }
+    if let Some(cpu_error) = halted {
+        title.push_str(&format!(" - Halted: {}", cpu_error));
+    }
+    title
+}
+
+/// Format of the framebuffer written by the headless runner
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ScreenFormat {
+    Png,
+    Ascii,
+    /// Unicode half blocks, two rows of pixels per character
+    Blocks
+}
+
+/// What to do when the CPU fails to execute an instruction
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum ErrorPolicy {
+    /// Stop the execution and show the error in the window title
+    #[default]
+    Halt,
+    /// Skip the faulty instruction as if it were a NOP, but halt once the PC leaves the memory
+    Ignore,
+    /// Pause the execution and dump the CPU state, press Return to resume (or enter the debugger with --debug)
+    Break
+}
+
+/// Tools which run instead of the emulator
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a ROM or an Octo source file (.8o), same as without subcommand
+    Run(EmulatorArgs),
+    /// Disassemble a ROM into source which can be assembled back into the same ROM
+    Disasm {
+        /// ROM path to be disassembled
+        rom: String,
+
+        /// Address where the ROM is loaded, e.g. 0x600 for ETI-660 ROMs
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: usize,
+
+        /// Output file of the source, printed to the standard output if not set
+        #[arg(short, long)]
+        output: Option<String>
+    },
+    /// Assemble a source file into a ROM
+    Asm {
+        /// Source path to be assembled
+        source: String,
+
+        /// Address where the ROM is loaded, e.g. 0x600 for ETI-660 ROMs
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_address: usize,
+
+        /// Output file of the ROM
+        #[arg(short, long)]
+        output: String
+    },
+    /// Run a ROM without window for a number of frames, then print the registers and write the framebuffer.
+    /// The exit code is 1 on CPU errors
+    Headless {
+        /// ROM path to be loaded, or Octo source file (.8o) to be compiled
+        rom: String,
+
+        /// Number of frames (at 60 Hz) to be emulated
+        #[arg(long, default_value_t = 600)]
+        frames: u32,
+
+        #[command(flatten)]
+        machine: MachineArgs,
+
+        /// Output file of the framebuffer, printed to the standard output if not set
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Format of the framebuffer, PNG if the output file ends with .png and Unicode blocks otherwise
+        #[arg(long, value_enum)]
+        format: Option<ScreenFormat>,
+
+        /// Size in pixels of a CHIP-8 pixel in the PNG image
+        #[arg(long, default_value_t = 4, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        scale: usize
+    },
+    /// Run the third-party conformance ROMs under every quirks preset and compare their screens to the ones of a
+    /// reference interpreter, then print a table of the results. The exit code is 1 if a ROM fails or can not be read
+    Selftest {
+        /// Directory of the conformance ROMs
+        #[arg(long, default_value = selftest::SELFTEST_DIR)]
+        roms: String
+    },
+    /// Play a movie (see --record) without window and check that the run ends with the recorded checksum.
+    /// The exit code is 1 if the run diverges
+    Verify {
+        /// ROM path the movie was recorded with, or Octo source file (.8o) to be compiled
+        rom: String,
+
+        /// Movie path to be played
+        movie: String
+    },
+    /// Compare two execution traces (see --trace) and report the first divergent instruction
+    TraceDiff {
+        /// Trace of this emulator or of a reference emulator
+        left: String,
+
+        /// Trace compared against the first one
+        right: String,
+
+        /// Number of instructions shown before the divergence
+        #[arg(short, long, default_value_t = 5)]
+        context: usize
+    }
+}
+
+fn run_command(command: &Command) -> Result<(), Error> {
+    match command {
+        Command::Disasm { rom, load_address, output } => {
+            let source = disassemble_at(&read_rom_from_file(rom)?, *load_address);
+            write_output(output.as_deref(), source.as_bytes())
+        },
+        Command::Asm { source, load_address, output } => {
+            let rom = assemble_file_at(Path::new(source), *load_address)?;
+            write_output(Some(output), &rom)
+        },
+        Command::Headless { rom, frames, machine, output, format, scale } => {
+            let mut chip8 = machine.builder(&read_program(rom, machine.load_address)?, machine.seed).build()?;
+            let run_result = chip8.run_frames(*frames);
+
+            let format = format.unwrap_or(match output {
+                Some(output) if output.ends_with(".png") => ScreenFormat::Png,
+                _ => ScreenFormat::Blocks
+            });
+            let screen = match format {
+                ScreenFormat::Png if output.is_none() => return Err(Error::msg("PNG output needs an output file")),
+                ScreenFormat::Png => screenshot::encode_png(chip8.get_display(), *scale),
+                ScreenFormat::Ascii => screenshot::format_text(chip8.get_display(), TextStyle::Ascii).into_bytes(),
+                ScreenFormat::Blocks => screenshot::format_text(chip8.get_display(), TextStyle::Blocks).into_bytes()
+            };
+            write_output(output.as_deref(), &screen)?;
+
+            if let Err(cpu_error) = run_result {
+                println!("CPU error: {}\n{}", cpu_error, Debugger::format_registers(&chip8));
+                std::process::exit(1);
+            }
+            println!("{}", Debugger::format_registers(&chip8));
+            Ok(())
+        },
+        Command::Selftest { roms } => {
+            let results = run_selftests(Path::new(roms));
+            println!("{}", format_results(&results));
+            let mut failed = false;
+            for (_, results) in &results {
+                let Ok(results) = results else {
+                    failed = true;
+                    continue;
+                };
+                for result in results.iter().filter(|result| !result.verified() && result.error.is_none()) {
+                    println!("{} under {} has no reference hash yet, framebuffer hash 0x{:016X}", result.name, result.preset, result.hash);
+                }
+                for failure in results.iter().filter(|result| result.failed()) {
+                    failed = true;
+                    match failure.expected_hash {
+                        Some(expected_hash) => println!("\n{} failed under {}: framebuffer hash 0x{:016X} instead of 0x{:016X}", failure.name, failure.preset, failure.hash, expected_hash),
+                        None => println!("\n{} failed under {}", failure.name, failure.preset)
+                    }
+                    if let Some(cpu_error) = &failure.error {
+                        println!("CPU error: {}", cpu_error);
+                    }
+                    print!("{}", screenshot::format_text(&failure.display, TextStyle::Blocks));
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+            Ok(())
+        },
+        Command::Verify { rom, movie } => {
+            let movie = Movie::decode(&fs::read(movie)?)?;
+            let playback = movie.play(&read_program(rom, movie.load_address)?)?;
+            if let Some(cpu_error) = &playback.error {
+                println!("CPU error at frame {}: {}", playback.frames, cpu_error);
+            }
+            println!("{}", Debugger::format_registers(&playback.chip8));
+            if !playback.matches(&movie) {
+                println!("Run diverged after {} frames: checksum 0x{:016X} instead of 0x{:016X}", playback.frames, playback.checksum, movie.checksum);
+                std::process::exit(1);
+            }
+            println!("Run matches the movie after {} frames", playback.frames);
+            Ok(())
+        },
+        Command::TraceDiff { left, right, context } => {
+            match diff_traces(&fs::read_to_string(left)?, &fs::read_to_string(right)?, *context) {
+                Some(divergence) => {
+                    println!("{}", divergence);
+                    std::process::exit(1);
+                },
+                None => println!("Traces are identical")
+            }
+            Ok(())
+        },
+        Command::Run(_) => unreachable!("the emulator is started by main")
+    }
+}
+
+/// Writes the data to the file, or to the standard output if there is no file
+fn write_output(file_path: Option<&str>, data: &[u8]) -> Result<(), Error> {
+    match file_path {
+        Some(file_path) => fs::write(file_path, data)?,
+        None => io::stdout().write_all(data)?
+    }
+    Ok(())
+}
+
 /// Chip 8 emulator implemented in Rust
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// ROM path to be loaded
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    emulator: EmulatorArgs
+}
+
+/// Options of the emulated machine, shared by the emulator and the headless runner
+#[derive(clap::Args, Debug)]
+struct MachineArgs {
+    /// Quirks preset of the interpreter the ROM was written for (modern behavior if not set)
+    #[arg(long, value_enum)]
+    quirks: Option<QuirksPreset>,
+
+    /// Keep the call stack and the display buffer in memory at 0xEA0 and 0xF00, as on the COSMAC VIP
+    #[arg(long)]
+    vip_memory_map: bool,
+
+    /// Memory size, 64k for the xo-chip quirks and 4k otherwise if not set
+    #[arg(long, value_enum)]
+    memory_size: Option<MemorySize>,
+
+    /// Address where the ROM is loaded and the execution starts, e.g. 0x600 for ETI-660 ROMs
+    #[arg(long, default_value = "0x200", value_parser = parse_address)]
+    load_address: usize,
+
+    /// Number of instructions executed per frame, at 60 frames per second
+    #[arg(long, default_value_t = Chip8Builder::DEFAULT_INSTRUCTIONS_PER_FRAME, value_parser = clap::value_parser!(u32).range(1..))]
+    ipf: u32,
+
+    /// Seed of the random number generator of 0xCXNN, so that runs with the same inputs are the same (random if not set)
+    #[arg(long)]
+    seed: Option<u64>
+}
+
+impl MachineArgs {
+    /// Quirks of the preset (modern behavior if not set), with the call stack and display buffer in memory if enabled
+    fn quirks(&self) -> Quirks {
+        Quirks { vip_memory_map: self.vip_memory_map, ..self.quirks.map(Quirks::from).unwrap_or_default() }
+    }
+
+    /// Memory size of the interpreter, XO-CHIP ROMs can address 64 KiB of memory
+    fn memory_size(&self) -> usize {
+        match (self.memory_size, self.quirks) {
+            (Some(MemorySize::Extended), _) | (None, Some(QuirksPreset::XoChip)) => Memory::XO_CHIP_SIZE,
+            _ => Memory::DEFAULT_SIZE
+        }
+    }
+
+    /// Builder of the machine running the ROM, with a random seed if the seed is not set
+    fn builder(&self, rom: &[u8], seed: Option<u64>) -> Chip8Builder {
+        let builder = Chip8::builder()
+            .rom(rom)
+            .memory_size(self.memory_size())
+            .load_address(self.load_address)
+            .quirks(self.quirks())
+            .instructions_per_frame(self.ipf);
+        match seed {
+            Some(seed) => builder.seed(seed),
+            None => builder
+        }
+    }
+}
+
+/// Options of the emulator
+#[derive(clap::Args, Debug)]
+struct EmulatorArgs {
+    /// ROM path to be loaded, or Octo source file (.8o) to be compiled
     #[arg(required=true)]
-    rom: String
+    rom: Option<String>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    /// Backend which draws the window
+    #[arg(long, value_enum, default_value_t)]
+    renderer: RendererBackend,
+
+    /// Run without sound, instead of opening an audio device
+    #[arg(long)]
+    no_audio: bool,
+
+    /// Waveform of the buzzer, the XO-CHIP audio pattern for the xo-chip quirks and a square wave otherwise if not set
+    #[arg(long, value_enum)]
+    waveform: Option<WaveformArg>,
+
+    /// Frequency in Hz of the buzzer, for every waveform but the XO-CHIP pattern
+    #[arg(long, default_value_t = Synthesizer::DEFAULT_TONE)]
+    tone: f32,
+
+    /// Fraction of the period during which the pulse wave is high, between 0 and 1
+    #[arg(long, default_value_t = Synthesizer::DEFAULT_DUTY_CYCLE)]
+    duty_cycle: f32,
+
+    /// Master volume in percent
+    #[arg(long, default_value_t = (Synthesizer::DEFAULT_VOLUME * 100.0) as u8, value_parser = clap::value_parser!(u8).range(0..=100))]
+    volume: u8,
+
+    /// Start with the sound muted
+    #[arg(long)]
+    mute: bool,
+
+    /// Behavior when the CPU fails to execute an instruction
+    #[arg(long, value_enum, default_value_t)]
+    on_error: ErrorPolicy,
+
+    /// Save state file to be loaded at startup
+    #[arg(long, conflicts_with_all = ["record", "play"])]
+    load_state: Option<String>,
+
+    /// Maximum number of snapshots kept for rewinding
+    #[arg(long, default_value_t = 3600)]
+    rewind_length: usize,
+
+    /// Number of frames between two rewind snapshots
+    #[arg(long, default_value_t = 1)]
+    rewind_interval: u32,
+
+    /// Key to hold for playing the game backward, as named by SDL
+    #[arg(long, default_value = "Backspace")]
+    rewind_key: String,
+
+    /// Start paused in the interactive debugger, which reads its commands from the standard input
+    #[arg(long, conflicts_with_all = ["record", "play"])]
+    debug: bool,
+
+    /// Start paused and wait for a GDB remote serial protocol client on the address, e.g. 127.0.0.1:1234
+    #[arg(long, conflicts_with_all = ["debug", "record", "play"])]
+    gdb: Option<String>,
+
+    /// Record the keypad of each frame into the movie file, written when the emulator quits or the CPU fails
+    #[arg(long, conflicts_with = "play")]
+    record: Option<String>,
+
+    /// Play the keypad of a movie file recorded with --record, with its quirks and seed
+    #[arg(long)]
+    play: Option<String>,
+
+    /// Write a line per executed instruction to the file, with the registers before its execution
+    #[arg(long)]
+    trace: Option<String>
 }
 
 fn main() -> Result<(), Error> {
-    let args = Args::parse();
+    let args = match Args::parse() {
+        Args { command: Some(Command::Run(emulator_args)), .. } => emulator_args,
+        Args { command: Some(command), .. } => return run_command(&command),
+        Args { emulator, .. } => emulator
+    };
+    let rom_path = args.rom.as_deref().ok_or_else(|| Error::msg("Missing ROM path"))?;
 
     SimpleLogger::new().init()?;
 
     info!("Chip 8 Emulator is starting...");
 
-    let mut memory = Memory::new();
-    memory.load_font_data();
-    memory.load_rom_data(
-        &(read_rom_from_file(&args.rom)
-            .map_err(|e| {error!("Could not read ROM {} successfully", &args.rom); e})?)
-    );
+    let rom = read_program(rom_path, args.machine.load_address)
+        .inspect_err(|_| error!("Could not read ROM {} successfully", rom_path))?;
+
+    let quirks = args.machine.quirks();
+    debug!("Using quirks {:?}", quirks);
+
+    // A recorded run must be reproducible, so its seed is always known
+    let seed = args.machine.seed.or_else(|| args.record.is_some().then(rand::random));
+    let memory_size = args.machine.memory_size();
+    let mut recording = args.record.is_some()
+        .then(|| Movie::new(&rom, quirks, memory_size, seed.unwrap_or_default(), args.machine.ipf, args.machine.load_address));
+    let mut playing = match &args.play {
+        Some(movie_path) => Some(read_movie(movie_path).inspect_err(|_| error!("Could not read movie {} successfully", movie_path))?),
+        None => None
+    };
+    let movie_mode = recording.is_some() || playing.is_some();
+    let mut movie_frame = 0;
 
-    info!("ROM {} loaded successfully", &args.rom);
+    let mut chip8 = match &playing {
+        Some(movie) => movie.build_machine(&rom)?,
+        None => args.machine.builder(&rom, seed).build()?
+    };
 
-    let mut timer = Timer::new();
+    info!("ROM {} loaded successfully", rom_path);
 
-    let mut cpu = Cpu::new(&mut memory);
+    if let Some(state_path) = &args.load_state {
+        load_state_from_file(&mut chip8, state_path)
+            .inspect_err(|_| error!("Could not load save state {} successfully", state_path))?;
+        info!("Save state {} loaded successfully", state_path);
+    }
 
     let sdl_context = sdl2::init().map_err(Error::msg)?;
-    let window = sdl_context
-        .video()
-        .map_err(Error::msg)?
-        .window("Chip-8 Emulator", 640, 320)
-        .opengl()
-        .build()?;
-
-    let mut renderer = Renderer::new(
-        window
-            .into_canvas()
-            .index(find_sdl_gl_driver()?)
-            .build()?
-    );
-
-    let audio_subsystem = sdl_context.audio().map_err(Error::msg)?;
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),  // mono
-        samples: None       // default sample size
-    };
+    let video_subsystem = sdl_context.video().map_err(Error::msg)?;
+    let mut renderer = Renderer::new(open_canvas(&video_subsystem, args.renderer)?);
 
-    let audio_device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
-        SquareWave {
-            phase_inc: 440.0 / spec.freq as f32,
-            phase: 0.0,
-            volume: 0.25
-        }
-    }).map_err(Error::msg)?;
+    let audio_queue = match args.no_audio {
+        true => None,
+        false => open_audio_queue(&sdl_context)
+    };
+    let sample_freq = audio_queue.as_ref().map_or(AUDIO_SAMPLE_FREQ, |audio_queue| audio_queue.spec().freq);
+    let mut synthesizer = Synthesizer::new(sample_freq);
+    // Only XO-CHIP ROMs can load an audio pattern, the other ones expect a plain beep
+    synthesizer.set_waveform(match (args.waveform, args.machine.quirks) {
+        (Some(waveform), _) => waveform.into(),
+        (None, Some(QuirksPreset::XoChip)) => Waveform::Pattern,
+        (None, _) => Waveform::Square
+    });
+    synthesizer.set_tone(args.tone);
+    synthesizer.set_duty_cycle(args.duty_cycle);
+    synthesizer.set_volume(args.volume as f32 / 100.0);
+    synthesizer.set_muted(args.mute);
+    let mut audio_stream = AudioStream::new(synthesizer, chip8.get_timer().get_frequency());
+    // Samples queued beyond this latency are dropped, which happens when the emulation runs faster than real time
+    let max_queued_bytes = sample_freq as u32 * AUDIO_LATENCY_FRAMES / chip8.get_timer().get_frequency()
+        * std::mem::size_of::<f32>() as u32;
+    if let Some(audio_queue) = &audio_queue {
+        audio_queue.resume();
+    }
 
     let mut event_pump = sdl_context.event_pump().map_err(Error::msg)?;
-    
 
-    let mut last_cpu_time = Instant::now();
-    let mut last_timer_time = Instant::now();
-    let mut last_renderer_time = Instant::now();
+    let rewind_scancode = Scancode::from_name(&args.rewind_key)
+        .ok_or_else(|| Error::msg(format!("Unknown rewind key {}", args.rewind_key)))?;
+    let mut rewind_buffer = RewindBuffer::new(args.rewind_length, args.rewind_interval);
+
+    let mut debugger = args.debug.then(Debugger::new);
+    let debugger_commands = args.debug.then(spawn_stdin_reader);
+    if debugger.is_some() {
+        println!("Debugger started paused, type help for the list of commands");
+    }
+    let mut gdb_stub = args.gdb.as_deref().map(accept_gdb_client).transpose()?;
+
+    let mut trace_writer = args.trace.as_deref().map(File::create).transpose()?.map(BufWriter::new);
+    chip8.set_tracing(trace_writer.is_some());
+
+    let mut halted: Option<CpuError> = None;
+    let mut paused = false;
+    let mut advance_frame = false;
+    let mut slow_motion = SlowMotion::Off;
+    let mut title = String::new();
+    let mut state_slot: u8 = 1;
+    // Each iteration of the main loop is a frame, at the timer frequency
+    let frame_duration = Duration::from_secs(1) / chip8.get_timer().get_frequency();
+    let mut next_frame_time = Instant::now();
     'main_loop: loop {
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } | Event::KeyDown {keycode: Some(Keycode::Escape), ..} => break 'main_loop,
+                Event::KeyDown {keycode: Some(Keycode::Return), ..} if paused => {
+                    info!("Resuming execution");
+                    paused = false;
+                },
+                Event::KeyDown {keycode: Some(Keycode::P), repeat: false, ..} => {
+                    paused = !paused;
+                    info!("{} execution", if paused { "Pausing" } else { "Resuming" });
+                },
+                Event::KeyDown {keycode: Some(Keycode::N), ..} if paused => advance_frame = true,
+                Event::KeyDown {keycode: Some(keycode @ (Keycode::Minus | Keycode::Equals)), ..} if movie_mode => {
+                    warn!("The speed can not be changed while a movie is recorded or played, {:?} ignored", keycode);
+                },
+                Event::KeyDown {keycode: Some(keycode @ (Keycode::Minus | Keycode::Equals)), ..} => {
+                    let instructions_per_frame = step_speed(chip8.get_instructions_per_frame(), keycode == Keycode::Equals);
+                    chip8.set_instructions_per_frame(instructions_per_frame);
+                    info!("Running {} instructions per frame", instructions_per_frame);
+                },
+                Event::KeyDown {keycode: Some(Keycode::M), repeat: false, ..} => slow_motion = slow_motion.next(),
+                Event::KeyDown {keycode: Some(keycode @ (Keycode::LeftBracket | Keycode::RightBracket | Keycode::Comma
+                    | Keycode::Period | Keycode::Slash | Keycode::Num0)), ..} => {
+                    let synthesizer = audio_stream.get_synthesizer_mut();
+                    match keycode {
+                        Keycode::LeftBracket => synthesizer.set_volume(synthesizer.get_volume() - VOLUME_STEP),
+                        Keycode::RightBracket => synthesizer.set_volume(synthesizer.get_volume() + VOLUME_STEP),
+                        Keycode::Comma => synthesizer.set_tone(synthesizer.get_tone() / TONE_STEP),
+                        Keycode::Period => synthesizer.set_tone(synthesizer.get_tone() * TONE_STEP),
+                        Keycode::Slash => synthesizer.set_waveform(synthesizer.get_waveform().next()),
+                        _ => synthesizer.set_muted(!synthesizer.is_muted())
+                    }
+                    info!("{}", describe_voice(synthesizer));
+                },
+                Event::KeyDown {keycode: Some(keycode @ (Keycode::F1 | Keycode::F2 | Keycode::F3 | Keycode::F4)), ..} => {
+                    state_slot = match keycode {
+                        Keycode::F1 => 1,
+                        Keycode::F2 => 2,
+                        Keycode::F3 => 3,
+                        _ => 4
+                    };
+                    info!("Save state slot {} selected", state_slot);
+                },
+                Event::KeyDown {keycode: Some(Keycode::F5), repeat: false, ..} => {
+                    let state_path = state_slot_path(rom_path, state_slot);
+                    match save_state_to_file(&chip8, &state_path) {
+                        Ok(()) => info!("State saved to {}", state_path),
+                        Err(e) => error!("Could not save state to {}: {}", state_path, e)
+                    }
+                },
+                Event::KeyDown {keycode: Some(Keycode::F9), repeat: false, ..} if movie_mode => {
+                    warn!("Save states can not be loaded while a movie is recorded or played");
+                },
+                Event::KeyDown {keycode: Some(Keycode::F9), repeat: false, ..} => {
+                    let state_path = state_slot_path(rom_path, state_slot);
+                    match load_state_from_file(&mut chip8, &state_path) {
+                        Ok(()) => {
+                            info!("State loaded from {}", state_path);
+                            halted = None;
+                        },
+                        Err(e) => error!("Could not load state from {}: {}", state_path, e)
+                    }
+                },
                 _ => {}
             }
         }
@@ -127,32 +830,173 @@ fn main() -> Result<(), Error> {
         let current_input_state: [u8; 16] = CHIP8_KEYS
             .map(|scancode| keyboard_state.is_scancode_pressed(scancode) as u8);
 
-        cpu.update_input_state(current_input_state);
-        cpu.tick(&mut timer);
+        // Movies are frame based and can not go backward
+        let rewinding = !movie_mode && keyboard_state.is_scancode_pressed(rewind_scancode);
+        let fast_forward = keyboard_state.is_scancode_pressed(Scancode::Tab);
+        let frame_paused = paused && !advance_frame;
+        let mut frame_ran = false;
 
-        let current_time = Instant::now();
-        
-        if current_time - last_timer_time >= Duration::from_micros((1_000_000f32 / timer.get_frequency() as f32) as u64) {
-            if timer.get_sound_timer() > 0 {
-                audio_device.resume();
-            } else {
-                audio_device.pause();
+        if let (Some(debugger), Some(commands)) = (debugger.as_mut(), debugger_commands.as_ref()) {
+            while let Ok(line) = commands.try_recv() {
+                println!("{}", debugger.execute_command(&line, &mut chip8));
+            }
+        }
+        if let Some(stub) = gdb_stub.as_mut() {
+            let poll_result = stub.poll(&mut chip8);
+            if let Err(e) = &poll_result {
+                error!("GDB connection failed: {}", e);
+            }
+            if poll_result.is_err() || !stub.is_attached() {
+                info!("GDB client detached");
+                gdb_stub = None;
+            }
+        }
+        let debugger_paused = debugger.as_ref().is_some_and(Debugger::is_paused)
+            || gdb_stub.as_ref().is_some_and(GdbStub::is_paused);
+
+        if rewinding {
+            if rewind_buffer.rewind(&mut chip8) {
+                halted = None;
             }
-            timer.update();
+        } else if movie_mode {
+            if halted.is_none() && !frame_paused {
+                if let Some(movie) = playing.take_if(|movie| movie_frame >= movie.frames.len()) {
+                    check_movie_end(&movie, &chip8, movie_frame);
+                }
+                let input_state = playing.as_ref()
+                    .and_then(|movie| movie.input_state(movie_frame))
+                    .unwrap_or(current_input_state);
+                if let Some(movie) = recording.as_mut() {
+                    movie.record_frame(input_state);
+                }
+                chip8.update_input_state(input_state);
+                let frame_result = chip8.run_frame();
+                frame_ran = frame_result.is_ok();
+                movie_frame += 1;
 
-            last_timer_time = current_time;
+                // The recording and the playback stop at the first CPU error, then the error policy applies
+                if let Err(cpu_error) = frame_result {
+                    if let (Some(movie), Some(movie_path)) = (recording.as_mut(), &args.record) {
+                        save_movie(movie, &chip8, movie_path)?;
+                        recording = None;
+                    }
+                    if let Some(movie) = playing.take() {
+                        check_movie_end(&movie, &chip8, movie_frame);
+                    }
+                    match args.on_error {
+                        ErrorPolicy::Ignore if !cpu_error.is_fetch_error() => warn!("Skipping faulty instruction: {}", cpu_error),
+                        _ => {
+                            error!("CPU halted: {}", cpu_error);
+                            halted = Some(cpu_error);
+                        }
+                    }
+                }
+            }
+        } else {
+            chip8.update_input_state(current_input_state);
+            let mut frame_stopped = halted.is_some() || frame_paused || debugger_paused;
+            // The debugger and the GDB stub check their breakpoints before each instruction, so the frame is run here
+            // instruction by instruction instead of with Chip8::run_frame
+            for _ in 0..chip8.get_instructions_per_frame() {
+                if frame_stopped || chip8.has_exited() {
+                    break;
+                }
+                let tick_result = match (debugger.as_mut(), gdb_stub.as_mut()) {
+                    (Some(debugger), _) => debugger.tick(&mut chip8)
+                        .map(|stop_reason| stop_reason.into_iter().for_each(|reason| println!("{}", reason))),
+                    // CPU errors are reported to the GDB client, which decides what to do next
+                    (None, Some(stub)) => {
+                        match stub.tick(&mut chip8) {
+                            Ok(Some(cpu_error)) => warn!("CPU error reported to GDB: {}", cpu_error),
+                            Ok(None) => {},
+                            Err(e) => {
+                                error!("GDB connection failed: {}", e);
+                                gdb_stub = None;
+                            }
+                        }
+                        Ok(())
+                    },
+                    (None, None) => chip8.tick()
+                };
+                if let Err(cpu_error) = tick_result {
+                    match args.on_error {
+                        ErrorPolicy::Ignore if !cpu_error.is_fetch_error() => warn!("Skipping faulty instruction: {}", cpu_error),
+                        ErrorPolicy::Halt | ErrorPolicy::Ignore => {
+                            error!("CPU halted: {}", cpu_error);
+                            halted = Some(cpu_error);
+                        },
+                        ErrorPolicy::Break if debugger.is_some() => {
+                            println!("CPU break: {}\n{}", cpu_error, Debugger::format_registers(&chip8));
+                        },
+                        ErrorPolicy::Break => {
+                            error!("CPU break: {}", cpu_error);
+                            let cpu = chip8.get_cpu();
+                            error!("V: {:02X?}, I: 0x{:03X}, PC: 0x{:03X}", cpu.get_var_registers(), cpu.get_index_register(), cpu.get_pc());
+                            error!("Call stack: {:03X?}", cpu.get_stack().iter().rev().collect::<Vec<_>>());
+                            paused = true;
+                            advance_frame = false;
+                        }
+                    }
+                }
+                frame_stopped = halted.is_some() || (paused && !advance_frame)
+                    || debugger.as_ref().is_some_and(Debugger::is_paused)
+                    || gdb_stub.as_ref().is_some_and(GdbStub::is_paused);
+            }
+            // The timers only run during the frames which ran to the end
+            if !frame_stopped {
+                chip8.update_timers();
+                rewind_buffer.record_frame(&chip8);
+                frame_ran = true;
+            }
         }
 
-        if current_time - last_renderer_time >= Duration::from_micros((1_000_000f32 / renderer.get_frequency() as f32) as u64) {
-            renderer.clear();
-            renderer.render_bw_pixels(cpu.get_display())?;
-            renderer.update();
-            last_renderer_time = current_time;
+        if let Some(writer) = trace_writer.as_mut() {
+            writer.write_all(chip8.take_trace().as_bytes())?;
         }
 
-        while Instant::now() - last_cpu_time < Duration::from_micros((1_000_000f32 / cpu.get_cpu_frequency() as f32) as u64) {};
-        last_cpu_time = Instant::now();
+        if chip8.has_exited() {
+            info!("ROM exited the interpreter");
+            break 'main_loop;
+        }
+
+        // The frames which did not run, e.g. while paused or rewinding, are silent
+        if let Some(audio_queue) = &audio_queue {
+            let samples = audio_stream.render_frame(
+                frame_ran.then(|| chip8.get_buzzer_frame()),
+                chip8.get_audio_pattern(),
+                chip8.get_pitch()
+            );
+            if audio_queue.size() < max_queued_bytes {
+                audio_queue.queue_audio(&samples).map_err(Error::msg)?;
+            }
+        }
+
+        advance_frame = false;
+
+        let current_title = window_title(&chip8, slow_motion, fast_forward, paused, halted.as_ref());
+        if current_title != title {
+            renderer.set_title(&current_title)?;
+            title = current_title;
+        }
+        renderer.clear();
+        renderer.render_pixels(chip8.get_display())?;
+        renderer.update();
+
+        // Sleep until the deadline of the next frame, a host too slow to keep up drops the late frames instead of
+        // running them in a burst. Fast forward does not sleep at all
+        next_frame_time += frame_duration * slow_motion.frame_duration_factor();
+        let current_time = Instant::now();
+        match next_frame_time > current_time && !fast_forward {
+            true => thread::sleep(next_frame_time - current_time),
+            false => next_frame_time = current_time
+        }
     }
 
+    if let Some(writer) = trace_writer.as_mut() {
+        writer.flush()?;
+    }
+    if let (Some(movie), Some(movie_path)) = (recording.as_mut(), &args.record) {
+        save_movie(movie, &chip8, movie_path)?;
+    }
     Ok(())
 }
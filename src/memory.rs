@@ -1,9 +1,55 @@
+use std::fmt::Display;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/// Error raised when accessing an address outside of the memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBoundsError(pub usize);
+
+impl Display for OutOfBoundsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "address 0x{:X} is out of memory bounds", self.0)
+    }
+}
+
+impl std::error::Error for OutOfBoundsError {}
+
+/// Error raised when a ROM does not fit between its load address and the end of the memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomTooLargeError {
+    pub rom_size: usize,
+    pub load_address: usize,
+    pub memory_size: usize
+}
+
+impl Display for RomTooLargeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ROM of {} bytes does not fit in {} bytes of memory, at most {} bytes can be loaded at 0x{:X}",
+            self.rom_size, self.memory_size, self.memory_size.saturating_sub(self.load_address), self.load_address)
+    }
+}
+
+impl std::error::Error for RomTooLargeError {}
+
+#[derive(Clone)]
 pub struct Memory {
-    data: [u8; 4096]
+    data: Vec<u8>,
+    /// Addresses past the end of the memory wrap around to 0, instead of being out of bounds
+    wrap_addresses: bool
 }
 
 impl Memory {
+    /// Size of the address space of CHIP-8 and SUPER-CHIP
+    pub const DEFAULT_SIZE: usize = 0x1000;
+    /// Size of the address space of XO-CHIP, which is addressable with 0xF000 NNNN
+    pub const XO_CHIP_SIZE: usize = 0x10000;
+    /// Default load address of the ROMs, where the PC starts
     pub const ROM_INIT_ADDRESS: usize = 0x200;
+    /// Load address of the ROMs written for the ETI-660
+    pub const ETI_660_ROM_INIT_ADDRESS: usize = 0x600;
+    /// Address of the call stack in the COSMAC VIP memory map, with a big endian return address per level
+    pub const VIP_STACK_ADDRESS: usize = 0xEA0;
+    /// Address of the 256 bytes display buffer in the COSMAC VIP memory map
+    pub const VIP_DISPLAY_ADDRESS: usize = 0xF00;
     const FONT_INIT_ADDRESS: usize = 0x50;
     const FONT_WIDTH: u16 = 5;
     const FONT_DATA: [u8; 80] = [
@@ -24,35 +70,114 @@ impl Memory {
         0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
         0xF0, 0x80, 0xF0, 0x80, 0x80  // F
     ];
+    const BIG_FONT_INIT_ADDRESS: usize = 0xA0;
+    const BIG_FONT_WIDTH: u16 = 10;
+    const BIG_FONT_DATA: [u8; 160] = [
+        0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+        0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+        0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+        0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+        0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+        0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+        0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+        0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+        0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+        0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+        0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+        0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0  // F
+    ];
 
     pub fn new() -> Self {
-        Self { data: [0; 4096] }
+        Self::with_size(Memory::DEFAULT_SIZE)
+    }
+
+    /// Whether the memory can have this size, which is either [`Memory::DEFAULT_SIZE`] or [`Memory::XO_CHIP_SIZE`]
+    pub fn is_supported_size(size: usize) -> bool {
+        size == Memory::DEFAULT_SIZE || size == Memory::XO_CHIP_SIZE
+    }
+
+    /// Creates a memory of one of the supported sizes, panicking on any other size since the font would not fit and the
+    /// wrapping addresses could not be resolved
+    pub fn with_size(size: usize) -> Self {
+        assert!(Memory::is_supported_size(size), "unsupported memory size {}", size);
+        Self { data: vec![0; size], wrap_addresses: false }
+    }
+
+    /// Makes the addresses past the end of the memory wrap around to 0, as on the interpreters which only decode the
+    /// lower bits of an address, instead of raising an [`OutOfBoundsError`]
+    pub fn set_address_wrapping(&mut self, enabled: bool) {
+        self.wrap_addresses = enabled;
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
     }
 
     pub fn load_font_data(&mut self) {
         self.data[Memory::FONT_INIT_ADDRESS..(Memory::FONT_INIT_ADDRESS + Memory::FONT_DATA.len())].copy_from_slice(&Memory::FONT_DATA);
+        self.data[Memory::BIG_FONT_INIT_ADDRESS..(Memory::BIG_FONT_INIT_ADDRESS + Memory::BIG_FONT_DATA.len())].copy_from_slice(&Memory::BIG_FONT_DATA);
     }
 
-    pub fn load_rom_data(&mut self, rom_data: &[u8]) {
-        self.data[Memory::ROM_INIT_ADDRESS..(Memory::ROM_INIT_ADDRESS + rom_data.len())].copy_from_slice(rom_data);
+    /// Copies the ROM into the memory from the load address, which is usually [`Memory::ROM_INIT_ADDRESS`]
+    pub fn load_rom_data(&mut self, rom_data: &[u8], load_address: usize) -> Result<(), RomTooLargeError> {
+        let error = RomTooLargeError { rom_size: rom_data.len(), load_address, memory_size: self.data.len() };
+        let destination = load_address.checked_add(rom_data.len())
+            .and_then(|end| self.data.get_mut(load_address..end))
+            .ok_or(error)?;
+        destination.copy_from_slice(rom_data);
+        Ok(())
     }
 
-    pub fn read_instruction(&self, pc: usize) -> u16 {
-        (self.data[pc] as u16) << 8  | self.data[pc + 1] as u16
+    pub fn read_instruction(&self, pc: usize) -> Result<u16, OutOfBoundsError> {
+        Ok((self.read_byte(pc)? as u16) << 8  | self.read_byte(pc + 1)? as u16)
     }
 
-    pub fn read_byte(&self, address: usize) -> u8 {
-        self.data[address]
+    pub fn read_byte(&self, address: usize) -> Result<u8, OutOfBoundsError> {
+        Ok(self.data[self.resolve_address(address)?])
     }
     
-    pub fn write_byte(&mut self, address: usize, value: u8) {
+    pub fn write_byte(&mut self, address: usize, value: u8) -> Result<(), OutOfBoundsError> {
+        let address = self.resolve_address(address)?;
         self.data[address] = value;
+        Ok(())
+    }
+
+    /// Index of the byte at the address, depending on whether the addresses wrap around
+    fn resolve_address(&self, address: usize) -> Result<usize, OutOfBoundsError> {
+        match self.wrap_addresses {
+            true => Ok(address % self.data.len()),
+            false if address < self.data.len() => Ok(address),
+            false => Err(OutOfBoundsError(address))
+        }
     }
 
     pub fn get_font_address(&self, font_idx: u8) -> usize {
         Memory::FONT_INIT_ADDRESS + (Memory::FONT_WIDTH * font_idx as u16) as usize
     }
 
+    pub fn get_big_font_address(&self, font_idx: u8) -> usize {
+        Memory::BIG_FONT_INIT_ADDRESS + (Memory::BIG_FONT_WIDTH * font_idx as u16) as usize
+    }
+
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.data.len() as u32);
+        writer.write_bytes(&self.data);
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        // The memory size is a setting of the machine, which a save state can not change
+        let size = reader.read_u32()? as usize;
+        if size != self.data.len() {
+            return Err(SaveStateError::InvalidField("memory size"));
+        }
+        self.data = reader.read_bytes(size)?.to_vec();
+        Ok(())
+    }
+
 }
 
 impl Default for Memory {
@@ -63,7 +188,8 @@ impl Default for Memory {
 
 #[cfg(test)]
 mod test {
-    use super::Memory;
+    use crate::savestate::{SaveStateError, StateReader, StateWriter};
+    use super::{Memory, OutOfBoundsError, RomTooLargeError};
 
     #[test]
     fn memory_test() {
@@ -74,5 +200,66 @@ mod test {
         assert_eq!(memory.data[0x50], 0xF0);
         assert_eq!(memory.data[0x51], 0x90);
         assert_eq!(memory.data[0x9F], 0x80);
+        assert_eq!(memory.data[0xA0], 0xFF);
+        assert_eq!(memory.data[0x13F], 0xC0);
+        assert_eq!(memory.data[0x140], 0x00);
+    }
+
+    #[test]
+    fn out_of_bounds_test() {
+        let mut memory = Memory::new();
+
+        assert_eq!(memory.read_byte(0xFFF), Ok(0));
+        assert_eq!(memory.read_byte(0x1000), Err(OutOfBoundsError(0x1000)));
+        assert_eq!(memory.read_instruction(0xFFF), Err(OutOfBoundsError(0x1000)));
+        assert_eq!(memory.write_byte(0x1000, 0xFF), Err(OutOfBoundsError(0x1000)));
+
+        let mut memory = Memory::with_size(Memory::XO_CHIP_SIZE);
+        assert_eq!(memory.write_byte(0xFFFF, 0xFF), Ok(()));
+        assert_eq!(memory.read_byte(0xFFFF), Ok(0xFF));
+        assert_eq!(memory.read_byte(0x10000), Err(OutOfBoundsError(0x10000)));
+    }
+
+    #[test]
+    fn address_wrapping_test() {
+        let mut memory = Memory::new();
+        memory.set_address_wrapping(true);
+
+        assert_eq!(memory.write_byte(0x1000, 0x12), Ok(()));
+        assert_eq!(memory.write_byte(0x1FFF, 0x34), Ok(()));
+        assert_eq!(memory.read_byte(0x0), Ok(0x12));
+        assert_eq!(memory.read_instruction(0xFFF), Ok(0x3412));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn load_rom_data_test() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.load_rom_data(&[0xAB; 0xE00], Memory::ROM_INIT_ADDRESS), Ok(()));
+        assert_eq!(memory.read_byte(0xFFF), Ok(0xAB));
+        assert_eq!(memory.load_rom_data(&[0x12, 0x34], Memory::ETI_660_ROM_INIT_ADDRESS), Ok(()));
+        assert_eq!(memory.read_instruction(0x600), Ok(0x1234));
+
+        let error = RomTooLargeError { rom_size: 0xE01, load_address: 0x200, memory_size: 0x1000 };
+        assert_eq!(memory.load_rom_data(&[0; 0xE01], Memory::ROM_INIT_ADDRESS), Err(error));
+        assert_eq!(error.to_string(), "ROM of 3585 bytes does not fit in 4096 bytes of memory, at most 3584 bytes can be loaded at 0x200");
+        assert!(memory.load_rom_data(&[0; 2], 0x2000).is_err());
+    }
+
+    #[test]
+    fn read_state_test() {
+        let mut writer = StateWriter::new();
+        Memory::new().write_state(&mut writer);
+        let state = writer.into_bytes();
+
+        assert_eq!(Memory::new().read_state(&mut StateReader::new(&state)), Ok(()));
+        let mut memory = Memory::with_size(Memory::XO_CHIP_SIZE);
+        assert_eq!(memory.read_state(&mut StateReader::new(&state)), Err(SaveStateError::InvalidField("memory size")));
+        assert_eq!(memory.size(), Memory::XO_CHIP_SIZE);
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported memory size 0")]
+    fn with_size_test() {
+        Memory::with_size(0);
+    }
+}
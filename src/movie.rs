@@ -0,0 +1,311 @@
+use std::fmt::Display;
+use crate::{chip8::{BuildError, Chip8}, cpu::{error::CpuError, quirks::Quirks}, memory::Memory};
+use crate::savestate::{self, SaveStateError, StateReader, StateWriter};
+
+/**
+Recording of the keypad input of a run, which replays the exact same run of a ROM.
+
+A movie starts with the magic bytes, the format version (u16) and the hash of the ROM, followed by everything needed
+to rebuild the machine as it was at power on: the quirks, the memory size (u32), the seed of the random number
+generator (u64), the number of instructions per frame (u32) and the load address of the ROM (u32). Then come the number
+of frames (u32) and the keypad of each frame as a 16 bit mask where bit N is key N, and the file ends with the checksum
+of the machine state after the last frame. All numbers are little endian.
+
+As for save states, when the layout changes [`Movie::VERSION`] is bumped and a migration from the previous version is
+added, so movies of older format versions are migrated to the current layout when they are decoded.
+
+Each frame sets the keypad then runs the machine with [`Chip8::run_frame`], so a movie does not depend on the speed of
+the host. The playback stops at the first CPU error, where the recording stops too
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub quirks: Quirks,
+    pub memory_size: usize,
+    pub seed: u64,
+    pub instructions_per_frame: u32,
+    pub load_address: usize,
+    /// Keypad mask of each frame
+    pub frames: Vec<u16>,
+    /// Checksum of the machine after the last frame, see [`state_checksum`]
+    pub checksum: u64
+}
+
+/// Conversion of the data following the header into the layout of the next format version
+type Migration = fn(Vec<u8>) -> Result<Vec<u8>, MovieError>;
+
+/// Outcome of a playback without window
+pub struct Playback {
+    pub chip8: Chip8,
+    /// Number of frames played, which is less than the movie length if the CPU failed
+    pub frames: usize,
+    pub error: Option<CpuError>,
+    pub checksum: u64
+}
+
+impl Playback {
+    pub fn matches(&self, movie: &Movie) -> bool {
+        self.checksum == movie.checksum
+    }
+}
+
+impl Movie {
+    pub const MAGIC: [u8; 4] = *b"C8MV";
+    pub const VERSION: u16 = 1;
+    const HEADER_SIZE: usize = 4 + 2 + 8;
+    /// Migrations of the data following the header, where the element at index i converts version i + 1 into version
+    /// i + 2
+    const MIGRATIONS: [Migration; Movie::VERSION as usize - 1] = [];
+
+    /// Creates an empty movie of the ROM, for a machine built with these settings
+    pub fn new(rom: &[u8], quirks: Quirks, memory_size: usize, seed: u64, instructions_per_frame: u32, load_address: usize) -> Self {
+        Self {
+            rom_hash: savestate::rom_hash(rom),
+            quirks,
+            memory_size,
+            seed,
+            instructions_per_frame,
+            load_address,
+            frames: Vec::new(),
+            checksum: 0
+        }
+    }
+
+    /// Builds the machine the movie was recorded with, at power on
+    pub fn build_machine(&self, rom: &[u8]) -> Result<Chip8, MovieError> {
+        if savestate::rom_hash(rom) != self.rom_hash {
+            return Err(MovieError::RomMismatch);
+        }
+        Ok(Chip8::builder()
+            .rom(rom)
+            .memory_size(self.memory_size)
+            .load_address(self.load_address)
+            .quirks(self.quirks)
+            .seed(self.seed)
+            .instructions_per_frame(self.instructions_per_frame)
+            .build()?)
+    }
+
+    /// Appends the keypad of the next frame
+    pub fn record_frame(&mut self, input_state: [u8; 16]) {
+        self.frames.push(key_mask(input_state));
+    }
+
+    /// Keypad of the frame, None past the end of the movie
+    pub fn input_state(&self, frame: usize) -> Option<[u8; 16]> {
+        self.frames.get(frame).map(|&mask| input_state(mask))
+    }
+
+    /// Stores the checksum of the machine, it must be called after the last frame
+    pub fn finish(&mut self, chip8: &Chip8) {
+        self.checksum = state_checksum(chip8);
+    }
+
+    /// Plays the frame of the movie on the machine
+    pub fn play_frame(&self, chip8: &mut Chip8, frame: usize) -> Result<(), CpuError> {
+        if let Some(input_state) = self.input_state(frame) {
+            chip8.update_input_state(input_state);
+            chip8.run_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Plays the whole movie without window, as fast as possible
+    pub fn play(&self, rom: &[u8]) -> Result<Playback, MovieError> {
+        let mut chip8 = self.build_machine(rom)?;
+        let mut error = None;
+        let mut frames = 0;
+        while frames < self.frames.len() && error.is_none() {
+            error = self.play_frame(&mut chip8, frames).err();
+            frames += 1;
+        }
+        let checksum = state_checksum(&chip8);
+        Ok(Playback { chip8, frames, error, checksum })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(&Movie::MAGIC);
+        writer.write_u16(Movie::VERSION);
+        writer.write_u64(self.rom_hash);
+        self.quirks.write_state(&mut writer);
+        writer.write_u32(self.memory_size as u32);
+        writer.write_u64(self.seed);
+        writer.write_u32(self.instructions_per_frame);
+        writer.write_u32(self.load_address as u32);
+        writer.write_u32(self.frames.len() as u32);
+        self.frames.iter().for_each(|&mask| writer.write_u16(mask));
+        writer.write_u64(self.checksum);
+        writer.into_bytes()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, MovieError> {
+        if data.len() < Movie::HEADER_SIZE || data[..4] != Movie::MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+        let mut reader = StateReader::new(&data[4..Movie::HEADER_SIZE]);
+        let version = reader.read_u16()?;
+        let rom_hash = reader.read_u64()?;
+        if version == 0 || version > Movie::VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let data = Movie::MIGRATIONS[version as usize - 1..].iter()
+            .try_fold(data[Movie::HEADER_SIZE..].to_vec(), |data, migration| migration(data))?;
+
+        let mut reader = StateReader::new(&data);
+        let quirks = Quirks::read_state(&mut reader)?;
+        let memory_size = reader.read_u32()? as usize;
+        let seed = reader.read_u64()?;
+        let instructions_per_frame = reader.read_u32()?;
+        let load_address = reader.read_u32()? as usize;
+        // The machine is built from these fields, so they must be settings the builder accepts
+        if !Memory::is_supported_size(memory_size) {
+            return Err(MovieError::InvalidField("memory size"));
+        }
+        if instructions_per_frame == 0 {
+            return Err(MovieError::InvalidField("number of instructions per frame"));
+        }
+        if load_address >= memory_size {
+            return Err(MovieError::InvalidField("load address"));
+        }
+        let frame_count = reader.read_u32()?;
+        let frames = (0..frame_count).map(|_| reader.read_u16()).collect::<Result<_, _>>()?;
+        let checksum = reader.read_u64()?;
+        Ok(Self { rom_hash, quirks, memory_size, seed, instructions_per_frame, load_address, frames, checksum })
+    }
+}
+
+/// Checksum of the framebuffer and of the whole machine state, the FNV-1a hash of a save state
+pub fn state_checksum(chip8: &Chip8) -> u64 {
+    savestate::rom_hash(&chip8.save_state())
+}
+
+/// Packs the keypad into a mask where bit N is set if key N is pressed
+pub fn key_mask(input_state: [u8; 16]) -> u16 {
+    input_state.iter().enumerate().fold(0, |mask, (key, &pressed)| mask | ((pressed != 0) as u16) << key)
+}
+
+/// Unpacks a mask made by [`key_mask`]
+pub fn input_state(mask: u16) -> [u8; 16] {
+    std::array::from_fn(|key| (mask >> key & 1) as u8)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    /// The data is not a movie
+    InvalidMagic,
+    /// The movie was made by a newer or unknown version of the format
+    UnsupportedVersion(u16),
+    /// The movie was recorded with another ROM
+    RomMismatch,
+    /// The movie ends before all the fields are read
+    Truncated,
+    /// A field has a value which can not be loaded
+    InvalidField(&'static str),
+    /// The machine the movie was recorded with can not be built, e.g. the ROM does not fit in its memory
+    Build(BuildError)
+}
+
+impl From<BuildError> for MovieError {
+    fn from(error: BuildError) -> Self {
+        MovieError::Build(error)
+    }
+}
+
+impl From<SaveStateError> for MovieError {
+    fn from(error: SaveStateError) -> Self {
+        match error {
+            SaveStateError::InvalidMagic => MovieError::InvalidMagic,
+            SaveStateError::UnsupportedVersion(version) => MovieError::UnsupportedVersion(version),
+            SaveStateError::RomMismatch => MovieError::RomMismatch,
+            SaveStateError::Truncated => MovieError::Truncated,
+            SaveStateError::InvalidField(field) => MovieError::InvalidField(field)
+        }
+    }
+}
+
+impl Display for MovieError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MovieError::InvalidMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(f, "unsupported movie version {}", version),
+            MovieError::RomMismatch => write!(f, "movie was recorded with another ROM"),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::InvalidField(field) => write!(f, "movie has an invalid {}", field),
+            MovieError::Build(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+#[cfg(test)]
+mod test {
+    use crate::{chip8::{BuildError, Chip8Builder}, cpu::quirks::Quirks, memory::{Memory, RomTooLargeError}, savestate};
+    use super::{Movie, MovieError, input_state, key_mask};
+
+    // Draws the digit of the key pressed: V0 = key, I = font of V0, clear, draw at (0, 0), loop
+    const ROM: [u8; 12] = [0xF0, 0x0A, 0xF0, 0x29, 0x00, 0xE0, 0xD1, 0x15, 0x12, 0x00, 0x00, 0x00];
+
+    fn record(keys: &[u16]) -> (Movie, u64) {
+        let mut movie = Movie::new(&ROM, Quirks::COSMAC_VIP, Memory::DEFAULT_SIZE, 42, Chip8Builder::DEFAULT_INSTRUCTIONS_PER_FRAME, Memory::ROM_INIT_ADDRESS);
+        let mut chip8 = movie.build_machine(&ROM).unwrap();
+        for &mask in keys {
+            movie.record_frame(input_state(mask));
+            movie.play_frame(&mut chip8, movie.frames.len() - 1).unwrap();
+        }
+        movie.finish(&chip8);
+        (movie, chip8.get_cpu().get_var_registers()[0] as u64)
+    }
+
+    #[test]
+    fn key_mask_test() {
+        let mut keys = [0; 16];
+        keys[0] = 1;
+        keys[0xF] = 1;
+        assert_eq!(key_mask(keys), 0x8001);
+        assert_eq!(input_state(0x8001), keys);
+    }
+
+    #[test]
+    fn playback_test() {
+        let (movie, key) = record(&[0, 0, 1 << 7, 0, 0]);
+        assert_eq!(key, 7);
+
+        let playback = movie.play(&ROM).unwrap();
+        assert_eq!(playback.frames, 5);
+        assert!(playback.error.is_none());
+        assert!(playback.matches(&movie));
+
+        let (other_movie, _) = record(&[0, 0, 1 << 3, 0, 0]);
+        assert_ne!(other_movie.checksum, movie.checksum);
+    }
+
+    #[test]
+    fn encode_test() {
+        let (movie, _) = record(&[0, 1 << 4, 0]);
+        let data = movie.encode();
+        assert_eq!(Movie::decode(&data), Ok(movie.clone()));
+        assert_eq!(Movie::decode(&data[..data.len() - 1]), Err(MovieError::Truncated));
+        assert_eq!(Movie::decode(b"C8ST"), Err(MovieError::InvalidMagic));
+        assert_eq!(movie.play(&[0x00, 0xE0]).err(), Some(MovieError::RomMismatch));
+
+        let rom = [0; 0xE01];
+        let error = RomTooLargeError { rom_size: rom.len(), load_address: 0x200, memory_size: 0x1000 };
+        let large_movie = Movie { rom_hash: savestate::rom_hash(&rom), ..movie };
+        assert_eq!(large_movie.play(&rom).err(), Some(MovieError::Build(BuildError::RomTooLarge(error))));
+    }
+
+    #[test]
+    fn invalid_field_test() {
+        let (movie, _) = record(&[0, 1 << 4, 0]);
+        let decode = |movie: Movie| Movie::decode(&movie.encode());
+
+        assert_eq!(decode(Movie { memory_size: 0x800, ..movie.clone() }), Err(MovieError::InvalidField("memory size")));
+        assert_eq!(decode(Movie { instructions_per_frame: 0, ..movie.clone() }),
+            Err(MovieError::InvalidField("number of instructions per frame")));
+        assert_eq!(decode(Movie { load_address: 0x1000, ..movie.clone() }), Err(MovieError::InvalidField("load address")));
+        assert_eq!(decode(Movie { quirks: Quirks { stack_depth: 0, ..movie.quirks }, ..movie }),
+            Err(MovieError::InvalidField("stack depth quirk")));
+    }
+}
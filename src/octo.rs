@@ -0,0 +1,1053 @@
+use std::{collections::HashMap, fmt::Display};
+use crate::memory::Memory;
+
+/// Error raised by the Octo compiler, with the location of the token which caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OctoError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String
+}
+
+impl Display for OctoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for OctoError {}
+
+/**
+Compiles Octo source into a ROM loaded at [`Memory::ROM_INIT_ADDRESS`], see [`compile_at`] for other load addresses.
+
+It supports the statements and the directives of the Octo language, including `: label`, `:const`, `:alias`,
+`:unpack`, `:next`, `:org`, `:byte`, `:pointer`, `:call`, `:macro`, `:calc`, `:assert`, `loop`/`while`/`again` and
+`if ... then` or `if ... begin ... else ... end`. The comparisons `<`, `>`, `<=` and `>=` use VF as a temporary
+register. Like Octo, the program starts with a jump to `main` unless `main` is defined before any byte is emitted,
+and `:calc` expressions are evaluated from right to left without operator precedence
+ */
+pub fn compile(source: &str) -> Result<Vec<u8>, OctoError> {
+    compile_at(source, Memory::ROM_INIT_ADDRESS)
+}
+
+/// Compiles Octo source into a ROM loaded at the given address, which is where its labels and `:org` start
+pub fn compile_at(source: &str, load_address: usize) -> Result<Vec<u8>, OctoError> {
+    let mut compiler = Compiler::new(tokenize(source)?, load_address);
+    compiler.compile()?;
+    compiler.finish()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    is_string: bool
+}
+
+impl Token {
+    fn error(&self, message: impl Into<String>) -> OctoError {
+        OctoError { line: self.line, column: self.column, message: message.into() }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, OctoError> {
+    let mut tokens = Vec::new();
+    for (line_idx, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let start = i;
+            let token = |text: String, is_string: bool| Token { text, line: line_idx + 1, column: start + 1, is_string };
+            match chars[i] {
+                '#' => break,
+                c if c.is_whitespace() => i += 1,
+                '"' => {
+                    let end = chars[i + 1..].iter().position(|&c| c == '"')
+                        .ok_or_else(|| token(String::new(), true).error("unterminated string"))?;
+                    tokens.push(token(chars[i + 1..i + 1 + end].iter().collect(), true));
+                    i += end + 2;
+                },
+                // Brackets are tokens on their own, even when they are not separated by spaces
+                '{' | '}' | '(' | ')' => {
+                    tokens.push(token(chars[i].to_string(), false));
+                    i += 1;
+                },
+                _ => {
+                    while i < chars.len() && !chars[i].is_whitespace() && !"{}()".contains(chars[i]) { i += 1; }
+                    tokens.push(token(chars[start..i].iter().collect(), false));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text)
+    };
+    let value = match digits.get(..2) {
+        Some("0x" | "0X") => i64::from_str_radix(&digits[2..], 16).ok()?,
+        Some("0b" | "0B") => i64::from_str_radix(&digits[2..], 2).ok()?,
+        _ if digits.chars().all(|c| c.is_ascii_digit()) && !digits.is_empty() => digits.parse().ok()?,
+        _ => return None
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Value of an operand, where labels defined later are resolved when the compilation ends
+enum Value {
+    Known(i64),
+    Forward(String)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FixupKind {
+    /// 12 bit address of a 0xNNNN instruction
+    Address,
+    /// 16 bit address following the long index instruction, or of a `:pointer`
+    Long,
+    /// NN byte of a 0x6XNN instruction, set to the nibble followed by the high bits of the address, or to the whole
+    /// high byte of a 16 bit address for `:unpack long`
+    UnpackHigh(Option<u8>),
+    /// NN byte of a 0x6XNN instruction, set to the low byte of the address
+    UnpackLow
+}
+
+struct Fixup {
+    address: usize,
+    kind: FixupKind,
+    name: String,
+    token: Token
+}
+
+/// Right side of a comparison
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Value(u8)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    Eq(u8, Operand),
+    Ne(u8, Operand),
+    Lt(u8, Operand),
+    Gt(u8, Operand),
+    Le(u8, Operand),
+    Ge(u8, Operand),
+    Key(u8),
+    NotKey(u8)
+}
+
+impl Condition {
+    fn negate(self) -> Self {
+        match self {
+            Condition::Eq(x, operand) => Condition::Ne(x, operand),
+            Condition::Ne(x, operand) => Condition::Eq(x, operand),
+            Condition::Lt(x, operand) => Condition::Ge(x, operand),
+            Condition::Ge(x, operand) => Condition::Lt(x, operand),
+            Condition::Gt(x, operand) => Condition::Le(x, operand),
+            Condition::Le(x, operand) => Condition::Gt(x, operand),
+            Condition::Key(x) => Condition::NotKey(x),
+            Condition::NotKey(x) => Condition::Key(x)
+        }
+    }
+}
+
+enum Block {
+    /// `if ... begin`, with the address of the jump to the else branch
+    If(usize, Token),
+    /// `else`, with the address of the jump to the end
+    Else(usize, Token),
+    /// `loop`, with the address of the loop start and the addresses of the jumps out of the loop of each `while`
+    Loop(usize, Vec<usize>, Token)
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>
+}
+
+struct Compiler {
+    tokens: Vec<Token>,
+    position: usize,
+    rom: Vec<u8>,
+    /// Address of the first byte of the ROM
+    load_address: usize,
+    address: usize,
+    started: bool,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    /// Number of tokens produced by macro expansions, to stop recursive macros
+    expanded_tokens: usize
+}
+
+impl Compiler {
+    const MAX_EXPANDED_TOKENS: usize = 1_000_000;
+
+    fn new(tokens: Vec<Token>, load_address: usize) -> Self {
+        Self {
+            tokens,
+            position: 0,
+            rom: Vec::new(),
+            load_address,
+            address: load_address,
+            started: false,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            expanded_tokens: 0
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Next token, which must exist since the previous token expects it
+    fn expect(&mut self, previous: &Token) -> Result<Token, OctoError> {
+        self.next().ok_or_else(|| previous.error(format!("missing operand after {}", previous.text)))
+    }
+
+    fn expect_text(&mut self, previous: &Token, text: &str) -> Result<Token, OctoError> {
+        let token = self.expect(previous)?;
+        if token.text != text {
+            return Err(token.error(format!("expected {} but found {}", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn peek_text(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|token| token.text.as_str())
+    }
+
+    fn write(&mut self, address: usize, bytes: &[u8]) {
+        let offset = address - self.load_address;
+        if self.rom.len() < offset + bytes.len() {
+            self.rom.resize(offset + bytes.len(), 0);
+        }
+        self.rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Reserves the jump to main the first time a byte is emitted, unless main is already defined
+    fn start(&mut self, token: &Token) {
+        if self.started { return; }
+        self.started = true;
+        if !self.labels.contains_key("main") {
+            self.fixups.push(Fixup { address: self.load_address, kind: FixupKind::Address, name: "main".to_string(), token: token.clone() });
+            self.write(self.load_address, &[0x10, 0x00]);
+            if self.address == self.load_address {
+                self.address += 2;
+            }
+        }
+    }
+
+    fn emit(&mut self, token: &Token, bytes: &[u8]) -> Result<(), OctoError> {
+        self.start(token);
+        if self.address + bytes.len() > Memory::XO_CHIP_SIZE {
+            return Err(token.error("the program does not fit in memory"));
+        }
+        self.write(self.address, bytes);
+        self.address += bytes.len();
+        Ok(())
+    }
+
+    fn emit_instruction(&mut self, token: &Token, instruction: u16) -> Result<(), OctoError> {
+        self.emit(token, &instruction.to_be_bytes())
+    }
+
+    /// Emits an instruction with an address operand, which is patched later if the label is not defined yet
+    fn emit_address_instruction(&mut self, token: &Token, base: u16, value: Value) -> Result<(), OctoError> {
+        match value {
+            Value::Known(address) if !(0..=0xFFF).contains(&address) => Err(token.error(format!("address 0x{:X} does not fit in 12 bits", address))),
+            Value::Known(address) => self.emit_instruction(token, base | address as u16),
+            Value::Forward(name) => {
+                self.emit_instruction(token, base)?;
+                self.fixups.push(Fixup { address: self.address - 2, kind: FixupKind::Address, name, token: token.clone() });
+                Ok(())
+            }
+        }
+    }
+
+    fn define_label(&mut self, token: &Token, name: &str, address: usize) -> Result<(), OctoError> {
+        self.check_name(token, name)?;
+        self.labels.insert(name.to_string(), address);
+        Ok(())
+    }
+
+    fn check_name(&self, token: &Token, name: &str) -> Result<(), OctoError> {
+        if self.labels.contains_key(name) || self.constants.contains_key(name) || self.aliases.contains_key(name) {
+            return Err(token.error(format!("the name {} is already defined", name)));
+        }
+        if register(name).is_some() || parse_number(name).is_some() {
+            return Err(token.error(format!("{} can not be used as a name", name)));
+        }
+        Ok(())
+    }
+
+    fn register(&self, token: &Token) -> Result<u8, OctoError> {
+        register(&token.text).or_else(|| self.aliases.get(&token.text).copied())
+            .ok_or_else(|| token.error(format!("expected a register but found {}", token.text)))
+    }
+
+    fn value(&mut self, token: &Token, allow_forward: bool) -> Result<Value, OctoError> {
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(Value::Known(value));
+        }
+        if let Some(value) = self.constants.get(&token.text) {
+            return Ok(Value::Known(value.floor() as i64));
+        }
+        if let Some(&address) = self.labels.get(&token.text) {
+            return Ok(Value::Known(address as i64));
+        }
+        if token.text == "{" {
+            let value = self.calc_block(token)?;
+            return Ok(Value::Known(value.floor() as i64));
+        }
+        if allow_forward && !token.is_string && register(&token.text).is_none() {
+            return Ok(Value::Forward(token.text.clone()));
+        }
+        Err(token.error(format!("undefined name {}", token.text)))
+    }
+
+    /// Value which must be known now, between min and max
+    fn known_value(&mut self, token: &Token, min: i64, max: i64) -> Result<i64, OctoError> {
+        match self.value(token, false)? {
+            Value::Known(value) if (min..=max).contains(&value) => Ok(value),
+            Value::Known(value) => Err(token.error(format!("value {} is out of range", value))),
+            Value::Forward(name) => Err(token.error(format!("undefined name {}", name)))
+        }
+    }
+
+    fn byte_value(&mut self, token: &Token) -> Result<u8, OctoError> {
+        self.known_value(token, -128, 255).map(|value| value as u8)
+    }
+
+    fn compile(&mut self) -> Result<(), OctoError> {
+        while let Some(token) = self.next() {
+            self.statement(token)?;
+        }
+        match self.blocks.last() {
+            Some(Block::If(_, token) | Block::Else(_, token) | Block::Loop(_, _, token)) => {
+                Err(token.error(format!("{} is never closed", token.text)))
+            },
+            None => Ok(())
+        }
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), OctoError> {
+        if token.is_string {
+            return Err(token.error("unexpected string"));
+        }
+        if let Ok(x) = self.register(&token) {
+            return self.register_statement(&token, x);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.expect(&token)?;
+                // Only main can be defined where the jump to main would be
+                if name.text != "main" {
+                    self.start(&name);
+                }
+                self.define_label(&name, &name.text, self.address)
+            },
+            ":const" => {
+                let name = self.expect(&token)?;
+                let value_token = self.expect(&name)?;
+                let value = self.known_value(&value_token, i64::MIN, i64::MAX)?;
+                self.check_name(&name, &name.text)?;
+                self.constants.insert(name.text, value as f64);
+                Ok(())
+            },
+            ":alias" => {
+                let name = self.expect(&token)?;
+                let register_token = self.expect(&name)?;
+                let x = match register_token.text.as_str() {
+                    "{" => self.calc_block(&register_token)? as i64,
+                    _ => self.register(&register_token)? as i64
+                };
+                if !(0..16).contains(&x) {
+                    return Err(register_token.error("an alias must name a register between v0 and vf"));
+                }
+                self.check_name(&name, &name.text)?;
+                self.aliases.insert(name.text, x as u8);
+                Ok(())
+            },
+            ":unpack" => {
+                let nibble_token = self.expect(&token)?;
+                let name = self.expect(&nibble_token)?;
+                let nibble = match nibble_token.text.as_str() {
+                    "long" => None,
+                    _ => Some(self.known_value(&nibble_token, 0, 15)? as u8)
+                };
+                let high_kind = FixupKind::UnpackHigh(nibble);
+                let bits = if nibble.is_some() { 12 } else { 16 };
+                match self.value(&name, true)? {
+                    Value::Known(address) if !(0..1 << bits).contains(&address) => {
+                        Err(name.error(format!("address 0x{:X} does not fit in {} bits", address, bits)))
+                    },
+                    Value::Known(address) => {
+                        let high = (nibble.unwrap_or(0) << 4) | (address >> 8) as u8;
+                        self.emit_instruction(&token, 0x6000 | high as u16)?;
+                        self.emit_instruction(&token, 0x6100 | (address & 0xFF) as u16)
+                    },
+                    Value::Forward(label) => {
+                        self.emit_instruction(&token, 0x6000)?;
+                        self.fixups.push(Fixup { address: self.address - 2, kind: high_kind, name: label.clone(), token: name.clone() });
+                        self.emit_instruction(&token, 0x6100)?;
+                        self.fixups.push(Fixup { address: self.address - 2, kind: FixupKind::UnpackLow, name: label, token: name });
+                        Ok(())
+                    }
+                }
+            },
+            ":next" => {
+                let name = self.expect(&token)?;
+                self.define_label(&name, &name.text, self.address + 1)
+            },
+            ":org" => {
+                let address_token = self.expect(&token)?;
+                self.address = self.known_value(&address_token, self.load_address as i64, Memory::XO_CHIP_SIZE as i64 - 1)? as usize;
+                Ok(())
+            },
+            ":byte" => {
+                let value_token = self.expect(&token)?;
+                let value = self.byte_value(&value_token)?;
+                self.emit(&token, &[value])
+            },
+            ":pointer" => {
+                let value_token = self.expect(&token)?;
+                match self.value(&value_token, true)? {
+                    Value::Known(address) if !(0..=0xFFFF).contains(&address) => {
+                        Err(value_token.error(format!("address 0x{:X} does not fit in 16 bits", address)))
+                    },
+                    Value::Known(address) => self.emit(&token, &(address as u16).to_be_bytes()),
+                    Value::Forward(name) => {
+                        self.emit(&token, &[0, 0])?;
+                        self.fixups.push(Fixup { address: self.address - 2, kind: FixupKind::Long, name, token: value_token });
+                        Ok(())
+                    }
+                }
+            },
+            ":call" => {
+                let value_token = self.expect(&token)?;
+                let value = self.value(&value_token, true)?;
+                self.emit_address_instruction(&value_token, 0x2000, value)
+            },
+            ":macro" => self.define_macro(&token),
+            ":calc" => {
+                let name = self.expect(&token)?;
+                let brace = self.expect_text(&name, "{")?;
+                let value = self.calc_block(&brace)?;
+                self.check_name(&name, &name.text)?;
+                self.constants.insert(name.text, value);
+                Ok(())
+            },
+            ":assert" => {
+                let mut brace = self.expect(&token)?;
+                let message = match brace.is_string {
+                    true => {
+                        let message = brace.text.clone();
+                        brace = self.expect_text(&brace, "{")?;
+                        message
+                    },
+                    false => "assertion failed".to_string()
+                };
+                if brace.text != "{" {
+                    return Err(brace.error("expected {"));
+                }
+                match self.calc_block(&brace)? == 0.0 {
+                    true => Err(token.error(message)),
+                    false => Ok(())
+                }
+            },
+            ":breakpoint" => self.expect(&token).map(|_| ()),
+            ":monitor" => {
+                let first = self.expect(&token)?;
+                self.expect(&first).map(|_| ())
+            },
+            directive if directive.starts_with(':') => Err(token.error(format!("unsupported directive {}", directive))),
+            ";" | "return" => self.emit_instruction(&token, 0x00EE),
+            "clear" => self.emit_instruction(&token, 0x00E0),
+            "hires" => self.emit_instruction(&token, 0x00FF),
+            "lores" => self.emit_instruction(&token, 0x00FE),
+            "exit" => self.emit_instruction(&token, 0x00FD),
+            "scroll-right" => self.emit_instruction(&token, 0x00FB),
+            "scroll-left" => self.emit_instruction(&token, 0x00FC),
+            "audio" => self.emit_instruction(&token, 0xF002),
+            "scroll-down" | "scroll-up" | "plane" => {
+                let n_token = self.expect(&token)?;
+                let n = self.known_value(&n_token, 0, 15)? as u16;
+                let instruction = match token.text.as_str() {
+                    "scroll-down" => 0x00C0 | n,
+                    "scroll-up" => 0x00D0 | n,
+                    _ => 0xF001 | n << 8
+                };
+                self.emit_instruction(&token, instruction)
+            },
+            "bcd" | "saveflags" | "loadflags" => {
+                let x_token = self.expect(&token)?;
+                let x = self.register(&x_token)? as u16;
+                let low_byte = match token.text.as_str() {
+                    "bcd" => 0x33,
+                    "saveflags" => 0x75,
+                    _ => 0x85
+                };
+                self.emit_instruction(&token, 0xF000 | x << 8 | low_byte)
+            },
+            "save" | "load" => {
+                let x_token = self.expect(&token)?;
+                let x = self.register(&x_token)? as u16;
+                let is_save = token.text == "save";
+                if self.peek_text() == Some("-") {
+                    let dash = self.expect(&x_token)?;
+                    let y_token = self.expect(&dash)?;
+                    let y = self.register(&y_token)? as u16;
+                    self.emit_instruction(&token, 0x5000 | x << 8 | y << 4 | if is_save { 0x2 } else { 0x3 })
+                } else {
+                    self.emit_instruction(&token, 0xF000 | x << 8 | if is_save { 0x55 } else { 0x65 })
+                }
+            },
+            "sprite" => {
+                let x_token = self.expect(&token)?;
+                let x = self.register(&x_token)? as u16;
+                let y_token = self.expect(&x_token)?;
+                let y = self.register(&y_token)? as u16;
+                let n_token = self.expect(&y_token)?;
+                let n = self.known_value(&n_token, 0, 15)? as u16;
+                self.emit_instruction(&token, 0xD000 | x << 8 | y << 4 | n)
+            },
+            "jump" | "jump0" | "native" => {
+                let target = self.expect(&token)?;
+                let value = self.value(&target, true)?;
+                let base = match token.text.as_str() {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000
+                };
+                self.emit_address_instruction(&target, base, value)
+            },
+            "delay" | "buzzer" | "pitch" => {
+                let operator = self.expect_text(&token, ":=")?;
+                let x_token = self.expect(&operator)?;
+                let x = self.register(&x_token)? as u16;
+                let low_byte = match token.text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A
+                };
+                self.emit_instruction(&token, 0xF000 | x << 8 | low_byte)
+            },
+            "i" => self.index_statement(&token),
+            "if" => self.if_statement(&token),
+            "else" => match self.blocks.pop() {
+                Some(Block::If(jump_address, _)) => {
+                    self.emit_instruction(&token, 0x1000)?;
+                    let end_jump_address = self.address - 2;
+                    self.patch_jump(jump_address, self.address);
+                    self.blocks.push(Block::Else(end_jump_address, token));
+                    Ok(())
+                },
+                _ => Err(token.error("else without if ... begin"))
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(jump_address, _) | Block::Else(jump_address, _)) => {
+                    self.patch_jump(jump_address, self.address);
+                    Ok(())
+                },
+                _ => Err(token.error("end without if ... begin"))
+            },
+            "loop" => {
+                self.start(&token);
+                self.blocks.push(Block::Loop(self.address, Vec::new(), token));
+                Ok(())
+            },
+            "while" => {
+                let condition = self.condition(&token)?;
+                if !matches!(self.blocks.last(), Some(Block::Loop(..))) {
+                    return Err(token.error("while outside of loop ... again"));
+                }
+                self.emit_skip_unless(&token, condition.negate())?;
+                self.emit_instruction(&token, 0x1000)?;
+                if let Some(Block::Loop(_, exit_jumps, _)) = self.blocks.last_mut() {
+                    exit_jumps.push(self.address - 2);
+                }
+                Ok(())
+            },
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop(start_address, exit_jumps, _)) => {
+                    self.emit_address_instruction(&token, 0x1000, Value::Known(start_address as i64))?;
+                    exit_jumps.into_iter().for_each(|jump_address| self.patch_jump(jump_address, self.address));
+                    Ok(())
+                },
+                _ => Err(token.error("again without loop"))
+            },
+            name if self.macros.contains_key(name) => self.expand_macro(&token),
+            _ => match self.value(&token, true)? {
+                // Numbers and constants are emitted as bytes, while labels are called
+                Value::Known(value) if parse_number(&token.text).is_some() || self.constants.contains_key(&token.text) => {
+                    if !(-128..=255).contains(&value) {
+                        return Err(token.error(format!("value {} does not fit in a byte", value)));
+                    }
+                    self.emit(&token, &[value as u8])
+                },
+                value => self.emit_address_instruction(&token, 0x2000, value)
+            }
+        }
+    }
+
+    fn register_statement(&mut self, token: &Token, x: u8) -> Result<(), OctoError> {
+        let x16 = x as u16;
+        let operator = self.expect(token)?;
+        let operand = self.expect(&operator)?;
+        let operand_register = self.register(&operand).ok();
+
+        let instruction = match (operator.text.as_str(), operand_register) {
+            (":=", Some(y)) => 0x8000 | x16 << 8 | (y as u16) << 4,
+            (":=", None) => match operand.text.as_str() {
+                "delay" => 0xF007 | x16 << 8,
+                "key" => 0xF00A | x16 << 8,
+                "random" => {
+                    let mask_token = self.expect(&operand)?;
+                    0xC000 | x16 << 8 | self.byte_value(&mask_token)? as u16
+                },
+                _ => 0x6000 | x16 << 8 | self.byte_value(&operand)? as u16
+            },
+            ("+=", Some(y)) => 0x8004 | x16 << 8 | (y as u16) << 4,
+            ("+=", None) => 0x7000 | x16 << 8 | self.byte_value(&operand)? as u16,
+            ("-=", Some(y)) => 0x8005 | x16 << 8 | (y as u16) << 4,
+            ("-=", None) => 0x7000 | x16 << 8 | (self.byte_value(&operand)? as u16).wrapping_neg() & 0xFF,
+            ("=-", Some(y)) => 0x8007 | x16 << 8 | (y as u16) << 4,
+            ("|=", Some(y)) => 0x8001 | x16 << 8 | (y as u16) << 4,
+            ("&=", Some(y)) => 0x8002 | x16 << 8 | (y as u16) << 4,
+            ("^=", Some(y)) => 0x8003 | x16 << 8 | (y as u16) << 4,
+            (">>=", Some(y)) => 0x8006 | x16 << 8 | (y as u16) << 4,
+            ("<<=", Some(y)) => 0x800E | x16 << 8 | (y as u16) << 4,
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", None) => return Err(operand.error(format!("expected a register but found {}", operand.text))),
+            _ => return Err(operator.error(format!("unknown operator {}", operator.text)))
+        };
+        self.emit_instruction(token, instruction)
+    }
+
+    fn index_statement(&mut self, token: &Token) -> Result<(), OctoError> {
+        let operator = self.expect(token)?;
+        let operand = self.expect(&operator)?;
+        match (operator.text.as_str(), operand.text.as_str()) {
+            (":=", "hex" | "bighex") => {
+                let x_token = self.expect(&operand)?;
+                let x = self.register(&x_token)? as u16;
+                self.emit_instruction(token, if operand.text == "hex" { 0xF029 } else { 0xF030 } | x << 8)
+            },
+            (":=", "long") => {
+                let target = self.expect(&operand)?;
+                self.emit_instruction(token, 0xF000)?;
+                match self.value(&target, true)? {
+                    Value::Known(address) if (0..=0xFFFF).contains(&address) => self.emit_instruction(token, address as u16),
+                    Value::Known(address) => Err(target.error(format!("address 0x{:X} does not fit in 16 bits", address))),
+                    Value::Forward(name) => {
+                        self.emit_instruction(token, 0x0000)?;
+                        self.fixups.push(Fixup { address: self.address - 2, kind: FixupKind::Long, name, token: target });
+                        Ok(())
+                    }
+                }
+            },
+            (":=", _) => {
+                let value = self.value(&operand, true)?;
+                self.emit_address_instruction(&operand, 0xA000, value)
+            },
+            ("+=", _) => {
+                let x = self.register(&operand)? as u16;
+                self.emit_instruction(token, 0xF01E | x << 8)
+            },
+            _ => Err(operator.error(format!("unknown operator {}", operator.text)))
+        }
+    }
+
+    fn condition(&mut self, token: &Token) -> Result<Condition, OctoError> {
+        let x_token = self.expect(token)?;
+        let x = self.register(&x_token)?;
+        let operator = self.expect(&x_token)?;
+        match operator.text.as_str() {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            _ => {}
+        }
+        let operand_token = self.expect(&operator)?;
+        let operand = match self.register(&operand_token) {
+            Ok(y) => Operand::Register(y),
+            Err(_) => Operand::Value(self.byte_value(&operand_token)?)
+        };
+        match operator.text.as_str() {
+            "==" => Ok(Condition::Eq(x, operand)),
+            "!=" => Ok(Condition::Ne(x, operand)),
+            "<" => Ok(Condition::Lt(x, operand)),
+            ">" => Ok(Condition::Gt(x, operand)),
+            "<=" => Ok(Condition::Le(x, operand)),
+            ">=" => Ok(Condition::Ge(x, operand)),
+            _ => Err(operator.error(format!("unknown comparison {}", operator.text)))
+        }
+    }
+
+    /// Emits the instructions which skip the next one when the condition is false
+    fn emit_skip_unless(&mut self, token: &Token, condition: Condition) -> Result<(), OctoError> {
+        let instruction = |base: u16, x: u8, operand: u16| base | (x as u16) << 8 | operand;
+        match condition {
+            Condition::Eq(x, Operand::Value(value)) => self.emit_instruction(token, instruction(0x4000, x, value as u16)),
+            Condition::Eq(x, Operand::Register(y)) => self.emit_instruction(token, instruction(0x9000, x, (y as u16) << 4)),
+            Condition::Ne(x, Operand::Value(value)) => self.emit_instruction(token, instruction(0x3000, x, value as u16)),
+            Condition::Ne(x, Operand::Register(y)) => self.emit_instruction(token, instruction(0x5000, x, (y as u16) << 4)),
+            Condition::Key(x) => self.emit_instruction(token, instruction(0xE0A1, x, 0)),
+            Condition::NotKey(x) => self.emit_instruction(token, instruction(0xE09E, x, 0)),
+            // x > y is y < x, while x > n is x >= n + 1
+            Condition::Gt(x, Operand::Register(y)) => self.emit_comparison(token, y, Operand::Register(x), false),
+            Condition::Le(x, Operand::Register(y)) => self.emit_comparison(token, y, Operand::Register(x), true),
+            Condition::Gt(x, Operand::Value(value)) | Condition::Le(x, Operand::Value(value)) => {
+                let next_value = value.checked_add(1).ok_or_else(|| token.error("comparison with 255 is always true or false"))?;
+                let greater_or_equal = matches!(condition, Condition::Gt(..));
+                self.emit_comparison(token, x, Operand::Value(next_value), greater_or_equal)
+            },
+            Condition::Lt(x, operand) => self.emit_comparison(token, x, operand, false),
+            Condition::Ge(x, operand) => self.emit_comparison(token, x, operand, true)
+        }
+    }
+
+    /// Sets VF to 1 if x >= operand with a subtraction, then skips the next instruction unless the comparison holds
+    fn emit_comparison(&mut self, token: &Token, x: u8, operand: Operand, greater_or_equal: bool) -> Result<(), OctoError> {
+        match operand {
+            Operand::Register(y) => self.emit_instruction(token, 0x8F00 | (y as u16) << 4)?,
+            Operand::Value(value) => self.emit_instruction(token, 0x6F00 | value as u16)?
+        }
+        self.emit_instruction(token, 0x8F07 | (x as u16) << 4)?;
+        self.emit_instruction(token, if greater_or_equal { 0x3F00 } else { 0x3F01 })
+    }
+
+    fn if_statement(&mut self, token: &Token) -> Result<(), OctoError> {
+        let condition = self.condition(token)?;
+        let keyword = self.expect(token)?;
+        match keyword.text.as_str() {
+            "then" => self.emit_skip_unless(token, condition),
+            "begin" => {
+                self.emit_skip_unless(token, condition.negate())?;
+                self.emit_instruction(token, 0x1000)?;
+                self.blocks.push(Block::If(self.address - 2, keyword));
+                Ok(())
+            },
+            _ => Err(keyword.error(format!("expected then or begin but found {}", keyword.text)))
+        }
+    }
+
+    fn patch_jump(&mut self, jump_address: usize, target: usize) {
+        self.write(jump_address, &(0x1000 | target as u16).to_be_bytes());
+    }
+
+    fn define_macro(&mut self, token: &Token) -> Result<(), OctoError> {
+        let name = self.expect(token)?;
+        let mut parameters = Vec::new();
+        loop {
+            let parameter = self.expect(&name)?;
+            if parameter.text == "{" { break; }
+            parameters.push(parameter.text);
+        }
+        let mut depth = 1;
+        let mut body = Vec::new();
+        while depth > 0 {
+            let body_token = self.next().ok_or_else(|| name.error(format!("macro {} is never closed", name.text)))?;
+            match body_token.text.as_str() {
+                "{" if !body_token.is_string => depth += 1,
+                "}" if !body_token.is_string => depth -= 1,
+                _ => {}
+            }
+            if depth > 0 {
+                body.push(body_token);
+            }
+        }
+        self.check_name(&name, &name.text)?;
+        self.macros.insert(name.text, Macro { parameters, body });
+        Ok(())
+    }
+
+    /// Replaces the macro invocation with the body of the macro, where the parameters are replaced by the arguments
+    fn expand_macro(&mut self, token: &Token) -> Result<(), OctoError> {
+        let Some(macro_definition) = self.macros.get(&token.text) else { return Ok(()); };
+        let n_parameters = macro_definition.parameters.len();
+        let arguments: Vec<Token> = self.tokens.get(self.position..self.position + n_parameters)
+            .ok_or_else(|| token.error(format!("macro {} expects {} arguments", token.text, n_parameters)))?
+            .to_vec();
+        let body: Vec<Token> = macro_definition.body.iter()
+            .map(|body_token| match macro_definition.parameters.iter().position(|parameter| *parameter == body_token.text) {
+                Some(idx) if !body_token.is_string => Token { text: arguments[idx].text.clone(), ..token.clone() },
+                _ => Token { line: token.line, column: token.column, ..body_token.clone() }
+            })
+            .collect();
+
+        self.expanded_tokens += body.len();
+        if self.expanded_tokens > Compiler::MAX_EXPANDED_TOKENS {
+            return Err(token.error(format!("macro {} expands forever", token.text)));
+        }
+        self.tokens.splice(self.position..self.position + n_parameters, body);
+        Ok(())
+    }
+
+    /// Evaluates the `{ ... }` block whose opening brace is the token
+    fn calc_block(&mut self, brace: &Token) -> Result<f64, OctoError> {
+        let value = self.calc_expression(brace)?;
+        self.expect_text(brace, "}")?;
+        Ok(value)
+    }
+
+    /// Binary operators have the same precedence and are evaluated from right to left, as in Octo
+    fn calc_expression(&mut self, previous: &Token) -> Result<f64, OctoError> {
+        let left = self.calc_term(previous)?;
+        let Some(operator) = self.tokens.get(self.position).cloned() else { return Ok(left); };
+        let binary: fn(f64, f64) -> f64 = match operator.text.as_str() {
+            "+" => |a, b| a + b,
+            "-" => |a, b| a - b,
+            "*" => |a, b| a * b,
+            "/" => |a, b| a / b,
+            "%" => |a, b| a % b,
+            "&" => |a, b| ((a as i64) & (b as i64)) as f64,
+            "|" => |a, b| ((a as i64) | (b as i64)) as f64,
+            "^" => |a, b| ((a as i64) ^ (b as i64)) as f64,
+            "<<" | ">>" => return self.calc_shift(left, &operator),
+            "pow" => f64::powf,
+            "min" => f64::min,
+            "max" => f64::max,
+            "<" => |a, b| (a < b) as i64 as f64,
+            ">" => |a, b| (a > b) as i64 as f64,
+            "<=" => |a, b| (a <= b) as i64 as f64,
+            ">=" => |a, b| (a >= b) as i64 as f64,
+            "==" => |a, b| (a == b) as i64 as f64,
+            "!=" => |a, b| (a != b) as i64 as f64,
+            _ => return Ok(left)
+        };
+        self.position += 1;
+        let right = self.calc_expression(&operator)?;
+        if matches!(operator.text.as_str(), "/" | "%") && right == 0.0 {
+            return Err(operator.error("division by zero"));
+        }
+        Ok(binary(left, right))
+    }
+
+    /// Shifts the integer part of the left operand, where shifting by a negative amount or by 64 bits or more fails
+    fn calc_shift(&mut self, left: f64, operator: &Token) -> Result<f64, OctoError> {
+        self.position += 1;
+        let right = self.calc_expression(operator)?;
+        let shift = if operator.text == "<<" { i64::checked_shl } else { i64::checked_shr };
+        u32::try_from(right as i64).ok()
+            .and_then(|amount| shift(left as i64, amount))
+            .map(|value| value as f64)
+            .ok_or_else(|| operator.error(format!("shift by {} is out of range", right)))
+    }
+
+    fn calc_term(&mut self, previous: &Token) -> Result<f64, OctoError> {
+        let token = self.expect(previous)?;
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|a| -a),
+            "~" => Some(|a| !(a as i64) as f64),
+            "!" => Some(|a| (a == 0.0) as i64 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term(&token)?));
+        }
+
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression(&token)?;
+                self.expect_text(&token, ")")?;
+                Ok(value)
+            },
+            "@" => {
+                let address = self.calc_term(&token)? as usize;
+                let offset = address.checked_sub(self.load_address)
+                    .ok_or_else(|| token.error("@ can only read the program"))?;
+                Ok(self.rom.get(offset).copied().unwrap_or(0) as f64)
+            },
+            "HERE" => Ok(self.address as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            text => match (parse_number(text), self.constants.get(text), self.labels.get(text), register(text).or_else(|| self.aliases.get(text).copied())) {
+                (Some(value), ..) => Ok(value as f64),
+                (_, Some(&value), ..) => Ok(value),
+                (_, _, Some(&address), _) => Ok(address as f64),
+                // Registers evaluate to their index, so aliases can be computed
+                (_, _, _, Some(x)) => Ok(x as f64),
+                _ => Err(token.error(format!("undefined name {}", text)))
+            }
+        }
+    }
+
+    /// Resolves the references to labels defined after their use
+    fn finish(mut self) -> Result<Vec<u8>, OctoError> {
+        if !self.labels.contains_key("main") {
+            let token = Token { text: String::new(), line: 1, column: 1, is_string: false };
+            return Err(token.error("the program does not define main"));
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let address = *self.labels.get(&fixup.name)
+                .ok_or_else(|| fixup.token.error(format!("undefined name {}", fixup.name)))?;
+            let offset = fixup.address - self.load_address;
+            match fixup.kind {
+                FixupKind::Address | FixupKind::UnpackHigh(Some(_)) if address > 0xFFF => {
+                    return Err(fixup.token.error(format!("address 0x{:X} does not fit in 12 bits", address)));
+                },
+                FixupKind::Long | FixupKind::UnpackHigh(None) if address > 0xFFFF => {
+                    return Err(fixup.token.error(format!("address 0x{:X} does not fit in 16 bits", address)));
+                },
+                FixupKind::Address => {
+                    self.rom[offset] |= (address >> 8) as u8;
+                    self.rom[offset + 1] = address as u8;
+                },
+                FixupKind::Long => self.rom[offset..offset + 2].copy_from_slice(&(address as u16).to_be_bytes()),
+                FixupKind::UnpackHigh(nibble) => self.rom[offset + 1] = (nibble.unwrap_or(0) << 4) | (address >> 8) as u8,
+                FixupKind::UnpackLow => self.rom[offset + 1] = address as u8
+            }
+        }
+        Ok(self.rom)
+    }
+}
+
+fn register(name: &str) -> Option<u8> {
+    let digit = name.strip_prefix(['v', 'V'])?;
+    (digit.len() == 1).then(|| u8::from_str_radix(digit, 16).ok()).flatten()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compile, compile_at, OctoError};
+
+    fn words(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn main_test() {
+        // main defined first does not need a jump
+        assert_eq!(compile(": main\n  clear\n  loop again"), Ok(words(&[0x00E0, 0x1202])));
+        // otherwise the program starts with a jump to main
+        assert_eq!(compile(": sub\n  return\n: main\n  sub"), Ok(words(&[0x1204, 0x00EE, 0x2202])));
+        assert_eq!(compile(": sub return").unwrap_err().message, "the program does not define main");
+        // the labels start at the load address
+        assert_eq!(compile_at(": sub\n  return\n: main\n  sub", 0x600), Ok(words(&[0x1604, 0x00EE, 0x2602])));
+        assert_eq!(compile_at(": main\n:org 0x200", 0x600).unwrap_err().message, "value 512 is out of range");
+    }
+
+    #[test]
+    fn statements_test() {
+        let source = "
+:const SPEED 3
+:alias x v1
+: main
+  x := 10
+  x += SPEED
+  x -= 1
+  v2 := x
+  v2 <<= x
+  i := sprite
+  i := long sprite
+  i += v2
+  i := hex v0
+  sprite x v2 5
+  v3 := random 0xFF
+  v4 := key
+  delay := v4
+  save v5
+  load v1 - v3
+  bcd v6
+  :unpack 0xA sprite
+: sprite
+  0xF0 0x90
+  :byte { SPEED * 2 }
+  :pointer main
+";
+        let mut expected = words(&[
+            0x610A, 0x7103, 0x71FF, 0x8210, 0x821E, 0xA226, 0xF000, 0x0226, 0xF21E, 0xF029, 0xD125, 0xC3FF, 0xF40A,
+            0xF415, 0xF555, 0x5133, 0xF633, 0x60A2, 0x6126
+        ]);
+        expected.extend([0xF0, 0x90, 0x06, 0x02, 0x00]);
+        assert_eq!(compile(source), Ok(expected));
+    }
+
+    #[test]
+    fn control_flow_test() {
+        let source = "
+: main
+  loop
+    if v0 == 5 then v1 := 1
+    if v0 key begin
+      v2 := 2
+    else
+      v2 := 3
+    end
+    while v0 != v1
+    v0 += 1
+  again
+";
+        assert_eq!(compile(source), Ok(words(&[
+            0x4005, 0x6101, // if then
+            0xE09E, 0x120C, 0x6202, 0x120E, 0x6203, // if begin else end
+            0x9010, 0x1216, // while
+            0x7001, 0x1200 // again
+        ])));
+    }
+
+    #[test]
+    fn comparison_test() {
+        // VF is 1 when the left side is greater or equal, then the skip is taken when the comparison is false
+        assert_eq!(compile(": main if v0 < v1 then clear"), Ok(words(&[0x8F10, 0x8F07, 0x3F01, 0x00E0])));
+        assert_eq!(compile(": main if v0 >= 5 then clear"), Ok(words(&[0x6F05, 0x8F07, 0x3F00, 0x00E0])));
+        assert_eq!(compile(": main if v0 > 5 then clear"), Ok(words(&[0x6F06, 0x8F07, 0x3F00, 0x00E0])));
+        assert_eq!(compile(": main if v0 <= v1 then clear"), Ok(words(&[0x8F00, 0x8F17, 0x3F00, 0x00E0])));
+    }
+
+    #[test]
+    fn macro_and_calc_test() {
+        let source = "
+:macro set register value { register := value }
+:calc DOUBLE { 2 * 3 + 1 }
+: main
+  set v0 DOUBLE
+  set v1 { ( 1 + 1 ) * 4 }
+";
+        // Right to left evaluation: 2 * (3 + 1)
+        assert_eq!(compile(source), Ok(words(&[0x6008, 0x6108])));
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(compile(": main\n  v0 := 256"), Err(OctoError { line: 2, column: 9, message: "value 256 is out of range".to_string() }));
+        assert_eq!(compile(": main\n  jump nowhere").unwrap_err().line, 2);
+        assert_eq!(compile(": main\n  loop\n  clear").unwrap_err().message, "loop is never closed");
+        assert_eq!(compile(": main\n  v0 :=").unwrap_err().message, "missing operand after :=");
+        assert_eq!(compile(": main\n: main").unwrap_err().message, "the name main is already defined");
+        assert_eq!(compile(":calc big { 1 << 64 }").unwrap_err().message, "shift by 64 is out of range");
+        assert_eq!(compile(":calc big { 256 >> -1 }").unwrap_err().message, "shift by -1 is out of range");
+        assert_eq!(compile(": main\n  :unpack 0xA 0x1000").unwrap_err().message, "address 0x1000 does not fit in 12 bits");
+        assert_eq!(compile(": main\n  :unpack long 0x10000").unwrap_err().message, "address 0x10000 does not fit in 16 bits");
+        assert_eq!(compile(": main\n  :pointer 0x10000").unwrap_err().message, "address 0x10000 does not fit in 16 bits");
+        assert_eq!(compile(": main\n  :unpack 0xA far\n:org 0x1000\n: far").unwrap_err().message, "address 0x1000 does not fit in 12 bits");
+        assert!(compile(": main\n  :unpack long far\n:org 0x1000\n: far").is_ok());
+    }
+}
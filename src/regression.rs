@@ -0,0 +1,108 @@
+use crate::{chip8::Chip8, cpu::error::CpuError, octo, selftest::{display_hash, PRESETS}};
+
+/**
+Regression program of an opcode group, written in Octo, whose final framebuffer is compared to a snapshot hash.
+
+The snapshots were recorded with this emulator, so a failure means that its behavior changed, not that it departs from
+the original interpreters, which is what the conformance ROMs of [`crate::selftest`] check
+ */
+struct RegressionTest {
+    group: &'static str,
+    source: &'static str,
+    /// Input state held during the whole run
+    keys: [u8; 16],
+    /// Framebuffer hash of each preset, in the order of [`PRESETS`]
+    snapshot: [u64; 4]
+}
+
+const PRELUDE: &str = include_str!("regression/prelude.8o");
+
+/// Number of frames each test runs for, which is enough for the display wait quirk
+const FRAMES: u32 = 120;
+
+const HELD_KEY_5: [u8; 16] = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+const REGRESSION_TESTS: [RegressionTest; 8] = [
+    RegressionTest {
+        group: "display",
+        source: include_str!("regression/display.8o"),
+        keys: [0; 16],
+        snapshot: [0xEED2_BAFD_C3E1_5944; 4]
+    },
+    RegressionTest {
+        group: "jumps",
+        source: include_str!("regression/jumps.8o"),
+        keys: [0; 16],
+        snapshot: [0xABE7_65D6_F806_92E9; 4]
+    },
+    RegressionTest {
+        group: "skips",
+        source: include_str!("regression/skips.8o"),
+        keys: [0; 16],
+        snapshot: [0x723E_B40E_C709_B429; 4]
+    },
+    RegressionTest {
+        group: "arithmetic",
+        source: include_str!("regression/arithmetic.8o"),
+        keys: [0; 16],
+        snapshot: [0x09CF_7178_7BFD_050D; 4]
+    },
+    RegressionTest {
+        group: "memory",
+        source: include_str!("regression/memory.8o"),
+        keys: [0; 16],
+        snapshot: [0x723E_B40E_C709_B429; 4]
+    },
+    RegressionTest {
+        group: "timers",
+        source: include_str!("regression/timers.8o"),
+        keys: [0; 16],
+        snapshot: [0x24D2_353F_4BF7_48ED; 4]
+    },
+    RegressionTest {
+        group: "keypad",
+        source: include_str!("regression/keypad.8o"),
+        keys: HELD_KEY_5,
+        snapshot: [0x96B7_7FC5_215F_694D; 4]
+    },
+    RegressionTest {
+        group: "quirks",
+        source: include_str!("regression/quirks.8o"),
+        keys: [0; 16],
+        snapshot: [0xBEF2_77D7_9782_14B3, 0xFBA8_B4C1_839E_2028, 0x2F39_C943_CC9E_250A, 0x0052_D794_1AB8_308B]
+    }
+];
+
+impl RegressionTest {
+    /// Framebuffer hash at the end of the run under the preset, with the CPU error which stopped it
+    fn run(&self, preset_idx: usize) -> (u64, Option<CpuError>) {
+        let (_, quirks, memory_size) = PRESETS[preset_idx];
+        let rom = octo::compile(&format!("{}\n{}", PRELUDE, self.source))
+            .unwrap_or_else(|e| panic!("The {} regression test does not compile: {}", self.group, e));
+        let mut chip8 = Chip8::builder()
+            .rom(&rom)
+            .memory_size(memory_size)
+            .quirks(quirks)
+            .seed(0)
+            .build()
+            .unwrap_or_else(|e| panic!("The {} regression test does not fit in memory: {}", self.group, e));
+        chip8.update_input_state(self.keys);
+        let error = chip8.run_frames(FRAMES).err();
+        (display_hash(chip8.get_display()), error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PRESETS, REGRESSION_TESTS};
+
+    #[test]
+    fn regression_test() {
+        for test in &REGRESSION_TESTS {
+            for (preset_idx, (preset, ..)) in PRESETS.iter().enumerate() {
+                let (hash, error) = test.run(preset_idx);
+                assert_eq!((hash, error), (test.snapshot[preset_idx], None), "{} regression under {}", test.group, preset);
+            }
+        }
+    }
+}
@@ -1,19 +1,29 @@
 use anyhow::Error;
-use sdl2::{render::{Canvas, TextureCreator}, video::{Window, WindowContext}, surface::Surface, pixels::{PixelFormatEnum, Color}};
+use chip_8_emu::{display::Display, screenshot::PALETTE};
+use sdl2::{render::{Canvas, TextureCreator}, video::{Window, WindowContext}, surface::Surface, pixels::{PixelFormatEnum, Color, Palette}};
 
-pub struct Renderer<const T: usize, const U: usize> {
+pub struct Renderer {
     canvas: Canvas<Window>,
-    texture_creator: TextureCreator<WindowContext>,
-    frequency: u32
+    texture_creator: TextureCreator<WindowContext>
 }
 
-impl<const T: usize, const U: usize> Renderer<T, U> {
+impl Renderer {
+    /// Colors of the pixel values, see [`chip_8_emu::screenshot::PALETTE`]
+    const PALETTE: [Color; 4] = {
+        let [off, first, second, both] = PALETTE;
+        [
+            Color::RGB(off[0], off[1], off[2]),
+            Color::RGB(first[0], first[1], first[2]),
+            Color::RGB(second[0], second[1], second[2]),
+            Color::RGB(both[0], both[1], both[2])
+        ]
+    };
+
     pub fn new(canvas: Canvas<Window>) -> Self {
         let texture_creator = canvas.texture_creator();
         Self {
             canvas, 
-            texture_creator,
-            frequency: 60
+            texture_creator
         }
     }
 
@@ -21,26 +31,24 @@ impl<const T: usize, const U: usize> Renderer<T, U> {
         self.canvas.clear();
     }
 
-    pub fn render_bw_pixels(&mut self, pixels: &[[u8; T]; U]) -> Result<(), Error> {
-        let mut pixels: Vec<u8> = pixels
-            .iter()
-            .flat_map(|array| array.iter())
-            .cloned()
-            .collect();
-        let mut surface = Surface::from_data(&mut pixels, T as u32, U as u32, T as u32, PixelFormatEnum::Index8).map_err(Error::msg)?;
-        surface.set_color_key(true, Color::BLACK).map_err(Error::msg)?;
+    /// Renders the display stretched to the whole window, the resolution may change from one frame to the next
+    pub fn render_pixels(&mut self, display: &Display) -> Result<(), Error> {
+        let mut pixels: Vec<u8> = display.get_pixels().to_vec();
+        let (width, height) = (display.width() as u32, display.height() as u32);
+        let mut surface = Surface::from_data(&mut pixels, width, height, width, PixelFormatEnum::Index8).map_err(Error::msg)?;
+        surface.set_palette(&Palette::with_colors(&Renderer::PALETTE).map_err(Error::msg)?).map_err(Error::msg)?;
         let texture = self.texture_creator.create_texture_from_surface(surface)?;
         self.canvas.copy(&texture, None, None).map_err(Error::msg)?;
 
         Ok(())
     }
 
-    pub fn update(&mut self) {
-        self.canvas.present();
+    pub fn set_title(&mut self, title: &str) -> Result<(), Error> {
+        self.canvas.window_mut().set_title(title)?;
+        Ok(())
     }
 
-    pub fn get_frequency(&self) -> u32 {
-        self.frequency
+    pub fn update(&mut self) {
+        self.canvas.present();
     }
-
 }
\ No newline at end of file
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+use crate::chip8::Chip8;
+
+/**
+Ring buffer of save states used to play a game backward.
+
+Only the most recent snapshot is kept whole. Every older snapshot is stored as the difference with the one taken
+after it, XORed and run-length encoded, so a frame where little changed takes a handful of bytes. Rewinding applies
+the differences from the most recent snapshot backward, while the oldest ones are dropped when the buffer is full
+ */
+pub struct RewindBuffer {
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    capacity: usize,
+    interval: u32,
+    frame_counter: u32
+}
+
+impl RewindBuffer {
+    const DELTA_FULL: u8 = 0;
+    const DELTA_XOR_RLE: u8 = 1;
+
+    /// Creates a buffer holding up to `capacity` snapshots, taken every `interval` frames
+    pub fn new(capacity: usize, interval: u32) -> Self {
+        Self {
+            latest: None,
+            deltas: VecDeque::new(),
+            capacity: capacity.max(1),
+            interval: interval.max(1),
+            frame_counter: 0
+        }
+    }
+
+    /// Takes a snapshot of the machine if the snapshot interval has elapsed, it must be called once per frame
+    pub fn record_frame(&mut self, chip8: &Chip8) {
+        if self.frame_counter == 0 {
+            self.push(chip8.save_state());
+        }
+        self.frame_counter = (self.frame_counter + 1) % self.interval;
+    }
+
+    /// Restores the most recent snapshot and removes it from the buffer, returning false if the buffer is empty
+    pub fn rewind(&mut self, chip8: &mut Chip8) -> bool {
+        self.frame_counter = 0;
+        match self.pop() {
+            Some(snapshot) => chip8.load_state(&snapshot).is_ok(),
+            None => false
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(RewindBuffer::encode_delta(&snapshot, &latest));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(snapshot);
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let snapshot = self.latest.take()?;
+        self.latest = self.deltas.pop_back()
+            .map(|delta| RewindBuffer::decode_delta(&snapshot, &delta));
+        Some(snapshot)
+    }
+
+    /// Number of snapshots in the buffer
+    pub fn len(&self) -> usize {
+        self.latest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// Bytes used by the snapshots in the buffer
+    pub fn size_in_bytes(&self) -> usize {
+        self.latest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+
+    /// Encodes `previous` as a difference from `next`
+    fn encode_delta(next: &[u8], previous: &[u8]) -> Vec<u8> {
+        if next.len() != previous.len() {
+            let mut delta = vec![RewindBuffer::DELTA_FULL];
+            delta.extend_from_slice(previous);
+            return delta;
+        }
+
+        // Sequence of (number of unchanged bytes, number of changed bytes, XORed changed bytes)
+        let mut delta = vec![RewindBuffer::DELTA_XOR_RLE];
+        let xored: Vec<u8> = next.iter().zip(previous).map(|(a, b)| a ^ b).collect();
+        let mut i = 0;
+        while i < xored.len() {
+            let zeros_start = i;
+            while i < xored.len() && xored[i] == 0 { i += 1; }
+            let literals_start = i;
+            while i < xored.len() && xored[i] != 0 { i += 1; }
+            write_varint(&mut delta, literals_start - zeros_start);
+            write_varint(&mut delta, i - literals_start);
+            delta.extend_from_slice(&xored[literals_start..i]);
+        }
+        delta
+    }
+
+    fn decode_delta(next: &[u8], delta: &[u8]) -> Vec<u8> {
+        if delta[0] == RewindBuffer::DELTA_FULL {
+            return delta[1..].to_vec();
+        }
+
+        let mut previous = next.to_vec();
+        let mut position = 1;
+        let mut i = 0;
+        while position < delta.len() {
+            i += read_varint(delta, &mut position);
+            let n_literals = read_varint(delta, &mut position);
+            for (byte, xored) in previous[i..i + n_literals].iter_mut().zip(&delta[position..position + n_literals]) {
+                *byte ^= xored;
+            }
+            position += n_literals;
+            i += n_literals;
+        }
+        previous
+    }
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 { return value; }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::chip8::Chip8;
+    use super::RewindBuffer;
+
+    #[test]
+    fn push_pop_test() {
+        let mut buffer = RewindBuffer::new(10, 1);
+        let snapshots: Vec<Vec<u8>> = (0..5u8)
+            .map(|i| { let mut snapshot = vec![0; 1000]; snapshot[i as usize * 100] = i + 1; snapshot })
+            .collect();
+        snapshots.iter().for_each(|snapshot| buffer.push(snapshot.clone()));
+
+        assert_eq!(buffer.len(), 5);
+        assert!(buffer.size_in_bytes() < 1100);
+        for snapshot in snapshots.iter().rev() {
+            assert_eq!(buffer.pop().as_ref(), Some(snapshot));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn different_length_test() {
+        let mut buffer = RewindBuffer::new(10, 1);
+        buffer.push(vec![1, 2, 3]);
+        buffer.push(vec![1, 2, 3, 4, 5]);
+
+        assert_eq!(buffer.pop(), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(buffer.pop(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn capacity_test() {
+        let mut buffer = RewindBuffer::new(3, 1);
+        (0..5u8).for_each(|i| buffer.push(vec![i; 200]));
+
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.pop(), Some(vec![4; 200]));
+        assert_eq!(buffer.pop(), Some(vec![3; 200]));
+        assert_eq!(buffer.pop(), Some(vec![2; 200]));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn rewind_test() {
+        // V0 += 1 in loop
+        let mut chip8 = Chip8::builder().rom(&[0x70, 0x01, 0x12, 0x00]).build().unwrap();
+        let mut buffer = RewindBuffer::new(100, 2);
+        for _ in 0..6 {
+            buffer.record_frame(&chip8);
+            chip8.tick().unwrap();
+            chip8.tick().unwrap();
+        }
+
+        assert_eq!(buffer.len(), 3);
+        assert!(buffer.rewind(&mut chip8));
+        assert_eq!(chip8.get_cpu().get_var_registers()[0], 4);
+        assert!(buffer.rewind(&mut chip8));
+        assert_eq!(chip8.get_cpu().get_var_registers()[0], 2);
+        assert!(buffer.rewind(&mut chip8));
+        assert_eq!(chip8.get_cpu().get_var_registers()[0], 0);
+        assert!(!buffer.rewind(&mut chip8));
+    }
+}
@@ -0,0 +1,141 @@
+/**
+Xorshift64* pseudo random number generator used by opcode 0xCXNN.
+
+Unlike `rand::thread_rng` it can be seeded, cloned and its whole state is a single `u64`, so the same seed always
+produces the same run of a ROM
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XorShiftRng {
+    state: u64
+}
+
+impl XorShiftRng {
+    pub fn new(seed: u64) -> Self {
+        // Spread the seed bits with a splitmix64 step, since the xorshift state must never be zero
+        let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        state ^= state >> 31;
+        Self { state: if state == 0 { 1 } else { state } }
+    }
+
+    pub fn from_entropy() -> Self {
+        Self::new(rand::random())
+    }
+
+    /// Internal state, which can be restored with [`XorShiftRng::from_state`]
+    pub fn get_state(&self) -> u64 {
+        self.state
+    }
+
+    /// Restores a state returned by [`XorShiftRng::get_state`], it returns None for the invalid zero state
+    pub fn from_state(state: u64) -> Option<Self> {
+        (state != 0).then_some(Self { state })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+/**
+Source of the random numbers of opcode 0xCXNN, which frontends can implement to supply their own generator.
+
+The whole state must fit in a `u64`, so that save states and rewind snapshots restore the same sequence of numbers
+ */
+pub trait RandomSource: Send {
+    fn next_u8(&mut self) -> u8;
+
+    fn get_state(&self) -> u64;
+
+    /// Restores a state returned by [`RandomSource::get_state`], it returns false if the state is invalid
+    fn set_state(&mut self, state: u64) -> bool;
+
+    /// Copy of the generator, used to clone the machine
+    fn clone_box(&self) -> Box<dyn RandomSource>;
+}
+
+impl Clone for Box<dyn RandomSource> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl RandomSource for XorShiftRng {
+    fn next_u8(&mut self) -> u8 {
+        XorShiftRng::next_u8(self)
+    }
+
+    fn get_state(&self) -> u64 {
+        XorShiftRng::get_state(self)
+    }
+
+    fn set_state(&mut self, state: u64) -> bool {
+        XorShiftRng::from_state(state).map(|rng| *self = rng).is_some()
+    }
+
+    fn clone_box(&self) -> Box<dyn RandomSource> {
+        Box::new(self.clone())
+    }
+}
+
+impl Default for XorShiftRng {
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RandomSource, XorShiftRng};
+
+    #[test]
+    fn seed_test() {
+        let mut rng_a = XorShiftRng::new(42);
+        let mut rng_b = XorShiftRng::new(42);
+        let mut rng_c = XorShiftRng::new(43);
+
+        let sequence_a: Vec<u8> = (0..16).map(|_| rng_a.next_u8()).collect();
+        let sequence_b: Vec<u8> = (0..16).map(|_| rng_b.next_u8()).collect();
+        let sequence_c: Vec<u8> = (0..16).map(|_| rng_c.next_u8()).collect();
+
+        assert_eq!(sequence_a, sequence_b);
+        assert_ne!(sequence_a, sequence_c);
+    }
+
+    #[test]
+    fn state_test() {
+        let mut rng = XorShiftRng::new(42);
+        rng.next_u64();
+        let mut restored = XorShiftRng::from_state(rng.get_state()).unwrap();
+
+        assert_eq!(rng.next_u64(), restored.next_u64());
+        assert_eq!(XorShiftRng::from_state(0), None);
+    }
+
+    #[test]
+    fn zero_seed_test() {
+        let mut rng = XorShiftRng::new(0);
+
+        assert!((0..16).any(|_| rng.next_u64() != 0));
+    }
+
+    #[test]
+    fn random_source_test() {
+        let mut rng: Box<dyn RandomSource> = Box::new(XorShiftRng::new(42));
+        rng.next_u8();
+        let mut cloned = rng.clone();
+
+        assert_eq!(rng.next_u8(), cloned.next_u8());
+        assert!(!rng.set_state(0));
+        assert!(rng.set_state(cloned.get_state()));
+        assert_eq!(rng.next_u8(), cloned.next_u8());
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt::Display;
+
+/**
+Versioned binary format of a machine snapshot.
+
+A save state starts with a header made of the magic bytes, the format version (u16) and the hash of the ROM the state
+was made for (u64), followed by the payload written by each component of the machine. All numbers are little endian.
+
+When the payload layout changes, [`SaveState::VERSION`] is bumped and a migration from the previous version is added to
+the list of migrations, so states saved by older versions can still be loaded
+ */
+pub struct SaveState;
+
+/// Conversion of a payload into the layout of the next format version
+type Migration = fn(Vec<u8>) -> Result<Vec<u8>, SaveStateError>;
+
+impl SaveState {
+    pub const MAGIC: [u8; 4] = *b"C8ST";
+    pub const VERSION: u16 = 1;
+    const HEADER_SIZE: usize = 4 + 2 + 8;
+    /// Migrations of the payload, where the element at index i converts version i + 1 into version i + 2
+    const MIGRATIONS: [Migration; SaveState::VERSION as usize - 1] = [];
+
+    /// Wraps the payload with the header
+    pub fn encode(rom_hash: u64, payload: &[u8]) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(&SaveState::MAGIC);
+        writer.write_u16(SaveState::VERSION);
+        writer.write_u64(rom_hash);
+        writer.write_bytes(payload);
+        writer.into_bytes()
+    }
+
+    /// Checks the header and returns the payload migrated to the current version
+    pub fn decode(rom_hash: u64, data: &[u8]) -> Result<Vec<u8>, SaveStateError> {
+        if data.len() < SaveState::HEADER_SIZE || data[..4] != SaveState::MAGIC {
+            return Err(SaveStateError::InvalidMagic);
+        }
+        let mut reader = StateReader::new(&data[4..SaveState::HEADER_SIZE]);
+        let version = reader.read_u16()?;
+        let state_rom_hash = reader.read_u64()?;
+
+        if version == 0 || version > SaveState::VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        if state_rom_hash != rom_hash {
+            return Err(SaveStateError::RomMismatch);
+        }
+
+        SaveState::MIGRATIONS[version as usize - 1..].iter()
+            .try_fold(data[SaveState::HEADER_SIZE..].to_vec(), |payload, migration| migration(payload))
+    }
+}
+
+/// Hash of the ROM stored in the save state header, it is the 64 bit FNV-1a hash of the ROM bytes
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaveStateError {
+    /// The data is not a save state
+    InvalidMagic,
+    /// The save state was made by a newer or unknown version of the format
+    UnsupportedVersion(u16),
+    /// The save state was made for another ROM
+    RomMismatch,
+    /// The save state ends before all the fields are read
+    Truncated,
+    /// A field has a value which can not be loaded
+    InvalidField(&'static str)
+}
+
+impl Display for SaveStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveStateError::InvalidMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            SaveStateError::RomMismatch => write!(f, "save state was made for another ROM"),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::InvalidField(field) => write!(f, "save state has an invalid {}", field)
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+/// Little endian writer used by the components of the machine to save their state
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Little endian reader used by the components of the machine to load their state
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        let bytes = self.data.get(self.position..self.position + len).ok_or(SaveStateError::Truncated)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.read_u8()? > 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SaveState, SaveStateError, StateReader, StateWriter, rom_hash};
+
+    #[test]
+    fn writer_reader_test() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789A_BCDE);
+        writer.write_u64(0x0102_0304_0506_0708);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u32(), Ok(0x789A_BCDE));
+        assert_eq!(reader.read_u64(), Ok(0x0102_0304_0506_0708));
+        assert_eq!(reader.read_u8(), Err(SaveStateError::Truncated));
+    }
+
+    #[test]
+    fn header_test() {
+        let hash = rom_hash(&[0x00, 0xE0]);
+        let data = SaveState::encode(hash, &[1, 2, 3]);
+
+        assert_eq!(SaveState::decode(hash, &data), Ok(vec![1, 2, 3]));
+        assert_eq!(SaveState::decode(rom_hash(&[0x00, 0xEE]), &data), Err(SaveStateError::RomMismatch));
+        assert_eq!(SaveState::decode(hash, &data[1..]), Err(SaveStateError::InvalidMagic));
+
+        let mut future_data = data.clone();
+        future_data[4] = 0xFF;
+        assert_eq!(SaveState::decode(hash, &future_data), Err(SaveStateError::UnsupportedVersion(0xFF)));
+    }
+}
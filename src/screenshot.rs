@@ -0,0 +1,154 @@
+use crate::display::Display;
+
+/// RGB colors of the pixel values: off, on in the first plane, on in the second plane, on in both planes
+pub const PALETTE: [[u8; 3]; 4] = [
+    [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55]
+];
+
+/// Characters of a text dump of the display
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextStyle {
+    /// One `#` or `.` per pixel
+    Ascii,
+    /// Unicode half blocks, with two rows of pixels per line
+    Blocks
+}
+
+/// Dumps the display as text, where a pixel is on if it is on in any plane
+pub fn format_text(display: &Display, style: TextStyle) -> String {
+    let rows: Vec<&[u8]> = display.rows().collect();
+    let mut output = String::new();
+    match style {
+        TextStyle::Ascii => for row in rows {
+            output.extend(row.iter().map(|&pixel| if pixel != 0 { '#' } else { '.' }));
+            output.push('\n');
+        },
+        TextStyle::Blocks => for pair in rows.chunks(2) {
+            let (upper, lower) = (pair[0], pair.get(1).copied().unwrap_or_default());
+            output.extend(upper.iter().enumerate().map(|(x, &pixel)| {
+                match (pixel != 0, lower.get(x).is_some_and(|&pixel| pixel != 0)) {
+                    (false, false) => ' ',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (true, true) => '█'
+                }
+            }));
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Encodes the display as an indexed PNG image, where each pixel is scaled into a square of scale x scale pixels
+pub fn encode_png(display: &Display, scale: usize) -> Vec<u8> {
+    let (width, height) = (display.width() * scale, display.height() * scale);
+
+    // Each scanline starts with the filter type, 0 for none
+    let mut image_data = Vec::with_capacity((width + 1) * height);
+    for row in display.rows() {
+        let mut scanline = vec![0];
+        scanline.extend(row.iter().flat_map(|&pixel| std::iter::repeat_n(pixel, scale)));
+        (0..scale).for_each(|_| image_data.extend_from_slice(&scanline));
+    }
+
+    let mut header = Vec::new();
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // 8 bits per pixel, indexed color, deflate compression, adaptive filtering, no interlace
+    header.extend([8, 3, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"PLTE", PALETTE.as_flattened());
+    write_chunk(&mut png, b"IDAT", &zlib_store(&image_data));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+/// Wraps the data into a zlib stream of uncompressed deflate blocks, which is enough for such small images
+fn zlib_store(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK_LEN: usize = 0xFFFF;
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK_LEN).peekable();
+    if blocks.peek().is_none() {
+        stream.extend([0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        stream.push(blocks.peek().is_none() as u8);
+        stream.extend((block.len() as u16).to_le_bytes());
+        stream.extend((!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let crc = data.iter().fold(0xFFFFFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xEDB88320,
+            _ => crc >> 1
+        })
+    });
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULO: u32 = 65521;
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % MODULO;
+        (a, (b + a) % MODULO)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use crate::display::Display;
+    use super::{adler32, crc32, encode_png, format_text, TextStyle};
+
+    #[test]
+    fn format_text_test() {
+        let mut display = Display::new();
+        display.flip_pixel(0, 0, 0);
+        display.flip_pixel(1, 1, 1);
+        display.flip_pixel(2, 0, 0);
+        display.flip_pixel(2, 1, 0);
+
+        let ascii = format_text(&display, TextStyle::Ascii);
+        assert_eq!(ascii.lines().count(), 32);
+        assert!(ascii.starts_with(&format!("#.#{}\n.##{}\n", ".".repeat(61), ".".repeat(61))));
+
+        let blocks = format_text(&display, TextStyle::Blocks);
+        assert_eq!(blocks.lines().count(), 16);
+        assert!(blocks.starts_with(&format!("▀▄█{}\n", " ".repeat(61))));
+    }
+
+    #[test]
+    fn checksum_test() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn encode_png_test() {
+        let png = encode_png(&Display::new(), 2);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1A\n");
+        // IHDR with the scaled size
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 128, 0, 0, 0, 64]);
+        assert_eq!(&png[png.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+}
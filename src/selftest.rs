@@ -0,0 +1,133 @@
+use std::{fmt::Write, fs, io, path::Path};
+use crate::{chip8::Chip8, cpu::{error::CpuError, quirks::Quirks}, display::Display, memory::Memory, savestate};
+
+/// Interpreters the conformance ROMs are run for, with the memory size of each one
+pub const PRESETS: [(&str, Quirks, usize); 4] = [
+    ("COSMAC VIP", Quirks::COSMAC_VIP, Memory::DEFAULT_SIZE),
+    ("CHIP-48", Quirks::CHIP_48, Memory::DEFAULT_SIZE),
+    ("SUPER-CHIP", Quirks::SUPER_CHIP, Memory::DEFAULT_SIZE),
+    ("XO-CHIP", Quirks::XO_CHIP, Memory::XO_CHIP_SIZE)
+];
+
+/// Directory of the conformance ROMs and of their licenses in the source tree
+pub const SELFTEST_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/selftest");
+
+/// Number of frames each ROM runs for before its framebuffer is hashed
+const FRAMES: u32 = 600;
+
+/**
+Third-party conformance ROM, whose final framebuffer is compared to the one of a reference interpreter.
+
+The expected hashes come from the reference interpreter of each preset, never from this emulator, and are kept per
+preset since the flags and quirks ROMs draw a different report on each interpreter. A missing hash means that the
+reference output was not recorded yet, so the ROM is run but can not fail
+ */
+pub struct SelfTest {
+    pub name: &'static str,
+    /// File name of the ROM in the selftest directory
+    pub file: &'static str,
+    /// Framebuffer hash of the reference interpreter for each preset, in the order of [`PRESETS`]
+    expected: [Option<u64>; 4]
+}
+
+pub const SELFTESTS: [SelfTest; 5] = [
+    SelfTest { name: "IBM logo", file: "ibm-logo.ch8", expected: [None; 4] },
+    SelfTest { name: "corax+", file: "corax+.ch8", expected: [None; 4] },
+    SelfTest { name: "flags", file: "flags.ch8", expected: [None; 4] },
+    SelfTest { name: "quirks", file: "quirks.ch8", expected: [None; 4] },
+    SelfTest { name: "keypad", file: "keypad.ch8", expected: [None; 4] }
+];
+
+/// Outcome of a conformance ROM under a preset
+pub struct SelfTestResult {
+    pub name: &'static str,
+    pub preset: &'static str,
+    pub hash: u64,
+    pub expected_hash: Option<u64>,
+    pub error: Option<CpuError>,
+    pub display: Display
+}
+
+impl SelfTestResult {
+    /// Whether the run differs from the reference interpreter, which can only be told once its hash is recorded
+    pub fn failed(&self) -> bool {
+        self.error.is_some() || self.expected_hash.is_some_and(|expected_hash| expected_hash != self.hash)
+    }
+
+    pub fn verified(&self) -> bool {
+        self.expected_hash.is_some()
+    }
+}
+
+/// Hash of the framebuffer compared to the reference, the FNV-1a hash of the resolution and the pixels
+pub fn display_hash(display: &Display) -> u64 {
+    let mut data = vec![display.width() as u8, display.height() as u8];
+    data.extend_from_slice(display.get_pixels());
+    savestate::rom_hash(&data)
+}
+
+impl SelfTest {
+    /// Runs the ROM read from the directory under every preset, or returns the error of reading it
+    pub fn run(&self, dir: &Path) -> io::Result<Vec<SelfTestResult>> {
+        let rom = fs::read(dir.join(self.file))?;
+        let results = PRESETS.iter().zip(self.expected).map(|(&(preset, quirks, memory_size), expected_hash)| {
+            let mut chip8 = Chip8::builder()
+                .rom(&rom)
+                .memory_size(memory_size)
+                .quirks(quirks)
+                .seed(0)
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let error = chip8.run_frames(FRAMES).err();
+            let display = chip8.get_display().clone();
+            Ok(SelfTestResult { name: self.name, preset, hash: display_hash(&display), expected_hash, error, display })
+        });
+        results.collect()
+    }
+}
+
+/// Runs every conformance ROM of the directory, with the error of each ROM which could not be read or loaded
+pub fn run_selftests(dir: &Path) -> Vec<(&'static SelfTest, io::Result<Vec<SelfTestResult>>)> {
+    SELFTESTS.iter().map(|test| (test, test.run(dir))).collect()
+}
+
+/// Table of the results with a row per ROM and a column per preset, where the runs without reference hash are
+/// reported as unverified
+pub fn format_results(results: &[(&SelfTest, io::Result<Vec<SelfTestResult>>)]) -> String {
+    let mut header = format!("{:<12}", "ROM");
+    PRESETS.iter().for_each(|(preset, ..)| write!(header, "{:<12}", preset).unwrap());
+    let rows = results.iter().map(|(test, results)| {
+        let mut row = format!("{:<12}", test.name);
+        match results {
+            Ok(results) => for result in results {
+                let status = match (result.failed(), result.verified()) {
+                    (true, _) => "FAIL",
+                    (false, true) => "pass",
+                    (false, false) => "unverified"
+                };
+                write!(row, "{:<12}", status).unwrap();
+            },
+            Err(e) => write!(row, "{}: {}", test.file, e).unwrap()
+        }
+        row
+    });
+    std::iter::once(header).chain(rows)
+        .map(|line| line.trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::{format_results, run_selftests, SELFTESTS};
+
+    #[test]
+    fn missing_rom_test() {
+        let results = run_selftests(Path::new("does-not-exist"));
+        assert_eq!(results.len(), SELFTESTS.len());
+        assert!(results.iter().all(|(_, result)| result.is_err()));
+        let table = format_results(&results);
+        assert!(table.starts_with("ROM         COSMAC VIP  CHIP-48     SUPER-CHIP  XO-CHIP\nIBM logo    ibm-logo.ch8: "));
+    }
+}
@@ -1,24 +1,390 @@
-use sdl2::audio::AudioCallback;
-
-pub struct SquareWave {
-    pub phase_inc: f32,
-    pub phase: f32,
-    pub volume: f32
-}
-
-impl AudioCallback for SquareWave {
-    type Channel = f32;
-
-    fn callback(&mut self, out: &mut [Self::Channel]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+use std::{f32::consts::PI, fmt::Display};
+
+use chip_8_emu::timer::BuzzerFrame;
+
+/**
+Plays the XO-CHIP 1-bit audio pattern in loop, each bit of the 128 bits pattern is a high or low sample.
+
+The playback rate of the pattern is 4000 bits per second at pitch 64, and it doubles every 48 pitch steps
+ */
+pub struct PatternWave {
+    pattern: [u8; 16],
+    bit_inc: f32,
+    bit_position: f32,
+    sample_freq: i32,
+    volume: f32
+}
+
+impl PatternWave {
+    const N_BITS: f32 = 128.0;
+
+    pub fn new(sample_freq: i32, volume: f32) -> Self {
+        let mut wave = Self {
+            pattern: [0; 16],
+            bit_inc: 0.0,
+            bit_position: 0.0,
+            sample_freq,
+            volume
+        };
+        wave.set_pitch(64);
+        wave
+    }
+
+    pub fn set_pattern(&mut self, pattern: &[u8; 16]) {
+        self.pattern = *pattern;
+    }
+
+    pub fn set_pitch(&mut self, pitch: u8) {
+        let bit_rate = 4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0);
+        self.bit_inc = bit_rate / self.sample_freq as f32;
+    }
+
+    /// Generates the next sample and advances the playback position
+    pub fn next_sample(&mut self) -> f32 {
+        let sample = if self.current_bit() {
+            self.volume
+        } else {
+            -self.volume
+        };
+        self.bit_position = (self.bit_position + self.bit_inc) % PatternWave::N_BITS;
+        sample
+    }
+
+    fn current_bit(&self) -> bool {
+        let bit_idx = self.bit_position as usize;
+        self.pattern[bit_idx / 8] & (0x80 >> (bit_idx % 8)) > 0
+    }
+}
+
+/// Waveform played by the buzzer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Waveform {
+    /// The XO-CHIP audio pattern of the ROM at its pitch, a 500 Hz square wave unless the ROM changes it
+    #[default]
+    Pattern,
+    Square,
+    /// Square wave which is high during the duty cycle of its period
+    Pulse,
+    Triangle,
+    Sine,
+    /// White noise, whose value changes at the tone frequency
+    Noise
+}
+
+impl Waveform {
+    /// Next waveform in the order of declaration, the last one is followed by the first one
+    pub fn next(self) -> Self {
+        match self {
+            Waveform::Pattern => Waveform::Square,
+            Waveform::Square => Waveform::Pulse,
+            Waveform::Pulse => Waveform::Triangle,
+            Waveform::Triangle => Waveform::Sine,
+            Waveform::Sine => Waveform::Noise,
+            Waveform::Noise => Waveform::Pattern
         }
     }
+}
+
+impl Display for Waveform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Waveform::Pattern => "pattern",
+            Waveform::Square => "square",
+            Waveform::Pulse => "pulse",
+            Waveform::Triangle => "triangle",
+            Waveform::Sine => "sine",
+            Waveform::Noise => "noise"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/**
+Voice of the buzzer: generates the samples of its waveform at the tone frequency and the master volume.
 
+The synthesizer does not depend on an audio device, the samples are pulled one at a time with
+[`Synthesizer::next_sample`]. Muting is applied by [`AudioStream`], which fades the sound out
+ */
+pub struct Synthesizer {
+    waveform: Waveform,
+    tone: f32,
+    duty_cycle: f32,
+    volume: f32,
+    muted: bool,
+    sample_freq: i32,
+    pattern_wave: PatternWave,
+    phase: f32,
+    noise: u16
+}
+
+impl Synthesizer {
+    pub const DEFAULT_TONE: f32 = 440.0;
+    pub const MIN_TONE: f32 = 20.0;
+    pub const MAX_TONE: f32 = 8000.0;
+    pub const DEFAULT_DUTY_CYCLE: f32 = 0.25;
+    pub const DEFAULT_VOLUME: f32 = 0.25;
+
+    pub fn new(sample_freq: i32) -> Self {
+        Self {
+            waveform: Waveform::default(),
+            tone: Synthesizer::DEFAULT_TONE,
+            duty_cycle: Synthesizer::DEFAULT_DUTY_CYCLE,
+            volume: Synthesizer::DEFAULT_VOLUME,
+            muted: false,
+            sample_freq,
+            pattern_wave: PatternWave::new(sample_freq, 1.0),
+            phase: 0.0,
+            noise: 1
+        }
+    }
+
+    pub fn get_waveform(&self) -> Waveform {
+        self.waveform
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    /// Frequency in Hz of every waveform but [`Waveform::Pattern`], which follows the pitch of the ROM
+    pub fn get_tone(&self) -> f32 {
+        self.tone
+    }
+
+    /// Sets the tone frequency, clamped between [`Synthesizer::MIN_TONE`] and [`Synthesizer::MAX_TONE`]
+    pub fn set_tone(&mut self, tone: f32) {
+        self.tone = tone.clamp(Synthesizer::MIN_TONE, Synthesizer::MAX_TONE);
+    }
+
+    /// Fraction of the period during which the [`Waveform::Pulse`] wave is high
+    pub fn get_duty_cycle(&self) -> f32 {
+        self.duty_cycle
+    }
+
+    /// Sets the duty cycle, clamped between 0.0 and 1.0
+    pub fn set_duty_cycle(&mut self, duty_cycle: f32) {
+        self.duty_cycle = duty_cycle.clamp(0.0, 1.0);
+    }
+
+    pub fn get_volume(&self) -> f32 {
+        self.volume
+    }
+
+    /// Sets the master volume, clamped between 0.0 (silent) and 1.0 (full scale)
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Sets the XO-CHIP audio pattern played by [`Waveform::Pattern`]
+    pub fn set_pattern(&mut self, pattern: &[u8; 16]) {
+        self.pattern_wave.set_pattern(pattern);
+    }
+
+    /// Sets the XO-CHIP pitch of [`Waveform::Pattern`]
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pattern_wave.set_pitch(pitch);
+    }
+
+    /// Generates the next sample and advances the playback position
+    pub fn next_sample(&mut self) -> f32 {
+        let sample = match self.waveform {
+            Waveform::Pattern => self.pattern_wave.next_sample(),
+            Waveform::Square => if self.phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Pulse => if self.phase < self.duty_cycle { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Sine => (2.0 * PI * self.phase).sin(),
+            Waveform::Noise => if self.noise & 1 != 0 { 1.0 } else { -1.0 }
+        };
+
+        self.phase += self.tone / self.sample_freq as f32;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            // Galois LFSR with the maximal period of 2^16 - 1 values
+            self.noise = (self.noise >> 1) ^ ((self.noise & 1).wrapping_neg() & 0xB400);
+        }
+        sample * self.volume
+    }
+}
+
+/**
+Generates the audio stream of the emulator, one frame of samples at a time, to be queued to the audio device.
+
+The samples follow the buzzer of each emulated frame instead of the wall clock, and every start and stop of the buzzer
+fades in or out over [`AudioStream::FADE_DURATION`] seconds to avoid clicks
+ */
+pub struct AudioStream {
+    synthesizer: Synthesizer,
+    sample_freq: i32,
+    frame_freq: u32,
+    frame_count: u64,
+    gain: f32,
+    fade_step: f32
+}
+
+impl AudioStream {
+    pub const FADE_DURATION: f32 = 0.002;
+
+    pub fn new(synthesizer: Synthesizer, frame_freq: u32) -> Self {
+        let sample_freq = synthesizer.sample_freq;
+        Self {
+            synthesizer,
+            sample_freq,
+            frame_freq,
+            frame_count: 0,
+            gain: 0.0,
+            fade_step: 1.0 / (sample_freq as f32 * AudioStream::FADE_DURATION).max(1.0)
+        }
+    }
+
+    pub fn get_synthesizer(&self) -> &Synthesizer {
+        &self.synthesizer
+    }
+
+    pub fn get_synthesizer_mut(&mut self) -> &mut Synthesizer {
+        &mut self.synthesizer
+    }
+
+    /**
+    Generates the samples of a frame, where the buzzer plays the synthesizer voice with the audio pattern and pitch of
+    the ROM.
+
+    Without buzzer frame, e.g. while the emulation is paused, or when muted, the frame is silent. The frames have a
+    whole number of samples, which are spread so that every second of emulated time has exactly the sample frequency
+     */
+    pub fn render_frame(&mut self, buzzer: Option<&BuzzerFrame>, pattern: &[u8; 16], pitch: u8) -> Vec<f32> {
+        self.synthesizer.set_pattern(pattern);
+        self.synthesizer.set_pitch(pitch);
+
+        let samples = self.next_frame_length();
+        let muted = self.synthesizer.is_muted();
+        (0..samples)
+            .map(|sample| {
+                let on = !muted && buzzer.is_some_and(|buzzer| buzzer.is_on_at(sample as f32 / samples as f32));
+                self.gain = match on {
+                    true => (self.gain + self.fade_step).min(1.0),
+                    false => (self.gain - self.fade_step).max(0.0)
+                };
+                self.synthesizer.next_sample() * self.gain
+            })
+            .collect()
+    }
+
+    fn next_frame_length(&mut self) -> usize {
+        let sample_freq = self.sample_freq as u64;
+        let frame_freq = self.frame_freq as u64;
+        let start = self.frame_count * sample_freq / frame_freq;
+        self.frame_count = (self.frame_count + 1) % frame_freq;
+        let end = match self.frame_count {
+            0 => sample_freq,
+            frame => frame * sample_freq / frame_freq
+        };
+        (end - start) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chip_8_emu::timer::BuzzerFrame;
+    use super::{AudioStream, PatternWave, Synthesizer, Waveform};
+
+    #[test]
+    fn pattern_test() {
+        // At pitch 64 and 8000 Hz, every bit of the pattern lasts two samples
+        let mut wave = PatternWave::new(8000, 0.5);
+        wave.set_pattern(&[0xA0; 16]);
+        let out: [f32; 8] = std::array::from_fn(|_| wave.next_sample());
+
+        assert_eq!(out, [0.5, 0.5, -0.5, -0.5, 0.5, 0.5, -0.5, -0.5]);
+    }
+
+    #[test]
+    fn pitch_test() {
+        // 48 pitch steps above 64 double the playback rate
+        let mut wave = PatternWave::new(8000, 0.5);
+        wave.set_pattern(&[0xA0; 16]);
+        wave.set_pitch(112);
+        let out: [f32; 4] = std::array::from_fn(|_| wave.next_sample());
+
+        assert_eq!(out, [0.5, -0.5, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn audio_stream_test() {
+        // 8000 Hz at 60 frames per second gives frames of 133 or 134 samples, and fades of 16 samples
+        let mut stream = AudioStream::new(synthesizer(8000, 0.5), 60);
+        let mut buzzer = BuzzerFrame::new(true);
+        buzzer.record(true);
+        let lengths = (0..60)
+            .map(|_| stream.render_frame(Some(&buzzer), &[0xFF; 16], 64).len())
+            .collect::<Vec<_>>();
+        assert_eq!(lengths.iter().sum::<usize>(), 8000);
+        assert!(lengths.iter().all(|&length| length == 133 || length == 134));
+
+        let mut stream = AudioStream::new(synthesizer(8000, 0.5), 60);
+        let samples = stream.render_frame(Some(&buzzer), &[0xFF; 16], 64);
+        assert_eq!(samples[0], 0.5 / 16.0);
+        assert!(samples[..15].windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(samples[15..].iter().all(|&sample| sample == 0.5));
+
+        let samples = stream.render_frame(None, &[0xFF; 16], 64);
+        assert!(samples[..15].windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(samples[15..].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn synthesizer_test() {
+        // A 2000 Hz tone at 8000 Hz has periods of 4 samples
+        let mut synth = synthesizer(8000, 0.5);
+        synth.set_tone(2000.0);
+        let mut samples = |waveform| {
+            synth.set_waveform(waveform);
+            (0..4).map(|_| synth.next_sample()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(samples(Waveform::Square), [0.5, 0.5, -0.5, -0.5]);
+        assert_eq!(samples(Waveform::Pulse), [0.5, -0.5, -0.5, -0.5]);
+        assert_eq!(samples(Waveform::Triangle), [-0.5, 0.0, 0.5, 0.0]);
+        let sine = samples(Waveform::Sine);
+        assert!(sine.iter().zip([0.0, 0.5, 0.0, -0.5]).all(|(sample, expected)| (sample - expected).abs() < 1e-6));
+        let noise = (0..64).flat_map(|_| samples(Waveform::Noise)).collect::<Vec<_>>();
+        assert!(noise.iter().all(|&sample| sample == 0.5 || sample == -0.5));
+        assert!(noise.contains(&0.5) && noise.contains(&-0.5));
+    }
+
+    #[test]
+    fn synthesizer_settings_test() {
+        let mut synth = Synthesizer::new(8000);
+        assert_eq!(synth.get_waveform(), Waveform::Pattern);
+        assert_eq!(synth.get_waveform().next().next(), Waveform::Pulse);
+        assert_eq!(Waveform::Noise.next(), Waveform::Pattern);
+
+        synth.set_tone(1.0);
+        assert_eq!(synth.get_tone(), Synthesizer::MIN_TONE);
+        synth.set_volume(1.5);
+        assert_eq!(synth.get_volume(), 1.0);
+        synth.set_duty_cycle(-0.5);
+        assert_eq!(synth.get_duty_cycle(), 0.0);
+    }
+
+    #[test]
+    fn mute_test() {
+        let mut stream = AudioStream::new(synthesizer(8000, 0.5), 60);
+        let buzzer = BuzzerFrame::new(true);
+        stream.get_synthesizer_mut().set_muted(true);
+        assert!(stream.render_frame(Some(&buzzer), &[0xFF; 16], 64).iter().all(|&sample| sample == 0.0));
+    }
+
+    fn synthesizer(sample_freq: i32, volume: f32) -> Synthesizer {
+        let mut synth = Synthesizer::new(sample_freq);
+        synth.set_volume(volume);
+        synth
+    }
 }
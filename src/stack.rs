@@ -1,18 +1,96 @@
+use std::fmt::Display;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/// Error raised when pushing onto a full stack or popping from an empty one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    Overflow,
+    Underflow
+}
+
+impl Display for StackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackError::Overflow => write!(f, "stack overflow"),
+            StackError::Underflow => write!(f, "stack underflow")
+        }
+    }
+}
+
+impl std::error::Error for StackError {}
+
+/// Call stack with a maximum depth, as the interpreters only reserved room for a few return addresses
+#[derive(Clone)]
 pub struct Stack<T> {
-    vector: Vec<T>
+    vector: Vec<T>,
+    max_depth: usize
 }
 
 impl<T> Stack<T> {
+    /// Depth of the call stack of most interpreters
+    pub const DEFAULT_MAX_DEPTH: usize = 16;
+
     pub fn new() -> Stack<T> {
-        Self { vector: Vec::new() }
+        Self::with_max_depth(Self::DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn with_max_depth(max_depth: usize) -> Stack<T> {
+        Self { vector: Vec::with_capacity(max_depth), max_depth }
     }
 
-    pub fn push(&mut self, value: T) {
+    pub fn push(&mut self, value: T) -> Result<(), StackError> {
+        if self.vector.len() >= self.max_depth {
+            return Err(StackError::Overflow);
+        }
         self.vector.push(value);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<T, StackError> {
+        self.vector.pop().ok_or(StackError::Underflow)
+    }
+
+    /// Value on the top of the stack, which is the next one popped
+    pub fn peek(&self) -> Option<&T> {
+        self.vector.last()
+    }
+
+    pub fn len(&self) -> usize {
+        self.vector.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vector.is_empty()
     }
 
-    pub fn pop(&mut self) -> Option<T> {
-        self.vector.pop()
+    pub fn get_max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Values from the bottom to the top of the stack, which the VIP memory map keeps in sync with the memory
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.vector
+    }
+
+    /// Iterates from the bottom to the top of the stack
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> + ExactSizeIterator {
+        self.vector.iter()
+    }
+}
+
+impl Stack<u16> {
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.vector.len() as u16);
+        self.vector.iter().for_each(|&value| writer.write_u16(value));
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let len = reader.read_u16()?;
+        if len as usize > self.max_depth {
+            return Err(SaveStateError::InvalidField("stack depth"));
+        }
+        self.vector = (0..len).map(|_| reader.read_u16()).collect::<Result<_, _>>()?;
+        Ok(())
     }
 }
 
@@ -22,16 +100,39 @@ impl<T> Default for Stack<T> {
     }
 }
 
+impl<'a, T> IntoIterator for &'a Stack<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.vector.iter()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Stack;
+    use super::{Stack, StackError};
 
     #[test]
     fn push_test() {
         let mut stack: Stack<u16> = Stack::new();
-        stack.push(16);
+        stack.push(16).unwrap();
 
         assert!(stack.vector[0] == 16);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn depth_test() {
+        let mut stack: Stack<u16> = Stack::with_max_depth(2);
+        assert_eq!(stack.pop(), Err(StackError::Underflow));
+        assert_eq!(stack.push(0x202), Ok(()));
+        assert_eq!(stack.push(0x304), Ok(()));
+        assert_eq!(stack.push(0x406), Err(StackError::Overflow));
+
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.peek(), Some(&0x304));
+        assert_eq!(stack.iter().copied().collect::<Vec<_>>(), [0x202, 0x304]);
+        assert_eq!(stack.pop(), Ok(0x304));
+        assert_eq!(stack.len(), 1);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+#[derive(Clone)]
 pub struct Timer {
     delay: u8,
     sound: u8,
@@ -41,6 +44,17 @@ impl Timer {
     pub fn get_frequency(&self) -> u32 {
         self.frequency
     }
+
+    pub(crate) fn write_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.delay);
+        writer.write_u8(self.sound);
+    }
+
+    pub(crate) fn read_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.delay = reader.read_u8()?;
+        self.sound = reader.read_u8()?;
+        Ok(())
+    }
 }
 
 impl Default for Timer {
@@ -49,10 +63,71 @@ impl Default for Timer {
     }
 }
 
+/**
+State of the buzzer during a frame of emulated time, sampled after each executed instruction.
+
+The buzzer is on while the sound timer is not zero, so it can start or stop in the middle of a frame when the ROM
+writes the sound timer, and only at the frame boundaries when the timer counts down
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuzzerFrame {
+    initially_on: bool,
+    edges: Vec<(u32, bool)>,
+    instructions: u32
+}
+
+impl BuzzerFrame {
+    pub fn new(initially_on: bool) -> Self {
+        Self { initially_on, edges: Vec::new(), instructions: 0 }
+    }
+
+    /// Records the state of the buzzer after an instruction
+    pub fn record(&mut self, on: bool) {
+        self.instructions += 1;
+        if on != self.is_on() {
+            self.edges.push((self.instructions, on));
+        }
+    }
+
+    /// State of the buzzer after the last executed instruction
+    pub fn is_on(&self) -> bool {
+        self.edges.last().map_or(self.initially_on, |&(_, on)| on)
+    }
+
+    /// State of the buzzer at a position of the frame, from 0.0 (its start) to 1.0 (its end)
+    pub fn is_on_at(&self, position: f32) -> bool {
+        let instruction = position * self.instructions as f32;
+        self.edges.iter()
+            .take_while(|&&(edge, _)| edge as f32 <= instruction)
+            .last()
+            .map_or(self.initially_on, |&(_, on)| on)
+    }
+
+    pub fn get_instructions(&self) -> u32 {
+        self.instructions
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::BuzzerFrame;
+
     #[test]
     fn timer_test() {
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn buzzer_frame_test() {
+        // The buzzer starts after the second of four instructions
+        let mut buzzer = BuzzerFrame::new(false);
+        [false, true, true, true].into_iter().for_each(|on| buzzer.record(on));
+
+        assert!(buzzer.is_on());
+        assert!(!buzzer.is_on_at(0.0));
+        assert!(!buzzer.is_on_at(0.49));
+        assert!(buzzer.is_on_at(0.5));
+        assert!(buzzer.is_on_at(0.99));
+        assert_eq!(buzzer.get_instructions(), 4);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt::{Display, Write};
+use crate::{cpu::{Cpu, opcode::Opcode}, memory::Memory, timer::Timer};
+
+/**
+Formats the trace line of the instruction fetched at the address, before the CPU executes it.
+
+The line is made of `KEY:VALUE` fields in hexadecimal, except the decimal cycle count, followed by the mnemonic after
+a `;`, e.g. `CYC:1 PC:0200 OP:00E0 V0:00 ... VF:00 I:0000 SP:00 DT:00 ST:00 ; CLS`, see [`Opcode::format_executed`]
+ */
+pub fn format_trace_line(cycle: u64, pc: usize, instruction: u16, cpu: &Cpu, memory: &Memory, timer: &Timer) -> String {
+    let mut line = format!("CYC:{} PC:{:04X} OP:{:04X}", cycle, pc, instruction);
+    for (idx, value) in cpu.get_var_registers().iter().enumerate() {
+        write!(line, " V{:X}:{:02X}", idx, value).unwrap();
+    }
+    write!(
+        line, " I:{:04X} SP:{:02X} DT:{:02X} ST:{:02X} ; {}",
+        cpu.get_index_register(), cpu.get_stack().len(), timer.get_delay_timer(), timer.get_sound_timer(),
+        Opcode::from(instruction).format_executed(cpu.get_quirks(), memory.read_instruction(pc + 2).ok())
+    ).unwrap();
+    line
+}
+
+/// Fields of a trace line, without the mnemonic and the cycle count which other emulators may count from 0
+fn parse_fields(line: &str) -> Vec<(&str, &str)> {
+    let fields = line.split(';').next().unwrap_or_default();
+    fields.split_whitespace()
+        .filter_map(|field| field.split_once(':'))
+        .filter(|(key, _)| *key != "CYC")
+        .collect()
+}
+
+/// First instruction where two traces differ, see [`diff_traces`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceDivergence {
+    /// Line number of the divergent instruction, starting from 1
+    pub line: usize,
+    /// Lines preceding the divergent instruction, which are the same in both traces
+    pub context: Vec<String>,
+    /// Divergent line of each trace, or None if the trace ended before it
+    pub left: Option<String>,
+    pub right: Option<String>,
+    /// Fields whose values differ, as (key, left value, right value)
+    pub fields: Vec<(String, String, String)>
+}
+
+impl Display for TraceDivergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Traces diverge at instruction {}", self.line)?;
+        for line in &self.context {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "- {}", self.left.as_deref().unwrap_or("<end of trace>"))?;
+        writeln!(f, "+ {}", self.right.as_deref().unwrap_or("<end of trace>"))?;
+        let fields: Vec<String> = self.fields.iter()
+            .map(|(key, left, right)| format!("{}: {} != {}", key, left, right))
+            .collect();
+        match fields.is_empty() {
+            true => write!(f, "One trace ended before the other"),
+            false => write!(f, "Differences: {}", fields.join(", "))
+        }
+    }
+}
+
+/**
+Compares two traces instruction by instruction and returns the first divergence, or None if they are the same.
+
+Only the fields present in both lines are compared, so a reference trace with fewer registers can still be compared.
+The context holds up to `context` lines preceding the divergence
+ */
+pub fn diff_traces(left: &str, right: &str, context: usize) -> Option<TraceDivergence> {
+    let left_lines: Vec<&str> = left.lines().filter(|line| !line.trim().is_empty()).collect();
+    let right_lines: Vec<&str> = right.lines().filter(|line| !line.trim().is_empty()).collect();
+
+    for idx in 0..left_lines.len().max(right_lines.len()) {
+        let (left_line, right_line) = (left_lines.get(idx), right_lines.get(idx));
+        let fields: Vec<(String, String, String)> = match (left_line, right_line) {
+            (Some(left_line), Some(right_line)) => {
+                let right_fields = parse_fields(right_line);
+                parse_fields(left_line).into_iter()
+                    .filter_map(|(key, left_value)| {
+                        let (_, right_value) = right_fields.iter().find(|(right_key, _)| *right_key == key)?;
+                        (!left_value.eq_ignore_ascii_case(right_value))
+                            .then(|| (key.to_string(), left_value.to_string(), right_value.to_string()))
+                    })
+                    .collect()
+            },
+            _ => Vec::new()
+        };
+        if fields.is_empty() && left_line.is_some() && right_line.is_some() {
+            continue;
+        }
+        return Some(TraceDivergence {
+            line: idx + 1,
+            context: left_lines[idx.saturating_sub(context)..idx].iter().map(|line| line.to_string()).collect(),
+            left: left_line.map(|line| line.to_string()),
+            right: right_line.map(|line| line.to_string()),
+            fields
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{chip8::Chip8, timer::Timer};
+    use super::{diff_traces, format_trace_line};
+
+    #[test]
+    fn format_trace_line_test() {
+        let chip8 = Chip8::builder().rom(&[0x60, 0x12]).build().unwrap();
+        let mut timer = Timer::new();
+        timer.set_delay_timer(0x3C);
+        assert_eq!(
+            format_trace_line(1, 0x200, 0x6012, chip8.get_cpu(), chip8.get_memory(), &timer),
+            "CYC:1 PC:0200 OP:6012 V0:00 V1:00 V2:00 V3:00 V4:00 V5:00 V6:00 V7:00 V8:00 V9:00 VA:00 VB:00 VC:00 VD:00 \
+             VE:00 VF:00 I:0000 SP:00 DT:3C ST:00 ; LD V0, 0x12"
+        );
+    }
+
+    #[test]
+    fn diff_traces_test() {
+        let left = "CYC:1 PC:0200 V0:00 ; A\nCYC:2 PC:0202 V0:01 ; B\nCYC:3 PC:0204 V0:02 ; C\n";
+        // The cycle count, the missing fields and the mnemonics are not compared
+        let right = "CYC:0 PC:0200 ; X\nCYC:1 PC:0202 V0:01\nCYC:2 PC:0206 V0:03\n";
+        assert_eq!(diff_traces(left, left, 2), None);
+        let divergence = diff_traces(left, right, 1).unwrap();
+        assert_eq!(divergence.line, 3);
+        assert_eq!(divergence.context, vec!["CYC:2 PC:0202 V0:01 ; B"]);
+        assert_eq!(divergence.fields, vec![
+            ("PC".to_string(), "0204".to_string(), "0206".to_string()),
+            ("V0".to_string(), "02".to_string(), "03".to_string())
+        ]);
+
+        let truncated = diff_traces(left, "CYC:1 PC:0200 V0:00", 5).unwrap();
+        assert_eq!((truncated.line, truncated.right), (2, None));
+    }
+}